@@ -0,0 +1,55 @@
+// Compares the double hashing of `BloomFilter` with hashing every key once per hash.
+// Run with `cargo bench --bench bloom`.
+
+use dustdata::bloom::BloomFilter;
+use farmhash::hash64_with_seed;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 100_000;
+const HASHES: i64 = 10;
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let started_at = Instant::now();
+    f();
+    started_at.elapsed()
+}
+
+/// Sets the bits of a key the way the filter did before double hashing, k hash calls per key.
+fn insert_k_hashes(bitvec: &mut [u8], value: &str) {
+    for i in 0..HASHES {
+        let pos =
+            (hash64_with_seed(value.as_bytes(), i as u64) % (bitvec.len() as u64 * 8)) as usize;
+        bitvec[pos / 8] |= 1 << (pos % 8);
+    }
+}
+
+fn main() {
+    let keys = (0..KEYS).map(|i| format!("key:{}", i)).collect::<Vec<_>>();
+
+    let mut filter = BloomFilter::new(0.001, KEYS);
+    filter.hashes = HASHES;
+
+    let mut bitvec = vec![0; filter.bitvec.len()];
+
+    let k_hashes = time(|| {
+        for key in &keys {
+            insert_k_hashes(black_box(&mut bitvec), key);
+        }
+    });
+
+    let double_hashing = time(|| {
+        for key in &keys {
+            black_box(&mut filter).insert(key);
+        }
+    });
+
+    println!(
+        "{} keys, {} hashes: k hashes {:?}, double hashing {:?} ({:.1}x)",
+        KEYS,
+        HASHES,
+        k_hashes,
+        double_hashing,
+        k_hashes.as_secs_f64() / double_hashing.as_secs_f64()
+    );
+}
@@ -4,7 +4,7 @@
 use farmhash::hash64_with_seed;
 
 fn num_bits(size: usize, fp_rate: f64) -> i64 {
-    let num = -1.0f64 * size as f64 * fp_rate.ln();
+    let num = -(size as f64) * fp_rate.ln();
     let den = 2.0f64.ln().powf(2.0);
     (num / den).ceil() as i64
 }
@@ -16,33 +16,39 @@ fn num_hashes(m: i64, n: usize) -> i64 {
 pub struct BloomFilter {
     pub bitvec: Vec<u8>,
     pub hashes: i64,
+    pub seed: u64, // seeds both base hashes, so filters with different seeds set different bits
 }
 
 impl BloomFilter {
     pub fn new(fp_rate: f64, size: usize) -> Self {
+        Self::with_seed(fp_rate, size, 0)
+    }
+
+    pub fn with_seed(fp_rate: f64, size: usize, seed: u64) -> Self {
         let m = num_bits(size, fp_rate);
         let k = num_hashes(m, size);
 
         BloomFilter {
             bitvec: vec![0; m.try_into().unwrap()],
             hashes: k,
+            seed,
         }
     }
 
     pub fn insert(&mut self, value: &str) {
+        let hashes = self.base_hashes(value);
+
         for i in 0..self.hashes {
-            let index =
-                hash64_with_seed(value.as_bytes(), i as u64) % (self.bitvec.len() as u64 * 8);
-            let pos = index as usize;
+            let pos = self.position(hashes, i);
             self.bitvec[pos / 8] |= 1 << (pos % 8);
         }
     }
 
     pub fn contains(&self, value: &str) -> bool {
+        let hashes = self.base_hashes(value);
+
         for i in 0..self.hashes {
-            let index =
-                hash64_with_seed(value.as_bytes(), i as u64) % (self.bitvec.len() as u64 * 8);
-            let pos = index as usize;
+            let pos = self.position(hashes, i);
             if (1 << (pos % 8)) & self.bitvec[pos / 8] == 0 {
                 return false;
             }
@@ -56,10 +62,10 @@ impl BloomFilter {
     }
 
     pub fn remove(&mut self, value: &str) {
+        let hashes = self.base_hashes(value);
+
         for i in 0..self.hashes {
-            let index =
-                hash64_with_seed(value.as_bytes(), i as u64) % (self.bitvec.len() as u64 * 8);
-            let pos = index as usize;
+            let pos = self.position(hashes, i);
             self.bitvec[pos / 8] &= !(1 << (pos % 8));
         }
     }
@@ -71,4 +77,40 @@ impl BloomFilter {
     pub fn get_hashes(&self) -> i64 {
         self.hashes
     }
+
+    /// The number of set bits.
+    pub fn population(&self) -> u64 {
+        self.bitvec
+            .iter()
+            .map(|byte| byte.count_ones() as u64)
+            .sum()
+    }
+
+    /// The false-positive rate for the current fill, `fill ^ hashes`.
+    pub fn estimated_fp_rate(&self) -> f64 {
+        let fill = self.population() as f64 / (self.bitvec.len() * 8) as f64;
+
+        fill.powi(self.hashes as i32)
+    }
+
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Kirsch-Mitzenmacher double hashing: the k positions are derived from two hashes
+    // as h1 + i * h2, instead of hashing the value k times.
+    fn base_hashes(&self, value: &str) -> (u64, u64) {
+        let h1 = hash64_with_seed(value.as_bytes(), self.seed);
+        let h2 = hash64_with_seed(value.as_bytes(), self.seed.wrapping_add(1));
+
+        // An odd step is never zero, so the k positions don't all fall on h1. The filter can
+        // have any size, so positions can still repeat.
+        (h1, h2 | 1)
+    }
+
+    fn position(&self, (h1, h2): (u64, u64), i: i64) -> usize {
+        let hash = h1.wrapping_add((i as u64).wrapping_mul(h2));
+
+        (hash % (self.bitvec.len() as u64 * 8)) as usize
+    }
 }
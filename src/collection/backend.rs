@@ -0,0 +1,253 @@
+use super::config::StorageBackend;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::{fs, path};
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<R: Read + Seek> ReadSeek for R {}
+
+/// Where the data chunks, logs and indexes of a collection are kept.
+/// `Disk` maps every path to a real file, `Memory` keeps the file contents in-process.
+pub enum Backend {
+    Disk {
+        handles: HashMap<path::PathBuf, fs::File>, // open append handles
+        sync_dir_on_create: bool,
+    },
+    Memory(HashMap<path::PathBuf, Vec<u8>>),
+}
+
+/// A new file written while the backend is shared, made visible by `Backend::install`.
+/// On disk it's written next to its final path with a `.tmp` suffix, in memory it's buffered.
+pub enum StagedFile {
+    Disk {
+        file: fs::File,
+        tmp_path: path::PathBuf,
+    },
+    Memory(Vec<u8>),
+}
+
+impl StagedFile {
+    pub fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            StagedFile::Disk { file, .. } => file.write_all(bytes),
+            StagedFile::Memory(buffer) => {
+                buffer.extend_from_slice(bytes);
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        match self {
+            StagedFile::Disk { file, .. } => file.sync_data(),
+            StagedFile::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Backend {
+    pub fn new(kind: StorageBackend, sync_dir_on_create: bool) -> Self {
+        match kind {
+            StorageBackend::Disk => Backend::Disk {
+                handles: HashMap::new(),
+                sync_dir_on_create,
+            },
+            StorageBackend::Memory => Backend::Memory(HashMap::new()),
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Backend::Memory(_))
+    }
+
+    pub fn create_dir_all(&self, path: &path::Path) -> io::Result<()> {
+        match self {
+            Backend::Disk { .. } => fs::create_dir_all(path),
+            Backend::Memory(_) => Ok(()),
+        }
+    }
+
+    pub fn exists(&self, path: &path::Path) -> bool {
+        match self {
+            Backend::Disk { .. } => path.exists(),
+            Backend::Memory(files) => files.contains_key(path),
+        }
+    }
+
+    pub fn len(&self, path: &path::Path) -> io::Result<u64> {
+        match self {
+            Backend::Disk { .. } => fs::metadata(path).map(|m| m.len()),
+            Backend::Memory(files) => files
+                .get(path)
+                .map(|bytes| bytes.len() as u64)
+                .ok_or_else(|| io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Creates the file if it doesn't exist yet.
+    pub fn touch(&mut self, path: &path::Path) -> io::Result<()> {
+        self.append(path, &[])
+    }
+
+    pub fn append(&mut self, path: &path::Path, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Backend::Disk {
+                handles,
+                sync_dir_on_create,
+            } => {
+                if !handles.contains_key(path) {
+                    let created = !path.exists();
+                    let file = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?;
+
+                    if created && *sync_dir_on_create {
+                        sync_dir(path.parent().unwrap_or(path::Path::new(".")))?;
+                    }
+
+                    handles.insert(path.to_path_buf(), file);
+                }
+
+                handles.get_mut(path).unwrap().write_all(bytes)
+            }
+            Backend::Memory(files) => {
+                files
+                    .entry(path.to_path_buf())
+                    .or_default()
+                    .extend_from_slice(bytes);
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn reader(&self, path: &path::Path) -> io::Result<Box<dyn ReadSeek + '_>> {
+        match self {
+            Backend::Disk { .. } => Ok(Box::new(fs::File::open(path)?)),
+            Backend::Memory(files) => files
+                .get(path)
+                .map(|bytes| Box::new(Cursor::new(bytes.as_slice())) as Box<dyn ReadSeek>)
+                .ok_or_else(|| io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Same as `reader`, but the reader doesn't borrow the backend, so it can outlive the
+    /// storage lock. In-memory files are copied.
+    pub fn owned_reader(&self, path: &path::Path) -> io::Result<Box<dyn ReadSeek + Send>> {
+        match self {
+            Backend::Disk { .. } => Ok(Box::new(fs::File::open(path)?)),
+            Backend::Memory(files) => files
+                .get(path)
+                .map(|bytes| Box::new(Cursor::new(bytes.clone())) as Box<dyn ReadSeek + Send>)
+                .ok_or_else(|| io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Flushes buffered writes of a file to stable storage.
+    pub fn sync(&self, path: &path::Path) -> io::Result<()> {
+        match self {
+            Backend::Disk { handles, .. } => match handles.get(path) {
+                Some(file) => file.sync_data(),
+                None => Ok(()),
+            },
+            Backend::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Lists the files directly inside a directory.
+    pub fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        match self {
+            Backend::Disk { .. } => {
+                let mut files = Vec::new();
+
+                for entry in fs::read_dir(dir)? {
+                    let entry = entry?;
+
+                    if entry.file_type()?.is_file() {
+                        files.push(entry.path());
+                    }
+                }
+
+                Ok(files)
+            }
+            Backend::Memory(files) => Ok(files
+                .keys()
+                .filter(|path| path.parent() == Some(dir))
+                .cloned()
+                .collect()),
+        }
+    }
+
+    /// Creates a file to write without borrowing the backend mutably, replacing any file
+    /// left at its temporary path.
+    pub fn stage(&self, path: &path::Path) -> io::Result<StagedFile> {
+        match self {
+            Backend::Disk { .. } => {
+                let mut tmp_path = path.as_os_str().to_owned();
+                tmp_path.push(".tmp");
+                let tmp_path = path::PathBuf::from(tmp_path);
+
+                Ok(StagedFile::Disk {
+                    file: fs::File::create(&tmp_path)?,
+                    tmp_path,
+                })
+            }
+            Backend::Memory(_) => Ok(StagedFile::Memory(Vec::new())),
+        }
+    }
+
+    /// Moves a staged file to its final path.
+    pub fn install(&mut self, path: &path::Path, staged: StagedFile) -> io::Result<()> {
+        match (self, staged) {
+            (
+                Backend::Disk {
+                    sync_dir_on_create, ..
+                },
+                StagedFile::Disk { tmp_path, .. },
+            ) => {
+                fs::rename(tmp_path, path)?;
+
+                if *sync_dir_on_create {
+                    sync_dir(path.parent().unwrap_or(path::Path::new(".")))?;
+                }
+
+                Ok(())
+            }
+            (Backend::Memory(files), StagedFile::Memory(bytes)) => {
+                files.insert(path.to_path_buf(), bytes);
+
+                Ok(())
+            }
+            _ => Err(io::Error::other("Staged file of another backend")),
+        }
+    }
+
+    pub fn remove(&mut self, path: &path::Path) -> io::Result<()> {
+        match self {
+            Backend::Disk { handles, .. } => {
+                handles.remove(path);
+                fs::remove_file(path)
+            }
+            Backend::Memory(files) => {
+                files.remove(path);
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Syncs a directory so the entries of files created in it survive a crash.
+#[cfg(unix)]
+pub(super) fn sync_dir(dir: &path::Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing on this platform.
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_dir: &path::Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use super::{Collection, Operation, Transaction};
+use crate::error::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+/// What a `BulkLoader` does with the batches it already committed when a batch fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnFailure {
+    /// Only the failed batch is discarded.
+    #[default]
+    KeepCommitted,
+    /// Every batch committed by the loader is rolled back too.
+    RollbackAll,
+}
+
+/// Counts of a finished bulk load.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub inserted: usize,
+    pub skipped_duplicates: usize, // keys that already existed or were pushed twice
+}
+
+/// Inserts values in batches, committing every `batch_size` values so memory stays bounded.
+/// Created with `Collection::bulk_loader`.
+pub struct BulkLoader<T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static> {
+    collection: Collection<T>,
+    batch: Vec<(String, T)>,
+    batch_keys: HashSet<String>,
+    batch_size: usize,
+    on_failure: OnFailure,
+    committed: Vec<usize>, // ids of the committed batches, kept for `OnFailure::RollbackAll`
+    report: ImportReport,
+}
+
+impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> BulkLoader<T> {
+    pub(super) fn new(collection: Collection<T>) -> Self {
+        let batch_size = collection
+            .max_transaction_ops
+            .map_or(1000, |max_ops| max_ops.min(1000));
+
+        Self {
+            collection,
+            batch: Vec::new(),
+            batch_keys: HashSet::new(),
+            batch_size,
+            on_failure: OnFailure::default(),
+            committed: Vec::new(),
+            report: ImportReport::default(),
+        }
+    }
+
+    /// Number of values committed per transaction.
+    /// Default: 1000, or `max_transaction_ops` if it's lower
+    pub fn batch_size(&mut self, batch_size: usize) -> &mut Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// What happens to the committed batches when a batch fails.
+    /// Default: `OnFailure::KeepCommitted`
+    pub fn on_failure(&mut self, on_failure: OnFailure) -> &mut Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Stages a value, committing the batch once it's full.
+    /// A key that is already staged is skipped.
+    pub fn push(&mut self, key: &str, value: T) -> Result<()> {
+        if !self.batch_keys.insert(key.to_string()) {
+            self.report.skipped_duplicates += 1;
+
+            return Ok(());
+        }
+
+        self.batch.push((key.to_string(), value));
+
+        if self.batch.len() >= self.batch_size {
+            self.commit_batch()?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the last batch and returns the counts of the whole load.
+    pub fn finish(mut self) -> Result<ImportReport> {
+        self.commit_batch()?;
+
+        Ok(self.report)
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.batch_keys.clear();
+
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut self.batch);
+
+        if let Err(err) = self.commit_locked(batch) {
+            if self.on_failure == OnFailure::RollbackAll {
+                self.rollback_committed()?;
+            }
+
+            return Err(err);
+        }
+
+        self.collection.wait_synced()
+    }
+
+    fn commit_locked(&mut self, batch: Vec<(String, T)>) -> Result<()> {
+        // Holding the write-ahead log lock keeps the keys checked here from being
+        // inserted by another commit before this one.
+        let mut wal = self.collection.lock_wal()?;
+
+        let mut transaction = Transaction::new();
+
+        {
+            let storage = self.collection.storage.read();
+
+            for (key, value) in batch {
+                if storage.contains_exact(&key) {
+                    self.report.skipped_duplicates += 1;
+                } else {
+                    transaction.data.push(Operation::Insert(key, value));
+                }
+            }
+        }
+
+        if transaction.data.is_empty() {
+            return Ok(());
+        }
+
+        let inserted = transaction.data.len();
+
+        self.collection.commit_locked(&mut wal, &mut transaction)?;
+
+        self.report.inserted += inserted;
+
+        if self.on_failure == OnFailure::RollbackAll {
+            self.committed.push(transaction.tx_id);
+        }
+
+        Ok(())
+    }
+
+    fn rollback_committed(&mut self) -> Result<()> {
+        while let Some(tx_id) = self.committed.pop() {
+            let mut revert_transaction = self.collection.wal.read().revert::<T>(tx_id)?;
+
+            self.collection.commit(&mut revert_transaction)?;
+        }
+
+        self.report.inserted = 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use super::config::{CompressionAlgorithm, CompressionConfig};
+use crate::error::{Error, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{Cursor, Read, Write};
+
+const CODEC_MAGIC: &[u8; 4] = b"DCMP";
+
+fn tag(algorithm: CompressionAlgorithm) -> u8 {
+    match algorithm {
+        CompressionAlgorithm::None => 0,
+        CompressionAlgorithm::Gzip => 1,
+        CompressionAlgorithm::Zstd => 2,
+        CompressionAlgorithm::Lz4 => 3,
+    }
+}
+
+/// Compresses bytes with the configured algorithm.
+/// The output starts with a magic and a one-byte algorithm tag, so `decode`
+/// doesn't need to know which algorithm was used.
+pub fn encode(bytes: &[u8], compression: Option<&CompressionConfig>) -> Result<Vec<u8>> {
+    let algorithm = compression
+        .map(|c| c.algorithm)
+        .unwrap_or(CompressionAlgorithm::None);
+
+    let mut encoded = CODEC_MAGIC.to_vec();
+    encoded.push(tag(algorithm));
+
+    match (algorithm, compression) {
+        (CompressionAlgorithm::Gzip, Some(compression)) => {
+            let mut encoder = GzEncoder::new(encoded, Compression::new(compression.level));
+            encoder.write_all(bytes)?;
+            encoded = encoder.finish()?;
+        }
+        (CompressionAlgorithm::Zstd, Some(compression)) => {
+            encoded.extend(zstd::encode_all(bytes, compression.level as i32)?);
+        }
+        (CompressionAlgorithm::Lz4, _) => {
+            encoded.extend(lz4_flex::compress_prepend_size(bytes));
+        }
+        _ => encoded.extend_from_slice(bytes),
+    }
+
+    Ok(encoded)
+}
+
+/// Decompresses bytes written by `encode`.
+/// Bytes without the codec magic are either gzip (older files) or uncompressed.
+pub fn decode(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(tagged) = bytes.strip_prefix(CODEC_MAGIC) else {
+        let mut decoder = GzDecoder::new(&bytes[..]);
+
+        if decoder.header().is_none() {
+            return Ok(bytes);
+        }
+
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded)?;
+
+        return Ok(decoded);
+    };
+
+    let corrupted = |e: &dyn std::fmt::Display| {
+        Error::CorruptedData(format!("Failed to decompress. Error: {}", e))
+    };
+
+    match tagged.split_first() {
+        Some((0, payload)) => Ok(payload.to_vec()),
+        Some((1, payload)) => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(payload)
+                .read_to_end(&mut decoded)
+                .map_err(|e| corrupted(&e))?;
+
+            Ok(decoded)
+        }
+        Some((2, payload)) => zstd::decode_all(payload).map_err(|e| corrupted(&e)),
+        Some((3, payload)) => {
+            lz4_flex::decompress_size_prepended(payload).map_err(|e| corrupted(&e))
+        }
+        Some((tag, _)) => Err(corrupted(&format!("unknown algorithm tag {}", tag))),
+        None => Err(corrupted(&"missing algorithm tag")),
+    }
+}
+
+/// Streaming version of `decode`, reading the bytes written by `encode` from `reader`.
+/// Lz4 and bytes without the codec magic are decoded in memory.
+pub fn decoder<'a, R: Read + Send + 'a>(mut reader: R) -> Result<Box<dyn Read + Send + 'a>> {
+    let mut header = Vec::with_capacity(CODEC_MAGIC.len() + 1);
+    reader
+        .by_ref()
+        .take(CODEC_MAGIC.len() as u64 + 1)
+        .read_to_end(&mut header)?;
+
+    if let Some([tag]) = header.strip_prefix(CODEC_MAGIC) {
+        match *tag {
+            0 => return Ok(Box::new(reader)),
+            1 => return Ok(Box::new(GzDecoder::new(reader))),
+            2 => return Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            _ => {}
+        }
+    }
+
+    reader.read_to_end(&mut header)?;
+
+    Ok(Box::new(Cursor::new(decode(header)?)))
+}
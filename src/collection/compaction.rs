@@ -0,0 +1,82 @@
+use super::metrics::Metrics;
+use super::storage::{self, CompactionStats};
+use super::{config, Storage, Wal};
+use crate::error::Result;
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// Compacts the storage while reads go on, only locking it exclusively to swap in the new
+/// data chunks. The caller holds the write-ahead log lock, so no commit waits on the
+/// storage lock meanwhile, which would hold up new reads behind it.
+pub fn compact(storage: &RwLock<storage::Storage>) -> Result<CompactionStats> {
+    let storage = storage.upgradable_read();
+    let prepared = storage.prepare_compaction()?;
+
+    RwLockUpgradableReadGuard::upgrade(storage).finish_compaction(prepared)
+}
+
+/// Background thread that compacts the storage once the ratio of dead bytes to
+/// live bytes exceeds the configured threshold.
+/// Failures are counted in `MetricsSnapshot::compaction_failures`, and the next run tries again.
+/// The thread stops when the worker is stopped or dropped.
+pub struct CompactionWorker {
+    shutdown: Mutex<Option<mpsc::Sender<()>>>,
+    handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl CompactionWorker {
+    pub fn spawn(
+        wal: Wal,
+        storage: Storage,
+        stats: Arc<RwLock<Option<CompactionStats>>>,
+        metrics: Arc<Metrics>,
+        config: config::CompactionConfig,
+    ) -> Self {
+        let (shutdown, shutdown_receiver) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                shutdown_receiver.recv_timeout(config.interval)
+            {
+                if storage.read().dead_ratio() <= config.dead_ratio_threshold {
+                    continue;
+                }
+
+                let _wal = wal.write();
+
+                match compact(&storage) {
+                    Ok(compaction_stats) => {
+                        *stats.write() = Some(compaction_stats);
+                        metrics.compaction();
+                    }
+                    Err(err) => {
+                        metrics.compaction_failure();
+                        warn!("Background compaction failed: {}", err);
+                    }
+                }
+            }
+        });
+
+        Self {
+            shutdown: Mutex::new(Some(shutdown)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    /// Stops the thread, waiting for a compaction in progress to finish.
+    pub fn stop(&self) {
+        // Disconnecting the channel wakes the thread up and stops it.
+        self.shutdown.lock().take();
+
+        if let Some(handle) = self.handle.lock().take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,114 @@
+use crate::config::MemtableEviction;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// In-memory cache of recently written values.
+/// Every value in the memtable is also in the storage, so entries can be evicted in
+/// the order of the eviction policy once the estimated size exceeds the flush threshold.
+pub struct Memtable<T> {
+    table: HashMap<String, MemtableEntry<T>>,
+    order: BTreeMap<u64, String>, // write sequence, or last access with `MemtableEviction::Lru` -> key
+    sequence: u64,
+    size: usize,
+    flush_threshold: Option<usize>,
+    eviction: MemtableEviction,
+}
+
+struct MemtableEntry<T> {
+    value: T,
+    size: usize,
+    sequence: u64,
+}
+
+impl<T: Serialize> Memtable<T> {
+    pub fn new(flush_threshold: Option<usize>, eviction: MemtableEviction) -> Self {
+        Self {
+            table: HashMap::new(),
+            order: BTreeMap::new(),
+            sequence: 0,
+            size: 0,
+            flush_threshold,
+            eviction,
+        }
+    }
+
+    pub fn eviction(&self) -> MemtableEviction {
+        self.eviction
+    }
+
+    pub fn get(&self, key: &str) -> Option<&T> {
+        self.table.get(key).map(|entry| &entry.value)
+    }
+
+    /// Gets a value and marks it as the most recently used one.
+    pub fn touch(&mut self, key: &str) -> Option<&T> {
+        let entry = self.table.get_mut(key)?;
+
+        self.sequence += 1;
+        self.order.remove(&entry.sequence);
+        self.order.insert(self.sequence, key.to_string());
+        entry.sequence = self.sequence;
+
+        Some(&entry.value)
+    }
+
+    pub fn insert(&mut self, key: String, value: T) {
+        self.remove(&key);
+
+        let size = key.len() + bincode::serialized_size(&value).unwrap_or(0) as usize;
+
+        self.sequence += 1;
+        self.size += size;
+        self.order.insert(self.sequence, key.clone());
+        self.table.insert(
+            key,
+            MemtableEntry {
+                value,
+                size,
+                sequence: self.sequence,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        let entry = self.table.remove(key)?;
+
+        self.size -= entry.size;
+        self.order.remove(&entry.sequence);
+
+        Some(entry.value)
+    }
+
+    pub fn clear(&mut self) {
+        self.table.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    /// Estimated size in bytes of the keys and values.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Evicts the oldest written, or least recently used, entries until the memtable fits
+    /// the flush threshold.
+    pub fn evict(&mut self) {
+        let Some(flush_threshold) = self.flush_threshold else {
+            return;
+        };
+
+        if self.eviction == MemtableEviction::None {
+            return;
+        }
+
+        while self.size > flush_threshold {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = self.table.remove(&key) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operation counters of a collection, shared by its handles.
+/// The counters are only read for snapshots, so relaxed ordering is enough.
+#[derive(Default)]
+pub struct Metrics {
+    inserts: AtomicU64,
+    updates: AtomicU64,
+    deletes: AtomicU64,
+    gets: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_written: AtomicU64,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    compaction_failures: AtomicU64,
+}
+
+/// Counters returned by `Collection::metrics`, every count is since the collection was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
+    pub gets: u64,
+    pub cache_hits: u64,    // gets served from the memtable
+    pub cache_misses: u64, // gets read from the data chunks, gets ruled out by the bloom filter are neither
+    pub bytes_written: u64, // appended to the data chunks, compactions excluded
+    pub flushes: u64,
+    pub compactions: u64,         // manual and in the background
+    pub compaction_failures: u64, // background compactions that returned an error
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            inserts: self.inserts.load(Ordering::Relaxed),
+            updates: self.updates.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compaction_failures: self.compaction_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn insert(&self) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn update(&self) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn flush(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn compaction_failure(&self) {
+        self.compaction_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -1,16 +1,44 @@
+mod backend;
+mod bulk;
+mod codec;
+mod compaction;
+mod memtable;
+mod metrics;
+mod multi;
+#[cfg(feature = "json")]
+mod ndjson;
+mod raw;
+mod secondary;
+mod snapshot;
 mod storage;
 mod wal;
 
 use crate::config;
 use crate::error::{self, Result};
+pub use bulk::{BulkLoader, ImportReport, OnFailure};
+pub use metrics::MetricsSnapshot;
+pub(crate) use multi::Coordinator;
+pub use multi::MultiTransaction;
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+pub use raw::RawCollection;
+use secondary::{FieldIndex, SecondaryIndex};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
     fmt::Debug,
-    sync::{Arc, RwLock},
+    fs,
+    io::Write,
+    ops::{self, RangeBounds},
+    path,
+    sync::{mpsc, Arc},
     time,
 };
-use wal::{TransactionLog, WalOperation};
+pub use storage::{BloomStats, CollectionStats, CompactionStats, IntegrityReport};
+pub use wal::{TransactionLog, WalOperation};
+
+/// Keys starting with this prefix are kept for the collection's own bookkeeping
+pub const RESERVED_KEY_PREFIX: &str = "__dustdata_";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Operation<T> {
@@ -18,6 +46,7 @@ pub enum Operation<T> {
     Update(String, T),
     Delete(String),
     Drop,
+    DropPrefix(String),
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +54,8 @@ pub struct Transaction<T> {
     status: TransactionStatus,
     data: Vec<Operation<T>>,
     tx_id: usize,
+    timestamp: usize, // wall clock when the transaction started, in microseconds
+    max_ops: Option<usize>, // set by `Collection::start`
 }
 
 impl<T> Transaction<T> {
@@ -48,14 +79,54 @@ impl<T> Transaction<T> {
 
     /// Adds a clear operation to the transaction
     /// This will clear the entire collection
+    /// The space of the data chunks is freed by the next `Collection::flush`
     pub fn clear(&mut self) {
         self.data.push(Operation::Drop);
     }
 
+    /// Adds an operation deleting every key that starts with `prefix` to the transaction
+    /// Each key is logged as its own delete, so rolling the transaction back restores them
+    pub fn clear_prefix(&mut self, prefix: &str) {
+        self.data.push(Operation::DropPrefix(prefix.to_string()));
+    }
+
     /// Extends the transaction with a list of operations
     pub fn extend(&mut self, operations: Vec<Operation<T>>) {
         self.data.extend(operations);
     }
+
+    /// Extends the transaction with a list of operations, unless they would take it over
+    /// the collection's `max_transaction_ops`
+    /// Returns `Error::TransactionTooLarge` and leaves the transaction as it was if they would
+    pub fn try_extend(&mut self, operations: Vec<Operation<T>>) -> Result<()> {
+        if let Some(max_ops) = self.max_ops {
+            if self.data.len() + operations.len() > max_ops {
+                return Err(error::Error::TransactionTooLarge(max_ops));
+            }
+        }
+
+        self.data.extend(operations);
+
+        Ok(())
+    }
+
+    /// Gets the latest staged value for a key
+    /// This only sees operations staged in this transaction, not committed data
+    pub fn get(&self, key: &str) -> Option<&T> {
+        for operation in self.data.iter().rev() {
+            match operation {
+                Operation::Insert(k, value) | Operation::Update(k, value) if k == key => {
+                    return Some(value)
+                }
+                Operation::Delete(k) if k == key => return None,
+                Operation::Drop => return None,
+                Operation::DropPrefix(prefix) if key.starts_with(prefix.as_str()) => return None,
+                _ => {}
+            }
+        }
+
+        None
+    }
 }
 
 impl<T> Default for Transaction<T> {
@@ -66,15 +137,107 @@ impl<T> Default for Transaction<T> {
 
 impl<T> Transaction<T> {
     pub fn new() -> Self {
+        let timestamp = get_current_timestamp();
+
         Self {
             status: TransactionStatus::Active,
             data: Vec::new(),
-            tx_id: get_current_timestamp(),
+            tx_id: timestamp,
+            timestamp,
+            max_ops: None,
         }
     }
+
+    /// The transaction id, which is also its key in the write-ahead log
+    /// It starts as the start timestamp and is final once committed, greater than the id of every transaction committed before it
+    pub fn id(&self) -> usize {
+        self.tx_id
+    }
+
+    /// The wall clock time the transaction started at, in microseconds since the Unix epoch
+    pub fn timestamp(&self) -> usize {
+        self.timestamp
+    }
+
+    pub fn status(&self) -> &TransactionStatus {
+        &self.status
+    }
+
+    /// Whether operations can still be staged and the transaction committed
+    pub fn is_active(&self) -> bool {
+        self.status == TransactionStatus::Active
+    }
+
+    /// Whether the transaction was committed, a committed transaction can be rolled back
+    pub fn is_committed(&self) -> bool {
+        self.status == TransactionStatus::Committed
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.status == TransactionStatus::Aborted
+    }
+
+    pub fn is_rolled_back(&self) -> bool {
+        self.status == TransactionStatus::RolledBack
+    }
 }
 
+/// A change made to a collection, sent to its subscribers
 #[derive(Debug, Clone)]
+pub enum ChangeEvent<T> {
+    Inserted { key: String, value: T },
+    Updated { key: String, old: T, new: T },
+    Deleted { key: String, old: T },
+    Cleared,
+}
+
+impl<T: Clone> ChangeEvent<T> {
+    fn from_wal_operation(operation: &WalOperation<T>) -> Self {
+        match operation {
+            WalOperation::Insert { key, value } => ChangeEvent::Inserted {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            WalOperation::Update {
+                key,
+                new_value,
+                old_value,
+            } => ChangeEvent::Updated {
+                key: key.clone(),
+                old: old_value.clone(),
+                new: new_value.clone(),
+            },
+            WalOperation::Delete { key, value } => ChangeEvent::Deleted {
+                key: key.clone(),
+                old: value.clone(),
+            },
+            WalOperation::Drop => ChangeEvent::Cleared,
+        }
+    }
+}
+
+/// What `undo_operations` needs besides the logged operations to revert a failed transaction
+#[derive(Default)]
+struct Undo {
+    versions: Vec<u64>, // the previous version of every updated and deleted key, in order
+    cleared: Vec<storage::ClearedEntries>, // the entries of every clear, in order
+}
+
+/// The subscribers of a collection, with the events of the commits they haven't been sent yet
+/// Events wait in commit order until their logs are synced, so a subscriber never sees a change that could be lost
+struct Subscribers<T> {
+    senders: Vec<mpsc::Sender<ChangeEvent<T>>>,
+    pending: VecDeque<(usize, Vec<ChangeEvent<T>>)>, // with the number of logs queued for group commit once they were written
+}
+
+/// A page of entries returned by `Collection::page`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub entries: Vec<(String, T)>,
+    pub next: Option<String>, // pass as `after` to get the next page, None once there are no more entries
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TransactionStatus {
     Active,
     Committed,
@@ -82,31 +245,118 @@ pub enum TransactionStatus {
     Aborted,
 }
 
+/// Locks are always taken in this order, so two threads can't wait on each other:
+/// the write-ahead log, then the memtable, then the storage, then the secondary indexes
+/// A method may skip any of them, but must never take one while holding a later one
+/// The subscribers, compaction stats and metrics can be locked last, and are never held while waiting on another lock
+#[derive(Clone)]
 pub struct Collection<T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static> {
     memtable: Memtable<T>,
     storage: Storage,
     pub wal: Wal,
+    compaction_stats: Arc<RwLock<Option<CompactionStats>>>,
+    metrics: Arc<metrics::Metrics>,
+    subscribers: Arc<Mutex<Subscribers<T>>>,
+    indexes: Indexes<T>,
+    indexes_path: Option<path::PathBuf>, // None when secondary indexes aren't persisted
+    group_commit: Option<Arc<wal::GroupCommit>>,
+    lock_timeout: time::Duration,
+    max_transaction_ops: Option<usize>,
+    key_constraints: config::KeyConstraints,
+    snapshot_compression: config::CompressionConfig,
+    read_only: bool,
+    _compaction: Option<Arc<compaction::CompactionWorker>>, // stopped with the last handle
 }
 
-type Memtable<T> = Arc<RwLock<HashMap<String, T>>>;
+type Memtable<T> = Arc<RwLock<memtable::Memtable<T>>>;
 type Storage = Arc<RwLock<storage::Storage>>;
 type Wal = Arc<RwLock<wal::Wal>>;
+type Indexes<T> = Arc<RwLock<HashMap<String, Box<dyn SecondaryIndex<T>>>>>;
 
 impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Collection<T> {
-    pub fn new(config: config::DustDataConfig) -> Self {
-        let storage = Arc::new(RwLock::new(storage::Storage::new(config.clone()).unwrap()));
-        let wal = Arc::new(RwLock::new(wal::Wal::new(config.clone()).unwrap()));
+    pub fn new(config: config::DustDataConfig) -> Result<Self> {
+        let flush_threshold = config.storage.flush_threshold;
+        let memtable_eviction = config.storage.memtable_eviction;
+        let storage = storage::Storage::new(config.clone())?;
+        let mut memtable = memtable::Memtable::new(flush_threshold, memtable_eviction);
 
-        Self {
-            memtable: Arc::new(RwLock::new(HashMap::new())),
+        if let Some(budget) = config.storage.warm_start {
+            Self::warm_memtable(&storage, &mut memtable, budget);
+        }
+
+        let storage = Arc::new(RwLock::new(storage));
+        let wal = wal::Wal::new(config.clone())?;
+        let group_commit = wal.group_commit();
+        let wal = Arc::new(RwLock::new(wal));
+
+        let compaction_stats = Arc::new(RwLock::new(None));
+        let metrics = Arc::new(metrics::Metrics::default());
+        let read_only = config.lock_mode == config::LockMode::ReadOnly;
+        let indexes_path = (config.backend == config::StorageBackend::Disk && !read_only)
+            .then(|| config.data_path.join("indexes"));
+        let compaction = config
+            .compaction
+            .filter(|_| !read_only)
+            .map(|compaction_config| {
+                Arc::new(compaction::CompactionWorker::spawn(
+                    wal.clone(),
+                    storage.clone(),
+                    compaction_stats.clone(),
+                    metrics.clone(),
+                    compaction_config,
+                ))
+            });
+
+        Ok(Self {
+            memtable: Arc::new(RwLock::new(memtable)),
             wal,
             storage,
+            compaction_stats,
+            metrics,
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                senders: Vec::new(),
+                pending: VecDeque::new(),
+            })),
+            indexes: Arc::new(RwLock::new(HashMap::new())),
+            indexes_path,
+            group_commit,
+            lock_timeout: config.lock_timeout,
+            max_transaction_ops: config.max_transaction_ops,
+            key_constraints: config.key_constraints.clone(),
+            snapshot_compression: config.snapshot_compression.clone(),
+            read_only,
+            _compaction: compaction,
+        })
+    }
+
+    /// Loads the most recently written values into the memtable, oldest first so the newest are evicted last
+    /// Warming is best effort, a value that can't be read stops it without failing the open
+    fn warm_memtable(
+        storage: &storage::Storage,
+        memtable: &mut memtable::Memtable<T>,
+        budget: usize,
+    ) {
+        for key in storage.recent_keys(budget).into_iter().rev() {
+            match storage.get_tuple::<T>(key.clone()) {
+                Ok(Some(value)) => memtable.insert(key, value),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("Stopped warming the memtable at key {}: {}", key, err);
+
+                    break;
+                }
+            }
         }
+
+        memtable.evict();
     }
 
     /// Starts a new transaction
     pub fn start(&self) -> Transaction<T> {
-        Transaction::new()
+        let mut transaction = Transaction::new();
+        transaction.max_ops = self.max_transaction_ops;
+
+        transaction
     }
 
     pub fn start_lazy<F>(&self, f: F) -> Result<Transaction<T>>
@@ -120,12 +370,94 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
     }
 
     /// Commits a transaction
+    /// Returns `Error::TransactionTooLarge` if it holds more operations than `max_transaction_ops`
+    /// Returns `Error::Other` if a key is reserved or breaks the `key_constraints`
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(tx_id = transaction.tx_id))
+    )]
     pub fn commit(&self, transaction: &mut Transaction<T>) -> Result<()> {
         if let TransactionStatus::Committed = transaction.status {
-            panic!("Transaction already committed");
+            return Err(error::Error::TransactionAlreadyCommitted);
+        }
+
+        // There is nothing to write for an empty transaction, even when read-only.
+        if self.read_only && transaction.data.is_empty() {
+            transaction.status = TransactionStatus::Committed;
+
+            return Ok(());
         }
 
-        let mut wal = self.wal.try_write().map_err(|_| error::Error::Deadlock)?;
+        let mut wal = self.lock_wal()?;
+
+        self.commit_locked(&mut wal, transaction)?;
+
+        drop(wal);
+        self.wait_synced()
+    }
+
+    /// Waits until the logs committed so far are synced when `group_commit` is enabled, then sends their events
+    /// Call it once the write-ahead log lock is released, so the commits waiting for it can join the batch
+    fn wait_synced(&self) -> Result<()> {
+        let synced = match &self.group_commit {
+            Some(group_commit) => group_commit.wait()?,
+            None => usize::MAX,
+        };
+
+        self.send_events(synced);
+
+        Ok(())
+    }
+
+    /// Locks the write-ahead log, waiting while another commit holds it
+    /// Returns `Error::Deadlock` once the lock timeout runs out, and `Error::ReadOnly` for read-only collections
+    fn lock_wal(&self) -> Result<RwLockWriteGuard<'_, wal::Wal>> {
+        self.ensure_writable()?;
+
+        self.wal
+            .try_write_for(self.lock_timeout)
+            .ok_or(error::Error::Deadlock)
+    }
+
+    /// Commits a transaction while already holding the write-ahead log lock
+    /// Returns the logged operations, which hold the previous values
+    fn commit_locked(
+        &self,
+        wal: &mut wal::Wal,
+        transaction: &mut Transaction<T>,
+    ) -> Result<Vec<WalOperation<T>>> {
+        transaction.tx_id = wal.next_tx_id(transaction.tx_id);
+
+        self.commit_with_id(wal, transaction)
+    }
+
+    /// Same as `commit_locked`, but logs the transaction under its id as is
+    fn commit_with_id(
+        &self,
+        wal: &mut wal::Wal,
+        transaction: &mut Transaction<T>,
+    ) -> Result<Vec<WalOperation<T>>> {
+        if let Some(max_ops) = self.max_transaction_ops {
+            if transaction.data.len() > max_ops {
+                return Err(error::Error::TransactionTooLarge(max_ops));
+            }
+        }
+
+        for operation in &transaction.data {
+            match operation {
+                Operation::Insert(key, _) | Operation::Update(key, _) | Operation::Delete(key) => {
+                    if Self::is_reserved_key(key) {
+                        return Err(error::Error::Other(format!(
+                            "Invalid key: {} starts with the reserved prefix {}",
+                            key, RESERVED_KEY_PREFIX
+                        )));
+                    }
+
+                    self.key_constraints.check(key)?
+                }
+                Operation::Drop | Operation::DropPrefix(_) => {}
+            }
+        }
 
         let wal_operations = self.execute_operation(&transaction.data)?;
 
@@ -134,41 +466,359 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
             data: wal_operations,
         };
 
-        wal.write(transaction_log);
+        wal.write(&transaction_log)?;
+        self.notify(wal, &transaction_log.data);
 
         transaction.status = TransactionStatus::Committed;
 
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            tx_id = transaction.tx_id,
+            operations = transaction_log.data.len(),
+            "Committed transaction"
+        );
+
+        Ok(transaction_log.data)
+    }
+
+    /// Whether a key is reserved for internal use, see `RESERVED_KEY_PREFIX`
+    /// Committing an operation on a reserved key returns `Error::Other`
+    pub fn is_reserved_key(key: &str) -> bool {
+        key.starts_with(RESERVED_KEY_PREFIX)
+    }
+
+    /// Inserts a value under the next key of the collection's sequence, in its own transaction
+    /// Keys are zero-padded to `auto_key_width` digits, so their order matches the order they were generated in
+    /// The sequence is persisted and never hands out the same key twice, even to concurrent callers
+    /// Returns the generated key
+    pub fn insert_auto(&self, value: T) -> Result<String> {
+        let mut wal = self.lock_wal()?;
+
+        let key = self.storage.write().next_auto_key()?;
+
+        let mut transaction = self.start();
+        transaction.insert(&key, value);
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        drop(wal);
+        self.wait_synced()?;
+
+        Ok(key)
+    }
+
+    /// Deletes a key in its own transaction, committed right away
+    /// Returns the deleted value, or `None` if the key doesn't exist
+    pub fn delete_now(&self, key: &str) -> Result<Option<T>> {
+        let mut wal = self.lock_wal()?;
+
+        if !self.contains_exact(key)? {
+            return Ok(None);
+        }
+
+        let mut transaction = self.start();
+        transaction.delete(key);
+
+        let operation = self.commit_locked(&mut wal, &mut transaction)?.pop();
+
+        drop(wal);
+        self.wait_synced()?;
+
+        match operation {
+            Some(WalOperation::Delete { value, .. }) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Updates a key in its own transaction, committed right away
+    /// The key is inserted if it doesn't exist
+    /// Returns the previous value, or `None` if the key was inserted
+    pub fn update_now(&self, key: &str, value: T) -> Result<Option<T>> {
+        let mut wal = self.lock_wal()?;
+
+        let mut transaction = self.start();
+
+        if self.contains_exact(key)? {
+            transaction.update(key, value);
+        } else {
+            transaction.insert(key, value);
+        }
+
+        let operation = self.commit_locked(&mut wal, &mut transaction)?.pop();
+
+        drop(wal);
+        self.wait_synced()?;
+
+        match operation {
+            Some(WalOperation::Update { old_value, .. }) => Ok(Some(old_value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets a value, or inserts the one `f` returns in its own transaction if the key doesn't exist
+    /// Concurrent callers for the same key run `f` once, the others get the inserted value
+    pub fn get_or_insert_with<F>(&self, key: &str, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+
+        let mut wal = self.lock_wal()?;
+
+        // Checked again with the write-ahead log lock held, another commit may have
+        // inserted the key since.
+        if let Some(value) = self.storage.read().get_tuple::<T>(key.to_string())? {
+            return Ok(value);
+        }
+
+        let value = f();
+
+        let mut transaction = self.start();
+        transaction.insert(key, value.clone());
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        drop(wal);
+        self.wait_synced()?;
+
+        Ok(value)
+    }
+
+    /// Changes a value in place with `f` and commits it as an update in its own transaction
+    /// The write-ahead log lock is held from the read to the commit, so no other commit changes the value in between
+    /// Returns the new value, or `None` without calling `f` if the key doesn't exist
+    pub fn modify<F>(&self, key: &str, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut T),
+    {
+        let mut wal = self.lock_wal()?;
+
+        let Some(mut value) = self.storage.read().get_tuple::<T>(key.to_string())? else {
+            return Ok(None);
+        };
+
+        f(&mut value);
+
+        let mut transaction = self.start();
+        transaction.update(key, value.clone());
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        drop(wal);
+        self.wait_synced()?;
+
+        Ok(Some(value))
+    }
+
+    /// Creates a loader that inserts values in batches, committing every `batch_size` values
+    /// Use it instead of one big transaction for large imports, so memory stays bounded
+    pub fn bulk_loader(&self) -> BulkLoader<T> {
+        BulkLoader::new(self.clone())
+    }
+
+    /// Deletes every key within a range in a single transaction
+    /// Returns the number of deleted keys
+    pub fn delete_range<R>(&self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<String>,
+    {
+        // Holding the write-ahead log lock keeps other commits from changing the keys
+        // between listing and deleting them.
+        let mut wal = self.lock_wal()?;
+
+        let keys = self.storage.read().keys_in_range(range);
+
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let mut transaction = self.start();
+
+        for key in &keys {
+            transaction.delete(key);
+        }
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        drop(wal);
+        self.wait_synced()?;
+
+        Ok(keys.len())
+    }
+
+    /// Rebuilds the collection as it was right after a transaction was committed
+    /// This clears the collection and replays every logged transaction up to `tx_id`
+    /// Replay stops at the first record that can't be read, like one torn by a crash during its append, and the transactions before it are kept
+    /// The recovery is committed as a transaction of its own, so followers and deltas see it
+    /// Returns `Error::Other` once `max_retained_logs` pruned the oldest log chunks, and `Error::TransactionTooLarge` if the replayed operations pass `max_transaction_ops`
+    pub fn recover_to(&self, tx_id: usize) -> Result<()> {
+        let mut wal = self.lock_wal()?;
+
+        if wal.index.get(tx_id).is_none() {
+            return Err(error::Error::NotFound(format!("Transaction {}", tx_id)));
+        }
+
+        // Replaying a pruned log would rebuild the collection without its oldest transactions.
+        if wal.is_pruned()? {
+            return Err(error::Error::Other(
+                "The write-ahead log was pruned, the collection can't be rebuilt from it"
+                    .to_string(),
+            ));
+        }
+
+        let mut transaction = self.start();
+        transaction.data.push(Operation::Drop);
+        let mut replayed = 0;
+
+        for (id, (log_chunk, offset)) in wal.index.diff(..=tx_id) {
+            match wal.read_by_offset_and_log_chunk::<T>(offset, log_chunk) {
+                Ok(Some(log)) => {
+                    transaction
+                        .data
+                        .extend(log.data.iter().map(WalOperation::operation));
+                    replayed += 1;
+                }
+                Ok(None) => {}
+                Err(
+                    err @ (error::Error::CorruptedData(_) | error::Error::MissingWalChunk { .. }),
+                ) => {
+                    warn!(
+                        "Stopped recovery at transaction {} after replaying {} transactions: {}",
+                        id, replayed, err
+                    );
+
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            tx_id,
+            replayed,
+            operations = transaction.data.len(),
+            "Recovered collection"
+        );
+
+        drop(wal);
+        self.wait_synced()
+    }
+
+    /// Reads the logged transactions committed after `tx_id`, in commit order
+    /// A follower passes the id of the last transaction it applied to get the ones it's missing
+    pub fn wal_since(&self, tx_id: usize) -> Result<Vec<TransactionLog<T>>> {
+        let wal = self.wal.read();
+
+        let mut logs = Vec::new();
+
+        for (_, (log_chunk, offset)) in wal
+            .index
+            .diff((ops::Bound::Excluded(tx_id), ops::Bound::Unbounded))
+        {
+            if let Some(log) = wal.read_by_offset_and_log_chunk::<T>(offset, log_chunk)? {
+                logs.push(log);
+            }
+        }
+
+        Ok(logs)
+    }
+
+    /// Returns the id of the last logged transaction, or `None` if nothing was committed
+    pub fn wal_head(&self) -> Option<usize> {
+        self.wal.read().index.get_head()
+    }
+
+    /// Replays transactions read with `wal_since` from another instance, making this collection a replica of it
+    /// The transactions are applied in id order and logged with their original ids, so applying one that is already in the write-ahead log skips it
+    /// Instances pick ids on their own, so an id logged here with other operations returns `Error::CorruptedData`
+    /// Stops at the first transaction that fails, the ones before it stay applied
+    pub fn apply_wal(&self, mut logs: Vec<TransactionLog<T>>) -> Result<()> {
+        let mut wal = self.lock_wal()?;
+
+        logs.sort_by_key(|log| log.id);
+
+        let result = logs
+            .into_iter()
+            .try_for_each(|log| self.apply_log(&mut wal, log));
+
+        drop(wal);
+        self.wait_synced()?;
+
+        result
+    }
+
+    /// Commits a transaction of another instance under its id, unless the same transaction is already logged
+    fn apply_log(&self, wal: &mut wal::Wal, log: TransactionLog<T>) -> Result<()> {
+        if wal.index.get(log.id).is_some() {
+            // Compared encoded, since values don't have to implement `PartialEq`.
+            let encode = |log: &TransactionLog<T>| bincode::serialize(&log.data).ok();
+            let logged = wal.read::<T>(log.id)?;
+
+            if logged.is_some_and(|logged| {
+                encode(&logged).is_some_and(|bytes| Some(bytes) == encode(&log))
+            }) {
+                return Ok(());
+            }
+
+            return Err(error::Error::CorruptedData(format!(
+                "Transaction {} is already logged with other operations",
+                log.id
+            )));
+        }
+
+        let mut transaction = self.start();
+        transaction.tx_id = log.id;
+        transaction.data = log.data.iter().map(WalOperation::operation).collect();
+
+        self.commit_with_id(wal, &mut transaction)?;
+
         Ok(())
     }
 
     /// Aborts a transaction
-    pub fn abort_transaction(&self, transaction: &mut Transaction<T>) {
+    pub fn abort_transaction(&self, transaction: &mut Transaction<T>) -> Result<()> {
         if let TransactionStatus::Committed = transaction.status {
-            panic!("Transaction already committed");
+            return Err(error::Error::TransactionAlreadyCommitted);
         }
 
         transaction.status = TransactionStatus::Aborted;
+
+        Ok(())
+    }
+
+    /// Returns the operations that rolling back a transaction would commit, without committing them
+    /// Empty if the transaction isn't in the write-ahead log, and `Error::Deadlock` once the lock timeout runs out
+    pub fn dry_run_rollback(&self, tx_id: usize) -> Result<Vec<Operation<T>>> {
+        let wal = self
+            .wal
+            .try_read_for(self.lock_timeout)
+            .ok_or(error::Error::Deadlock)?;
+
+        Ok(wal.revert::<T>(tx_id)?.data)
     }
 
     /// Rolls back a transaction
     /// This will revert all operations in the transaction
     /// Returns the reverted transaction
     pub fn rollback_transaction(&self, transaction: &mut Transaction<T>) -> Result<Transaction<T>> {
-        match transaction.status {
-            TransactionStatus::RolledBack => panic!("Transaction already rolled back"),
-            TransactionStatus::Active => panic!("Transaction not committed"),
-            TransactionStatus::Aborted => panic!("Transaction aborted"),
-            _ => {}
-        }
+        Self::ensure_committed(transaction)?;
 
-        let tx_id = transaction.tx_id;
+        let mut wal = self.lock_wal()?;
 
-        let mut rollback_transaction = {
-            let mut wal = self.wal.write().map_err(|_| error::Error::Deadlock)?;
-            wal.revert::<T>(tx_id)?
-        };
+        // Holding the write-ahead log lock keeps another commit from changing the keys
+        // between reading the operations to revert and committing them.
+        let mut rollback_transaction = self.start();
+        rollback_transaction.extend(wal.revert::<T>(transaction.tx_id)?.data);
+
+        self.commit_locked(&mut wal, &mut rollback_transaction)?;
 
-        self.commit(&mut rollback_transaction)?;
+        drop(wal);
+        self.wait_synced()?;
 
         transaction.status = TransactionStatus::RolledBack;
 
@@ -178,44 +828,282 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
     /// Resets a transaction.
     /// This will revert all operations in the transaction without committing it
     pub fn reset_transaction<R>(&self, transaction: &mut Transaction<T>) -> Result<()> {
-        match transaction.status {
-            TransactionStatus::RolledBack => panic!("Transaction already rolled back"),
-            TransactionStatus::Active => panic!("Transaction not committed"),
-            TransactionStatus::Aborted => panic!("Transaction aborted"),
-            _ => {}
-        }
+        Self::ensure_committed(transaction)?;
 
         let tx_id = transaction.tx_id;
 
-        let mut wal = self.wal.write().map_err(|_| error::Error::Deadlock)?;
-        let revert_transaction = wal.revert::<T>(tx_id).unwrap();
+        // The write-ahead log lock is held while the revert is applied, like any other commit.
+        let wal = self.lock_wal()?;
+        let revert_transaction = wal.revert::<T>(tx_id)?;
+
+        let wal_operations = self.execute_operation(&revert_transaction.data)?;
+        self.notify(&wal, &wal_operations);
+
+        transaction.status = TransactionStatus::Active;
 
         drop(wal);
+        self.wait_synced()
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(error::Error::ReadOnly);
+        }
 
-        self.execute_operation(&revert_transaction.data).unwrap();
+        Ok(())
+    }
 
-        transaction.status = TransactionStatus::Active;
+    fn ensure_committed(transaction: &Transaction<T>) -> Result<()> {
+        match transaction.status {
+            TransactionStatus::RolledBack => Err(error::Error::InvalidTransactionState(
+                "transaction already rolled back".to_string(),
+            )),
+            TransactionStatus::Active => Err(error::Error::InvalidTransactionState(
+                "transaction not committed".to_string(),
+            )),
+            TransactionStatus::Aborted => Err(error::Error::InvalidTransactionState(
+                "transaction aborted".to_string(),
+            )),
+            TransactionStatus::Committed => Ok(()),
+        }
+    }
+
+    /// Forces the collection state to disk
+    /// This persists the storage index and the secondary indexes, and syncs the data chunks and the write-ahead log
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn flush(&self) -> Result<()> {
+        let mut wal = self.wal.write();
+        wal.flush()?;
+        self.storage.write().flush()?;
+
+        let wal_head = wal.index.get_head();
+
+        for index in self.indexes.write().values_mut() {
+            index.write(wal_head)?;
+        }
+
+        wal.prune()?;
+
+        self.metrics.flush();
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(wal_head, "Flushed collection");
 
         Ok(())
     }
 
-    /// Checks if the collection contains a key
+    /// Copies the data chunks, index and blobs to `path` with commits held off, for `DustData::clone_collection`
+    pub(crate) fn copy_data_to(&self, path: &path::Path) -> Result<()> {
+        let _wal = self.lock_wal()?;
+
+        self.storage.write().copy_to(path)
+    }
+
+    /// The number of handles sharing this collection's state, for `DustData::drop_collection`
+    pub(crate) fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.memtable)
+    }
+
+    /// Stops the background compaction of every handle, for `DustData` to unlock once nothing writes anymore
+    pub(crate) fn stop_compaction(&self) {
+        if let Some(compaction) = &self._compaction {
+            compaction.stop();
+        }
+    }
+
+    /// Compacts the data chunks, reclaiming the space of deleted and overwritten values
+    /// Reads keep using the old chunks until the new ones are swapped in at the end, commits wait until the compaction is done
+    pub fn compact(&self) -> Result<CompactionStats> {
+        let _wal = self.lock_wal()?;
+
+        let stats = compaction::compact(&self.storage)?;
+
+        *self.compaction_stats.write() = Some(stats);
+        self.metrics.compaction();
+
+        Ok(stats)
+    }
+
+    /// Merges the data chunks that are less than half full into fewer files
+    /// Cheaper than `compact` when only a few chunks are small, dead bytes in the full chunks are kept
+    pub fn coalesce(&self) -> Result<CompactionStats> {
+        let _wal = self.lock_wal()?;
+
+        self.storage.write().coalesce()
+    }
+
+    /// Rebuilds the index and the bloom filter from the data chunks, returning the number of entries rebuilt
+    /// Useful when the index file is lost or corrupted, the data chunks are the source of truth
+    pub fn rebuild_index(&self) -> Result<usize> {
+        let _wal = self.lock_wal()?;
+
+        self.storage.write().rebuild_index()
+    }
+
+    /// Checks the index against the data chunks and reports any inconsistency, without changing anything
+    /// Use `rebuild_index` to repair an index that isn't clean
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        self.storage.read().check_integrity::<T>()
+    }
+
+    /// Returns the number of keys in the collection
+    /// The index is kept in memory, so this is exact and doesn't read any file
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.storage.read().len())
+    }
+
+    /// Returns an estimate of the number of keys, for metrics that don't need an exact count
+    /// The index is kept in memory, so this is the count of `len`, which never drifts and needs no reset on compaction
+    pub fn estimated_len(&self) -> usize {
+        self.storage.read().len()
+    }
+
+    /// Checks if the collection has no keys
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the number of keys and the on-disk sizes of the collection
+    /// `dead_bytes` against `data_chunk_bytes` tells how much a `compact` would reclaim
+    pub fn stats(&self) -> Result<CollectionStats> {
+        let mut stats = self.storage.read().stats()?;
+        stats.wal_log_chunks = self.wal.read().log_chunks()?;
+
+        Ok(stats)
+    }
+
+    /// Returns the size and fill of the key bloom filter
+    /// A `fill_ratio` close to 1 means the filter is saturated and most lookups of missing keys pass it
+    pub fn bloom_stats(&self) -> BloomStats {
+        self.storage.read().bloom_stats()
+    }
+
+    /// Returns the operation counters of the collection, shared by every handle
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Returns the stats of the last compaction, manual or in the background
+    pub fn compaction_stats(&self) -> Option<CompactionStats> {
+        *self.compaction_stats.read()
+    }
+
+    /// Flushes the collection and closes this handle, returning any persistence error
+    /// Other handles to the same collection stay open
+    /// Dropping without calling `close` is still safe, but errors are silent
+    pub fn close(self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Subscribes to the changes made to the collection
+    /// Events are sent in commit order once a transaction is committed and its log written, or synced with `group_commit`
+    /// Staged operations aren't sent
+    /// Changes made by `recover_to` and `reset_transaction` are sent too
+    /// Dropping the receiver unsubscribes
+    pub fn subscribe(&self) -> mpsc::Receiver<ChangeEvent<T>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().senders.push(sender);
+
+        receiver
+    }
+
+    /// Queues the events of the applied operations once their log is written, `wait_synced` sends them
+    /// Called with the write-ahead log lock held, so events are queued in commit order
+    fn notify(&self, wal: &wal::Wal, operations: &[WalOperation<T>]) {
+        let mut subscribers = self.subscribers.lock();
+
+        if subscribers.senders.is_empty() {
+            return;
+        }
+
+        let events = operations
+            .iter()
+            .map(ChangeEvent::from_wal_operation)
+            .collect();
+        subscribers.pending.push_back((wal.queued(), events));
+    }
+
+    /// Sends the queued events of the first `synced` logs to every subscriber, pruning the dropped ones
+    fn send_events(&self, synced: usize) {
+        let mut subscribers = self.subscribers.lock();
+        let Subscribers { senders, pending } = &mut *subscribers;
+
+        while pending.front().is_some_and(|(queued, _)| *queued <= synced) {
+            let (_, events) = pending.pop_front().unwrap();
+
+            for event in events {
+                senders.retain(|sender| sender.send(event.clone()).is_ok());
+            }
+        }
+    }
+
+    /// Checks if the collection may contain a key, using only the bloom filter
+    /// A `false` is always right, but a `true` can be a false positive for a key that was never inserted
+    /// Use `contains_exact` when a false positive matters
     pub fn contains(&self, key: &str) -> Result<bool> {
-        Ok(self.storage.read().unwrap().contains(key))
+        Ok(self.storage.read().contains(key))
+    }
+
+    /// Checks if the collection contains a key, without false positives
+    /// The bloom filter rules out missing keys fast, and a possible hit is confirmed against the index
+    pub fn contains_exact(&self, key: &str) -> Result<bool> {
+        Ok(self.storage.read().contains_exact(key))
+    }
+
+    /// Checks many keys like `contains_exact`, taking the storage lock once
+    /// Returns one result per key, in the order of `keys`
+    pub fn contains_many(&self, keys: &[&str]) -> Result<Vec<bool>> {
+        let storage = self.storage.read();
+
+        Ok(keys.iter().map(|key| storage.contains_exact(key)).collect())
+    }
+
+    /// Checks if any key may start with `prefix`, a cheap guard before a prefix scan
+    /// With `StorageConfig::prefix_filter_depth` set it checks the prefix bloom filter, so a `false` is always right but a `true` can be a false positive
+    /// Without it the index is checked, which is exact
+    pub fn prefix_exists(&self, prefix: &str) -> Result<bool> {
+        Ok(self.storage.read().prefix_exists(prefix))
+    }
+
+    /// Returns the estimated size in bytes of the values cached in memory
+    pub fn memtable_size(&self) -> Result<usize> {
+        Ok(self.memtable.read().size())
+    }
+
+    /// Returns a reader of the serialized value of a key, without reading the whole value into memory
+    /// The reader yields the bincode encoding of the value, so it can be parsed with `bincode::deserialize_from` or a streaming deserializer
+    /// Values stored compressed are decompressed while they're read
+    pub fn get_streaming(&self, key: &str) -> Result<Option<Box<dyn std::io::Read + Send>>> {
+        self.metrics.get();
+
+        self.storage.read().value_reader(key)
     }
 
     /// Gets a value from the collection
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(key = key))
+    )]
     pub fn get(&self, key: &str) -> Result<Option<T>> {
+        self.metrics.get();
+
         if !self.contains(key)? {
             return Ok(None);
         }
 
-        let memtable = self.memtable.read().map_err(|_| error::Error::Deadlock)?;
+        // Reads reorder an LRU memtable, which needs the write lock.
+        let cached = if self.memtable.read().eviction() == config::MemtableEviction::Lru {
+            self.memtable.write().touch(key).cloned()
+        } else {
+            self.memtable.read().get(key).cloned()
+        };
+
+        self.metrics.cache(cached.is_some());
 
-        if let Some(value) = memtable.get(key) {
-            Ok(Some(value.clone()))
+        if let Some(value) = cached {
+            Ok(Some(value))
         } else {
-            let storage = self.storage.read().unwrap().get_tuple(key.to_owned())?;
+            let storage = self.storage.read().get_tuple(key.to_owned())?;
             if let Some(value) = storage {
                 Ok(Some(value))
             } else {
@@ -224,32 +1112,381 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
         }
     }
 
-    fn execute_operation(&self, operations: &Vec<Operation<T>>) -> Result<Vec<WalOperation<T>>> {
-        let mut memtable = self.memtable.write().map_err(|_| error::Error::Deadlock)?;
-        let mut storage = self.storage.write().map_err(|_| error::Error::Deadlock)?;
+    /// Gets a value by reference, returning what `f` makes of it, without cloning a cached value
+    /// `f` runs under the memtable lock for a cached value, a write lock with `MemtableEviction::Lru`, so it shouldn't touch this collection
+    /// A value that isn't cached is read from disk and borrowed, with no lock held
+    pub fn with_value<R, F>(&self, key: &str, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.metrics.get();
+
+        if !self.contains(key)? {
+            return Ok(None);
+        }
+
+        // Reads reorder an LRU memtable, which needs the write lock.
+        if self.memtable.read().eviction() == config::MemtableEviction::Lru {
+            if let Some(value) = self.memtable.write().touch(key) {
+                self.metrics.cache(true);
+                return Ok(Some(f(value)));
+            }
+        } else if let Some(value) = self.memtable.read().get(key) {
+            self.metrics.cache(true);
+            return Ok(Some(f(value)));
+        }
+
+        self.metrics.cache(false);
+
+        let value = self.storage.read().get_tuple::<T>(key.to_owned())?;
+
+        Ok(value.map(|value| f(&value)))
+    }
+
+    /// Gets a value with its version, which starts at 1 on insert and grows by one on every update
+    /// Values written before versions were stored are at version 0
+    pub fn get_versioned(&self, key: &str) -> Result<Option<(T, u64)>> {
+        self.metrics.get();
+
+        self.storage.read().get_versioned_tuple(key)
+    }
+
+    /// Updates a key in its own transaction if its version is still `expected_version`
+    /// Returns the new version, `Error::VersionConflict` with the stored version if it changed, or `Error::NotFound`
+    pub fn update_if_version(&self, key: &str, value: T, expected_version: u64) -> Result<u64> {
+        let mut wal = self.lock_wal()?;
+
+        // Holding the write-ahead log lock keeps another commit from updating the key
+        // between the check and the update.
+        let version = match self.storage.read().get_versioned_tuple::<T>(key)? {
+            Some((_, version)) => version,
+            None => return Err(error::Error::NotFound(key.to_string())),
+        };
+
+        if version != expected_version {
+            return Err(error::Error::VersionConflict(version));
+        }
+
+        let mut transaction = self.start();
+        transaction.update(key, value);
+
+        self.commit_locked(&mut wal, &mut transaction)?;
+
+        drop(wal);
+        self.wait_synced()?;
+
+        Ok(version + 1)
+    }
+
+    /// Gets the entry with the smallest key
+    pub fn first(&self) -> Result<Option<(String, T)>> {
+        let storage = self.storage.read();
+
+        match storage.next_key(None) {
+            Some(key) => Ok(storage.get_tuple(key.clone())?.map(|value| (key, value))),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the entry with the largest key
+    pub fn last(&self) -> Result<Option<(String, T)>> {
+        let storage = self.storage.read();
+
+        match storage.prev_key(None) {
+            Some(key) => Ok(storage.get_tuple(key.clone())?.map(|value| (key, value))),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterates over the entries in descending key order, starting at the largest key
+    /// Each step looks up the next smaller key in the index, so taking the last N entries costs N lookups
+    /// The storage isn't locked between steps, keys committed behind the cursor are skipped and deleted keys aren't returned
+    pub fn iter_rev(&self) -> impl Iterator<Item = Result<(String, T)>> + '_ {
+        let mut cursor: Option<String> = None;
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            let storage = self.storage.read();
+
+            loop {
+                let Some(key) = storage.prev_key(cursor.as_deref()) else {
+                    done = true;
+                    return None;
+                };
+
+                cursor = Some(key.clone());
+
+                match storage.get_tuple::<T>(key.clone()) {
+                    Ok(Some(value)) => return Some(Ok((key, value))),
+                    Ok(None) => continue,
+                    Err(err) => {
+                        done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Gets up to `limit` entries with keys strictly greater than `after`, in ascending key order
+    /// Pages follow the key order, so keys inserted between calls show up in a later page only if they sort after the cursor, and no key is returned twice
+    /// The cursor doesn't have to exist anymore, paging continues after a deleted `after` key
+    pub fn page(&self, after: Option<&str>, limit: usize) -> Result<Page<T>> {
+        let storage = self.storage.read();
+
+        let mut entries = Vec::new();
+        let mut cursor = after.map(str::to_string);
+
+        while entries.len() < limit {
+            let Some(key) = storage.next_key(cursor.as_deref()) else {
+                break;
+            };
+
+            cursor = Some(key.clone());
+
+            if let Some(value) = storage.get_tuple::<T>(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+
+        let next = match entries.last() {
+            Some((key, _)) if storage.next_key(Some(key)).is_some() => Some(key.clone()),
+            _ => None,
+        };
+
+        Ok(Page { entries, next })
+    }
+
+    /// Returns the values `predicate` accepts, with their keys, in ascending key order
+    /// This is a full scan: every value of the collection is read from the data chunks and deserialized, use a secondary index for frequent queries
+    pub fn filter<F>(&self, predicate: F) -> Result<Vec<(String, T)>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut entries = Vec::new();
+
+        self.storage.read().for_each_value(|key, value: T| {
+            if predicate(&value) {
+                entries.push((key.to_string(), value));
+            }
+        })?;
+
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(entries)
+    }
+
+    /// Counts the values `predicate` accepts, a full scan like `filter` that doesn't keep the values
+    pub fn count_where<F>(&self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut count = 0;
+
+        self.storage.read().for_each_value(|_, value: T| {
+            if predicate(&value) {
+                count += 1;
+            }
+        })?;
+
+        Ok(count)
+    }
+
+    /// Creates a secondary index named `name`, keyed by the value `extractor` returns for each value
+    /// The extractor runs on every committed insert, update and delete, so the index stays consistent with the collection
+    /// The index is persisted on `flush` and rebuilt from the values when it's missing or out of date
+    /// Indexes aren't registered across restarts, `create_index` has to be called again after opening the collection
+    pub fn create_index<K, F>(&self, name: &str, extractor: F) -> Result<()>
+    where
+        K: Ord + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        let mut components = path::Path::new(name).components();
+
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(path::Component::Normal(_)), None)
+        ) {
+            return Err(error::Error::Other(format!("Invalid index name {}", name)));
+        }
+
+        // Holding the write-ahead log lock keeps commits out while the index is built.
+        let wal = self
+            .wal
+            .try_write_for(self.lock_timeout)
+            .ok_or(error::Error::Deadlock)?;
+
+        if self.indexes.read().contains_key(name) {
+            return Err(error::Error::AlreadyExists(format!("Index {}", name)));
+        }
+
+        let path = match &self.indexes_path {
+            Some(indexes_path) => {
+                fs::create_dir_all(indexes_path)?;
+                Some(indexes_path.join(format!("{}.idx", name)))
+            }
+            None => None,
+        };
+
+        let (mut index, loaded) =
+            FieldIndex::open(Box::new(extractor), path, wal.index.get_head())?;
+
+        let storage = self.storage.read();
+
+        if !loaded {
+            for key in storage.keys_in_range(..) {
+                if let Some(value) = storage.get_tuple::<T>(key.clone())? {
+                    index.insert(&key, &value);
+                }
+            }
+        }
+
+        self.indexes
+            .write()
+            .insert(name.to_string(), Box::new(index));
+
+        Ok(())
+    }
+
+    /// Gets every key and value whose indexed key is `value`, in ascending key order
+    /// Returns `Error::NotFound` if there is no index named `name`
+    pub fn find_by_index<K>(&self, name: &str, value: &K) -> Result<Vec<(String, T)>>
+    where
+        K: Ord + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        // The storage lock is held so the values match the index.
+        let storage = self.storage.read();
+        let indexes = self.indexes.read();
+
+        let index = indexes
+            .get(name)
+            .ok_or_else(|| error::Error::NotFound(format!("Index {}", name)))?;
+
+        let keys = index
+            .as_any()
+            .downcast_ref::<FieldIndex<T, K>>()
+            .ok_or_else(|| {
+                error::Error::Other(format!(
+                    "Index {} was created with a different key type",
+                    name
+                ))
+            })?
+            .find(value);
+
+        let mut entries = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            if let Some(value) = storage.get_tuple::<T>(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Applies operations to the memtable, the storage and the secondary indexes
+    /// The caller must hold the write-ahead log lock, the first one of the lock order
+    fn execute_operation(&self, operations: &[Operation<T>]) -> Result<Vec<WalOperation<T>>> {
+        debug_assert!(
+            self.wal.is_locked_exclusive(),
+            "execute_operation called without the write-ahead log lock"
+        );
+
+        let mut memtable = self.memtable.write();
+        let mut storage = self.storage.write();
 
         let mut wal_operations = Vec::new();
+        let mut undo = Undo::default();
+        let total_bytes = storage.total_bytes();
 
-        for operation in operations {
+        if let Err(err) = Self::apply_operations(
+            &mut memtable,
+            &mut storage,
+            operations,
+            &mut wal_operations,
+            &mut undo,
+        ) {
+            // The memtable is only a cache of the storage, so it's dropped rather than undone.
+            memtable.clear();
+            Self::undo_operations(&mut storage, wal_operations, undo)?;
+
+            return Err(err);
+        }
+
+        memtable.evict();
+
+        self.metrics
+            .bytes_written(storage.total_bytes().saturating_sub(total_bytes));
+
+        for operation in &wal_operations {
+            match operation {
+                WalOperation::Insert { .. } => self.metrics.insert(),
+                WalOperation::Update { .. } => self.metrics.update(),
+                WalOperation::Delete { .. } => self.metrics.delete(),
+                WalOperation::Drop => {}
+            }
+        }
+
+        for index in self.indexes.write().values_mut() {
+            for operation in &wal_operations {
+                index.apply(operation)?;
+            }
+        }
+
+        Ok(wal_operations)
+    }
+
+    /// Applies the operations in order, logging each applied one in `wal_operations`
+    /// The index entries removed by every `Operation::Drop` are kept in `cleared` to undo it
+    fn apply_operations(
+        memtable: &mut memtable::Memtable<T>,
+        storage: &mut storage::Storage,
+        operations: &[Operation<T>],
+        wal_operations: &mut Vec<WalOperation<T>>,
+        undo: &mut Undo,
+    ) -> Result<()> {
+        let mut operations = operations.iter().peekable();
+
+        while let Some(operation) = operations.next() {
             let operation = match operation {
                 Operation::Insert(key, value) => {
-                    memtable.insert(key.to_owned(), value.clone());
+                    // Consecutive inserts are written to the data chunk in one go.
+                    let mut inserts = vec![(key, value)];
 
-                    let tuple_entry = storage::StorageTupleEntry {
-                        key: key.to_owned(),
-                        value: value.clone(),
-                    };
+                    while let Some(Operation::Insert(key, value)) = operations.peek() {
+                        inserts.push((key, value));
+                        operations.next();
+                    }
 
-                    storage.insert_tuple(tuple_entry)?;
+                    let tuple_entries = inserts
+                        .iter()
+                        .map(|(key, value)| storage::StorageTupleEntry {
+                            key: key.to_string(),
+                            value: (*value).clone(),
+                        })
+                        .collect();
 
-                    WalOperation::Insert {
-                        key: key.to_string(),
-                        value: value.clone(),
+                    storage.insert_tuples(tuple_entries)?;
+
+                    for (key, value) in inserts {
+                        memtable.insert(key.to_owned(), value.clone());
+
+                        wal_operations.push(WalOperation::Insert {
+                            key: key.to_string(),
+                            value: value.clone(),
+                        });
                     }
+
+                    continue;
                 }
                 Operation::Delete(key) => {
                     memtable.remove(key.as_str());
-                    let old_value = storage.remove_tuple(key.to_owned())?;
+                    let (old_value, version) = storage.remove_tuple(key.to_owned())?;
+                    undo.versions.push(version);
 
                     WalOperation::Delete {
                         key: key.to_string(),
@@ -264,7 +1501,8 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
                         value: value.clone(),
                     };
 
-                    let old_value = storage.update_tuple(tuple_entry)?;
+                    let (old_value, version) = storage.update_tuple(tuple_entry)?;
+                    undo.versions.push(version);
 
                     WalOperation::Update {
                         key: key.to_string(),
@@ -272,9 +1510,20 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
                         old_value,
                     }
                 }
+                Operation::DropPrefix(prefix) => {
+                    for key in storage.keys_with_prefix(prefix) {
+                        memtable.remove(key.as_str());
+                        let (value, version) = storage.remove_tuple(key.clone())?;
+                        undo.versions.push(version);
+
+                        wal_operations.push(WalOperation::Delete { key, value });
+                    }
+
+                    continue;
+                }
                 Operation::Drop => {
                     memtable.clear();
-                    storage.clear()?;
+                    undo.cleared.push(storage.clear()?);
 
                     WalOperation::Drop
                 }
@@ -283,7 +1532,35 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Co
             wal_operations.push(operation);
         }
 
-        Ok(wal_operations)
+        Ok(())
+    }
+
+    /// Reverts the applied operations of a failed transaction, last first
+    /// Old values are written back at their old versions, so a failed commit doesn't change them
+    fn undo_operations(
+        storage: &mut storage::Storage,
+        wal_operations: Vec<WalOperation<T>>,
+        mut undo: Undo,
+    ) -> Result<()> {
+        for operation in wal_operations.into_iter().rev() {
+            match operation {
+                WalOperation::Insert { key, .. } => {
+                    storage.remove_tuple::<T>(key)?;
+                }
+                WalOperation::Update {
+                    key,
+                    old_value: value,
+                    ..
+                }
+                | WalOperation::Delete { key, value } => {
+                    let version = undo.versions.pop().unwrap();
+                    storage.restore_tuple(storage::StorageTupleEntry { key, value }, version)?;
+                }
+                WalOperation::Drop => storage.restore(undo.cleared.pop().unwrap())?,
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -295,3 +1572,36 @@ pub fn get_current_timestamp() -> usize {
     .try_into()
     .unwrap()
 }
+
+/// Copies a directory and its subdirectories file by file, skipping the `.tmp` files
+/// left by `write_file_atomically`.
+pub(crate) fn copy_dir_all(src: &path::Path, dst: &path::Path) -> std::io::Result<()> {
+    fs::create_dir_all(dst)?;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&path, &dst.join(entry.file_name()))?;
+        } else if path.extension().is_none_or(|extension| extension != "tmp") {
+            fs::copy(&path, dst.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a file by writing to `<path>.tmp` first and renaming it over `path`,
+/// so a crash mid-write never leaves a partially written file behind.
+fn write_file_atomically(path: &path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = path::PathBuf::from(tmp_path);
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
@@ -0,0 +1,328 @@
+use super::{
+    backend, get_current_timestamp, wal, write_file_atomically, Collection, Transaction,
+    TransactionStatus, Wal,
+};
+use crate::config;
+use crate::error::{Error, Result};
+use crate::DustData;
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, path, time};
+
+const COORDINATOR_DIRNAME: &str = ".dustdata-transactions";
+
+/// Records the transactions spanning several collections while they're committed, so one
+/// cut short by a crash is discarded on the next open instead of staying logged in only
+/// some of the collections.
+/// Each commit writes a `<n>.intent` file holding its id and the write-ahead log
+/// directories of its collections, and removes it once every log agrees.
+pub(crate) struct Coordinator {
+    path: path::PathBuf,
+    next: AtomicUsize, // names the intent files, unique while the instance is open
+}
+
+impl Coordinator {
+    /// Discards the transactions of the intents left by a crash from the write-ahead logs
+    /// of their collections, then removes the intents.
+    /// Their values were never persisted, the storage index isn't written while the logs
+    /// are locked for a commit.
+    pub fn open(
+        data_path: &path::Path,
+        compression: Option<config::CompressionConfig>,
+    ) -> Result<Self> {
+        let path = data_path.join(COORDINATOR_DIRNAME);
+
+        fs::create_dir_all(&path)?;
+
+        for entry in fs::read_dir(&path)? {
+            let intent = entry?.path();
+
+            if intent
+                .extension()
+                .is_some_and(|extension| extension == "intent")
+            {
+                let (tx_id, logs): (usize, Vec<path::PathBuf>) =
+                    bincode::deserialize(&fs::read(&intent)?).map_err(|e| {
+                        Error::CorruptedData(format!(
+                            "Corrupted transaction intent {}. Error: {}",
+                            intent.display(),
+                            e
+                        ))
+                    })?;
+
+                // Collections dropped since have nothing left to discard.
+                for log in logs.iter().filter(|log| log.is_dir()) {
+                    wal::WALIndex::new(Some(log), compression.clone(), false)?.remove(tx_id)?;
+                }
+            }
+
+            fs::remove_file(&intent)?;
+        }
+
+        backend::sync_dir(&path)?;
+
+        Ok(Self {
+            path,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Durably records a transaction about to be committed, returning the path of its intent.
+    fn begin(&self, tx_id: usize, logs: &[&path::Path]) -> Result<path::PathBuf> {
+        let intent = self.path.join(format!(
+            "{}.intent",
+            self.next.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        write_file_atomically(&intent, &bincode::serialize(&(tx_id, logs)).unwrap())?;
+        backend::sync_dir(&self.path)?;
+
+        Ok(intent)
+    }
+
+    /// Durably removes the intent of a transaction whose logs agree.
+    fn end(&self, intent: &path::Path) -> Result<()> {
+        fs::remove_file(intent)?;
+        backend::sync_dir(&self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Type-erased transaction of one collection staged in a `MultiTransaction`.
+trait Staged {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn set_tx_id(&mut self, tx_id: usize);
+    fn wal(&self) -> Result<(Wal, time::Duration)>;
+    fn commit_locked(&mut self, wal: &mut wal::Wal) -> Result<()>;
+    fn revert_locked(&mut self, wal: &mut wal::Wal) -> Result<()>;
+    fn wait_synced(&self) -> Result<()>;
+}
+
+struct StagedTransaction<T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static> {
+    collection: Collection<T>,
+    transaction: Transaction<T>,
+    applied: Vec<wal::WalOperation<T>>, // kept to revert the transaction if another collection fails
+}
+
+impl<T> Staged for StagedTransaction<T>
+where
+    T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
+{
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn set_tx_id(&mut self, tx_id: usize) {
+        self.transaction.tx_id = tx_id;
+    }
+
+    fn wal(&self) -> Result<(Wal, time::Duration)> {
+        self.collection.ensure_writable()?;
+
+        Ok((self.collection.wal.clone(), self.collection.lock_timeout))
+    }
+
+    fn commit_locked(&mut self, wal: &mut wal::Wal) -> Result<()> {
+        self.applied = self.collection.commit_locked(wal, &mut self.transaction)?;
+
+        Ok(())
+    }
+
+    fn revert_locked(&mut self, wal: &mut wal::Wal) -> Result<()> {
+        let mut revert_transaction = self.collection.start();
+        revert_transaction.data = self
+            .applied
+            .iter()
+            .rev()
+            .map(wal::WalOperation::reverse_operation)
+            .collect();
+
+        self.collection
+            .commit_locked(wal, &mut revert_transaction)?;
+        self.transaction.status = TransactionStatus::RolledBack;
+
+        Ok(())
+    }
+
+    fn wait_synced(&self) -> Result<()> {
+        self.collection.wait_synced()
+    }
+}
+
+/// A transaction spanning several collections, committed all or nothing.
+/// Created with `DustData::transaction`.
+///
+/// The write-ahead logs of every collection are locked before anything is applied, then
+/// each collection commits its transaction under the same id. If a collection fails, the
+/// ones committed before it are reverted, and the failed one is left like a failed
+/// `Collection::commit`.
+/// The logs are synced before they're unlocked, and a transaction cut short by a crash is
+/// discarded from every log on the next open, see `Coordinator`.
+pub struct MultiTransaction<'a> {
+    dustdata: &'a DustData,
+    staged: BTreeMap<String, Box<dyn Staged>>, // locked in name order, so concurrent commits can't deadlock
+    tx_id: usize,
+    committed: bool,
+}
+
+impl<'a> MultiTransaction<'a> {
+    pub(crate) fn new(dustdata: &'a DustData) -> Self {
+        Self {
+            dustdata,
+            staged: BTreeMap::new(),
+            tx_id: get_current_timestamp(),
+            committed: false,
+        }
+    }
+
+    /// The id shared by the transactions of every collection.
+    /// It's final once committed, greater than the ids already logged by every collection.
+    pub fn id(&self) -> usize {
+        self.tx_id
+    }
+
+    /// Returns the transaction staged for a collection, opening the collection if it isn't.
+    /// Returns `Error::Other` if the collection was opened with a different type.
+    pub fn collection<T>(&mut self, name: &str) -> Result<&mut Transaction<T>>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
+    {
+        if !self.staged.contains_key(name) {
+            let collection = self.dustdata.collection::<T>(name)?;
+
+            let mut transaction = collection.start();
+            transaction.tx_id = self.tx_id;
+
+            self.staged.insert(
+                name.to_string(),
+                Box::new(StagedTransaction {
+                    collection,
+                    transaction,
+                    applied: Vec::new(),
+                }),
+            );
+        }
+
+        self.staged
+            .get_mut(name)
+            .unwrap()
+            .as_any_mut()
+            .downcast_mut::<StagedTransaction<T>>()
+            .map(|staged| &mut staged.transaction)
+            .ok_or_else(|| {
+                Error::Other(format!(
+                    "Collection {} was opened with a different type",
+                    name
+                ))
+            })
+    }
+
+    /// Commits the transaction of every collection, or none of them.
+    /// Returns the error of the collection that failed once the others are reverted, or
+    /// `Error::RevertFailed` with it if some couldn't be.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.committed {
+            return Err(Error::TransactionAlreadyCommitted);
+        }
+
+        let wals = self
+            .staged
+            .values()
+            .map(|staged| staged.wal())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut guards = Vec::with_capacity(wals.len());
+
+        for (wal, lock_timeout) in &wals {
+            guards.push(wal.try_write_for(*lock_timeout).ok_or(Error::Deadlock)?);
+        }
+
+        // One id that every write-ahead log can take, so they all log the transaction under it.
+        self.tx_id = guards
+            .iter()
+            .map(|wal| wal.next_tx_id(self.tx_id))
+            .max()
+            .unwrap_or(self.tx_id);
+
+        let logs = guards.iter().map(|wal| wal.log_path()).collect::<Vec<_>>();
+        let intent = self
+            .dustdata
+            .coordinator
+            .as_ref()
+            .map(|coordinator| {
+                coordinator
+                    .begin(self.tx_id, &logs)
+                    .map(|intent| (coordinator, intent))
+            })
+            .transpose()?;
+
+        let mut staged = self.staged.iter_mut().collect::<Vec<_>>();
+
+        for (_, staged) in &mut staged {
+            staged.set_tx_id(self.tx_id);
+        }
+
+        let mut committed = 0;
+        let mut result = Ok(());
+
+        while committed < staged.len() {
+            if let Err(err) = staged[committed].1.commit_locked(&mut guards[committed]) {
+                result = Err(err);
+                break;
+            }
+
+            committed += 1;
+        }
+
+        if let Err(err) = result {
+            let mut reverts = Vec::new();
+
+            for j in (0..committed).rev() {
+                if let Err(revert_err) = staged[j].1.revert_locked(&mut guards[j]) {
+                    reverts.push((staged[j].0.clone(), revert_err));
+                }
+            }
+
+            result = if reverts.is_empty() {
+                Err(err)
+            } else {
+                Err(Error::RevertFailed {
+                    error: Box::new(err),
+                    reverts,
+                })
+            };
+        }
+
+        // The logs are synced before the intent is removed, so a crash can't leave the
+        // transaction in only some of them. After a failed revert they disagree anyway, and
+        // the intent is left for the next open to discard the transaction.
+        if !matches!(result, Err(Error::RevertFailed { .. })) {
+            let synced = guards
+                .iter_mut()
+                .try_for_each(|wal| wal.flush())
+                .and_then(|()| {
+                    intent
+                        .as_ref()
+                        .map_or(Ok(()), |(coordinator, intent)| coordinator.end(intent))
+                });
+
+            result = result.and(synced);
+        }
+
+        drop(guards);
+
+        for (_, staged) in &staged[..committed] {
+            staged.wait_synced()?;
+        }
+
+        result?;
+
+        self.committed = true;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use super::Collection;
+use crate::error::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Debug;
+use std::io::{BufRead, Write};
+
+/// Operations committed per transaction by `import_ndjson`.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+#[derive(Serialize)]
+struct ExportedEntry<'a, T> {
+    key: &'a str,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct ImportedEntry<T> {
+    key: String,
+    value: T,
+}
+
+impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Collection<T> {
+    /// Writes every key and value as newline-delimited JSON, one `{"key": ..., "value": ...}` object per line in ascending key order
+    /// Values are read and written one at a time, and commits wait until the export is done
+    /// Returns the number of exported entries
+    pub fn export_ndjson<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let storage = self.storage.read();
+        let keys = storage.keys_in_range(..);
+
+        let mut exported = 0;
+
+        for key in keys {
+            let Some(value) = storage.get_tuple::<T>(key.clone())? else {
+                continue;
+            };
+
+            let entry = ExportedEntry {
+                key: &key,
+                value: &value,
+            };
+
+            serde_json::to_writer(&mut writer, &entry)
+                .map_err(|e| Error::Other(format!("Failed to export {}. Error: {}", key, e)))?;
+            writer.write_all(b"\n")?;
+
+            exported += 1;
+        }
+
+        writer.flush()?;
+
+        Ok(exported)
+    }
+
+    /// Inserts the entries of newline-delimited JSON written by `export_ndjson`, committing every 1000 entries, or every `max_transaction_ops` if it's lower
+    /// Blank lines are skipped. An invalid line or an existing key stops the import, the batches committed before it stay in place
+    /// Returns the number of imported entries
+    pub fn import_ndjson<R: BufRead>(&self, reader: R) -> Result<usize> {
+        let batch_size = self
+            .max_transaction_ops
+            .map_or(IMPORT_BATCH_SIZE, |max_ops| max_ops.min(IMPORT_BATCH_SIZE));
+
+        let mut transaction = self.start();
+        let mut imported = 0;
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: ImportedEntry<T> = serde_json::from_str(&line).map_err(|e| {
+                Error::Other(format!(
+                    "Invalid NDJSON entry at line {}. Error: {}",
+                    line_number + 1,
+                    e
+                ))
+            })?;
+
+            transaction.insert(&entry.key, entry.value);
+            imported += 1;
+
+            if imported % batch_size == 0 {
+                self.commit(&mut transaction)?;
+                transaction = self.start();
+            }
+        }
+
+        if !transaction.data.is_empty() {
+            self.commit(&mut transaction)?;
+        }
+
+        Ok(imported)
+    }
+}
@@ -0,0 +1,48 @@
+use super::Collection;
+use crate::error::Result;
+
+/// A collection of opaque byte values, for values that are already encoded.
+/// Created with `DustData::raw_collection`. The bytes are written to the data chunks as
+/// they are, without bincode, so the values don't need `Serialize` or `DeserializeOwned`.
+/// It shares its state with `DustData::collection::<Vec<u8>>` of the same name.
+/// Values in the data chunks are framed by their length only, without a checksum, so an
+/// uncompressed value corrupted on disk is returned as it is. Blobs are checked against
+/// their hash.
+#[derive(Clone)]
+pub struct RawCollection {
+    collection: Collection<Vec<u8>>,
+}
+
+impl RawCollection {
+    pub(crate) fn new(collection: Collection<Vec<u8>>) -> Self {
+        Self { collection }
+    }
+
+    /// Inserts a value in its own transaction.
+    /// Returns `Error::AlreadyExists` if the key exists.
+    pub fn insert(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.collection
+            .start_lazy(|transaction| transaction.insert(key, value.to_vec()))?;
+
+        Ok(())
+    }
+
+    /// Inserts or replaces a value in its own transaction, returning the previous value.
+    pub fn update(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.collection.update_now(key, value.to_vec())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.collection.get(key)
+    }
+
+    /// Deletes a key in its own transaction, returning the deleted value.
+    pub fn delete(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.collection.delete_now(key)
+    }
+
+    /// The underlying collection, for transactions and everything else.
+    pub fn collection(&self) -> &Collection<Vec<u8>> {
+        &self.collection
+    }
+}
@@ -0,0 +1,154 @@
+use super::wal::WalOperation;
+use crate::error::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fs, io, path};
+
+/// Type-erased secondary index, updated with every committed operation.
+pub trait SecondaryIndex<T>: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn apply(&mut self, operation: &WalOperation<T>) -> Result<()>;
+    fn write(&mut self, wal_head: Option<usize>) -> Result<()>;
+}
+
+type Extractor<T, K> = Box<dyn Fn(&T) -> K + Send + Sync>;
+type Entries<K> = BTreeMap<K, BTreeSet<String>>; // secondary key -> primary keys
+
+/// Index of the primary keys by a value extracted from each value.
+pub struct FieldIndex<T, K> {
+    extractor: Extractor<T, K>,
+    entries: Entries<K>,
+    path: Option<path::PathBuf>, // None when the index isn't persisted
+    dirty: bool,                 // whether the file is behind the entries
+}
+
+impl<T, K> FieldIndex<T, K>
+where
+    T: 'static,
+    K: Ord + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Loads the index persisted at `path` if it was written while the write-ahead log
+    /// was at `wal_head`. Returns `false` if it wasn't, and the index has to be rebuilt
+    /// with `insert`.
+    pub fn open(
+        extractor: Extractor<T, K>,
+        path: Option<path::PathBuf>,
+        wal_head: Option<usize>,
+    ) -> Result<(Self, bool)> {
+        let mut index = Self {
+            extractor,
+            entries: Entries::new(),
+            path,
+            dirty: true,
+        };
+
+        let Some(path) = &index.path else {
+            return Ok((index, false));
+        };
+
+        let persisted = match fs::read(path) {
+            Ok(bytes) => bincode::deserialize::<(Option<usize>, Entries<K>)>(&bytes).ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match persisted {
+            Some((persisted_head, entries)) if persisted_head == wal_head => {
+                index.entries = entries;
+                index.dirty = false;
+
+                Ok((index, true))
+            }
+            _ => {
+                // A stale file must not outlive a crash before the next write.
+                Self::remove_file(path)?;
+
+                Ok((index, false))
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, value: &T) {
+        self.entries
+            .entry((self.extractor)(value))
+            .or_default()
+            .insert(key.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str, value: &T) {
+        let secondary_key = (self.extractor)(value);
+
+        if let Some(keys) = self.entries.get_mut(&secondary_key) {
+            keys.remove(key);
+
+            if keys.is_empty() {
+                self.entries.remove(&secondary_key);
+            }
+        }
+    }
+
+    /// Returns the primary keys of the values whose extracted key is `value`, in ascending order.
+    pub fn find(&self, value: &K) -> Vec<String> {
+        self.entries
+            .get(value)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_file(path: &path::Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T, K> SecondaryIndex<T> for FieldIndex<T, K>
+where
+    T: 'static,
+    K: Ord + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn apply(&mut self, operation: &WalOperation<T>) -> Result<()> {
+        match operation {
+            WalOperation::Insert { key, value } => self.insert(key, value),
+            WalOperation::Update {
+                key,
+                new_value,
+                old_value,
+            } => {
+                self.remove(key, old_value);
+                self.insert(key, new_value);
+            }
+            WalOperation::Delete { key, value } => self.remove(key, value),
+            WalOperation::Drop => self.entries.clear(),
+        }
+
+        if !self.dirty {
+            if let Some(path) = &self.path {
+                Self::remove_file(path)?;
+            }
+
+            self.dirty = true;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, wal_head: Option<usize>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let bytes = bincode::serialize(&(wal_head, &self.entries)).unwrap();
+
+        super::write_file_atomically(path, &bytes)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use super::{
+    codec, get_current_timestamp, write_file_atomically, Collection, Operation, TransactionLog,
+    WalOperation,
+};
+use crate::error::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Debug;
+use std::io::{BufReader, Read};
+use std::{fs, ops, path};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"DSN2"; // followed by a `Header` and the body, encoded by `codec`
+
+/// Identifies a snapshot, so a delta can check the snapshot it's applied onto.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct SnapshotId {
+    name: String,         // the file name the snapshot was written as
+    tx_id: Option<usize>, // the last transaction it holds, None if nothing was logged
+}
+
+/// Read before the body, so taking a delta doesn't decode its base.
+/// The body of a full snapshot holds the entries, the body of a delta the transaction logs
+/// committed after its base.
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    id: SnapshotId,
+    base: Option<SnapshotId>, // None for a full snapshot
+}
+
+impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Collection<T> {
+    /// Writes every key and value to a `<timestamp>.snapshot` file in the `path` directory, returning the file name
+    /// The file is compressed with `DustDataConfig::snapshot_compression` and records the algorithm, so any snapshot can be restored
+    /// The write-ahead log and storage read locks are held while the values are read, so no commit lands in the middle of the snapshot
+    pub fn snapshot(&self, path: &path::Path) -> Result<String> {
+        let mut entries = Vec::new();
+
+        let tx_id = {
+            let wal = self.wal.read();
+
+            self.storage
+                .read()
+                .for_each_value(|key, value: T| entries.push((key.to_string(), value)))?;
+
+            wal.index.get_head()
+        };
+
+        let name = format!("{}.snapshot", get_current_timestamp());
+        let header = Header {
+            id: SnapshotId {
+                name: name.clone(),
+                tx_id,
+            },
+            base: None,
+        };
+
+        self.write_snapshot(path, &header, &entries)?;
+
+        Ok(name)
+    }
+
+    /// Writes the transactions committed since the `base` snapshot in the `path` directory to a `<timestamp>.delta` file, returning the file name
+    /// `base` is a name returned by `snapshot` or `snapshot_delta`, so deltas can be chained
+    /// Returns `Error::Other` if the write-ahead log was pruned past the base, a full snapshot is needed then
+    pub fn snapshot_delta(&self, base: &str, path: &path::Path) -> Result<String> {
+        let base = Self::read_header(&path.join(base))?.0.id;
+
+        let mut logs = Vec::new();
+
+        let tx_id = {
+            let wal = self.wal.read();
+
+            let since = match base.tx_id {
+                Some(tx_id) if wal.index.get(tx_id).is_some() => ops::Bound::Excluded(tx_id),
+                None if !wal.is_pruned()? => ops::Bound::Unbounded,
+                _ => {
+                    return Err(Error::Other(format!(
+                    "The write-ahead log was pruned past snapshot {}, take a full snapshot instead",
+                    base.name
+                )))
+                }
+            };
+
+            for (_, (log_chunk, offset)) in wal.index.diff((since, ops::Bound::Unbounded)) {
+                if let Some(log) = wal.read_by_offset_and_log_chunk::<T>(offset, log_chunk)? {
+                    logs.push(log);
+                }
+            }
+
+            wal.index.get_head()
+        };
+
+        let name = format!("{}.delta", get_current_timestamp());
+        let header = Header {
+            id: SnapshotId {
+                name: name.clone(),
+                tx_id,
+            },
+            base: Some(base),
+        };
+
+        self.write_snapshot(path, &header, &logs)?;
+
+        Ok(name)
+    }
+
+    /// Replaces the contents of the collection with a file written by `snapshot`, in one transaction
+    /// Versions start again at 1, and `Error::TransactionTooLarge` is returned if the snapshot doesn't fit in `max_transaction_ops`
+    pub fn restore_snapshot(&self, path: &path::Path) -> Result<()> {
+        self.restore_snapshot_chain(path, &[])
+    }
+
+    /// Like `restore_snapshot`, then replays the `deltas` in order, all in one transaction
+    /// Each delta must have been taken from the file before it in the chain, or `Error::Other` is returned and nothing is restored
+    pub fn restore_snapshot_chain(&self, base: &path::Path, deltas: &[&path::Path]) -> Result<()> {
+        let (header, body) = Self::read_header(base)?;
+
+        if header.base.is_some() {
+            return Err(Error::Other(format!(
+                "{} is a delta, a chain starts with a full snapshot",
+                base.display()
+            )));
+        }
+
+        let entries: Vec<(String, T)> = Self::decode_body(base, body)?;
+
+        let mut transaction = self.start();
+        transaction.data.push(Operation::Drop);
+        transaction.data.extend(
+            entries
+                .into_iter()
+                .map(|(key, value)| Operation::Insert(key, value)),
+        );
+
+        let mut previous = header.id;
+
+        for delta in deltas {
+            let (header, body) = Self::read_header(delta)?;
+
+            if header.base.as_ref() != Some(&previous) {
+                return Err(Error::Other(format!(
+                    "{} wasn't taken from snapshot {}",
+                    delta.display(),
+                    previous.name
+                )));
+            }
+
+            let logs: Vec<TransactionLog<T>> = Self::decode_body(delta, body)?;
+
+            transaction.data.extend(
+                logs.iter()
+                    .flat_map(|log| log.data.iter().map(WalOperation::operation)),
+            );
+
+            previous = header.id;
+        }
+
+        self.commit(&mut transaction)
+    }
+
+    fn write_snapshot<B: Serialize>(
+        &self,
+        path: &path::Path,
+        header: &Header,
+        body: &B,
+    ) -> Result<()> {
+        let serialize = |e| Error::Other(format!("Failed to serialize the snapshot. Error: {}", e));
+
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend(bincode::serialize(header).map_err(serialize)?);
+        snapshot.extend(codec::encode(
+            &bincode::serialize(body).map_err(serialize)?,
+            Some(&self.snapshot_compression),
+        )?);
+
+        fs::create_dir_all(path)?;
+        write_file_atomically(&path.join(&header.id.name), &snapshot)?;
+
+        Ok(())
+    }
+
+    /// Reads the header of a snapshot file, returning a reader over the rest of it
+    fn read_header(path: &path::Path) -> Result<(Header, BufReader<fs::File>)> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+
+        let mut magic = [0; 4];
+
+        if reader.read_exact(&mut magic).is_err() || &magic != SNAPSHOT_MAGIC {
+            return Err(Error::CorruptedData(format!(
+                "{} isn't a snapshot",
+                path.display()
+            )));
+        }
+
+        let header = bincode::deserialize_from(&mut reader)
+            .map_err(|e| Self::corrupted_snapshot(path, e))?;
+
+        Ok((header, reader))
+    }
+
+    fn decode_body<B: DeserializeOwned>(
+        path: &path::Path,
+        mut reader: BufReader<fs::File>,
+    ) -> Result<B> {
+        let mut compressed = Vec::new();
+        reader.read_to_end(&mut compressed)?;
+
+        bincode::deserialize(&codec::decode(compressed)?)
+            .map_err(|e| Self::corrupted_snapshot(path, e))
+    }
+
+    fn corrupted_snapshot(path: &path::Path, e: bincode::Error) -> Error {
+        Error::CorruptedData(format!(
+            "Corrupted snapshot {}. Error: {}",
+            path.display(),
+            e
+        ))
+    }
+}
@@ -1,20 +1,104 @@
 use crate::bloom;
 use crate::error::{Error, Result};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::Deserialize;
 use serde::{de::DeserializeOwned, Serialize};
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
 use std::io::{prelude::*, SeekFrom};
+use std::ops::{Bound, RangeBounds};
 use std::{fs, path};
 
+use super::backend::{Backend, ReadSeek, StagedFile};
+use super::codec;
 use super::config;
 
 pub struct Storage {
+    backend: Backend,
     file: File,
     index: Index,
     filter: Filter,
+    prefix_filter: Option<Filter>, // prefixes of every key, see `StorageConfig::prefix_filter_depth`
     storage_path: path::PathBuf,
+    config: config::StorageConfig,
+    live_bytes: u64,  // bytes of the segments referenced by the index
+    total_bytes: u64, // bytes of all data chunks
+    sequence: u64,    // last number used by `next_auto_key`
+    cleared: bool,    // a `clear` left data chunks to reclaim on the next flush
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompactionStats {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub live_segments: usize,
+    pub orphaned_bytes: u64, // reclaimed bytes a crash left unreferenced, 0 for `coalesce`
+    pub duration: std::time::Duration,
+}
+
+impl CompactionStats {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+
+    /// Reclaimed bytes of values replaced by updates and deletes, and of tombstones.
+    pub fn superseded_bytes(&self) -> u64 {
+        self.reclaimed_bytes().saturating_sub(self.orphaned_bytes)
+    }
+}
+
+/// Sizes of a collection, returned by `Collection::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CollectionStats {
+    pub live_keys: usize,
+    pub data_chunks: usize,
+    pub data_chunk_bytes: u64,
+    pub index_bytes: u64,       // 0 for in-memory collections
+    pub filter_bytes: u64,      // bloom filter size in memory
+    pub filter_fp_rate: f64,    // same estimate as `BloomStats::fp_rate`
+    pub dead_bytes: u64,        // reclaimable by compaction
+    pub chunk_utilization: f64, // data_chunk_bytes over the capacity of the data chunks, at most 1
+    pub wal_log_chunks: usize,
+}
+
+/// State of the key bloom filter, returned by `Collection::bloom_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BloomStats {
+    pub bytes: usize,
+    pub hashes: i64,
+    pub set_bits: u64,
+    pub fill_ratio: f64, // set bits over all bits
+    pub fp_rate: f64,    // estimated from the fill, `fill_ratio ^ hashes`
+}
+
+/// Problems found by `Collection::check_integrity`, every list holds keys unless noted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    pub missing_chunks: Vec<String>, // index entries pointing at a missing data chunk
+    pub out_of_bounds: Vec<String>,  // index entries pointing past the end of their chunk
+    pub undecodable: Vec<String>,    // segments that fail to deserialize
+    pub filter_misses: Vec<String>,  // indexed keys the bloom filter doesn't contain
+    pub stale_filter_keys: Vec<String>, // keys in the bloom filter but not in the index
+    pub orphaned_segments: Vec<(String, u64)>, // (data chunk, offset) not referenced by the index
+    pub unindexed_segments: Vec<(String, u64)>, // orphaned segments left by a crash, see `Storage::crash_orphans`
+}
+
+impl IntegrityReport {
+    /// Whether the index can serve every key it holds.
+    /// Stale filter keys and orphaned segments are left behind by deletes and updates
+    /// until the next compaction, so they don't count as problems.
+    pub fn is_clean(&self) -> bool {
+        self.missing_chunks.is_empty()
+            && self.out_of_bounds.is_empty()
+            && self.undecodable.is_empty()
+            && self.filter_misses.is_empty()
+    }
+}
+
+struct ScannedSegment<'a> {
+    offset: u64,
+    length: u64,
+    header: Option<(u8, &'a str)>, // flag and key, None for segments without a key
 }
 
 pub struct StorageTupleEntry<T> {
@@ -25,101 +109,169 @@ pub struct StorageTupleEntry<T> {
 impl Storage {
     pub fn new(config: config::DustDataConfig) -> Result<Self> {
         let storage_path = config.data_path.join("data");
+        let mut backend = Backend::new(config.backend, config.sync_dir_on_create);
+        let read_only = config.lock_mode == config::LockMode::ReadOnly;
 
-        std::fs::create_dir_all(&storage_path).ok();
+        if !read_only {
+            backend.create_dir_all(&storage_path).ok();
+        }
 
-        let index = Index::new(
-            &storage_path,
-            config.storage.compression.is_some(),
-            config.storage.compression.as_ref().map(|c| c.level),
+        let mut index = Index::new(
+            (!backend.is_memory()).then_some(storage_path.as_path()),
+            config.storage.compression.clone(),
+            read_only,
         )?;
 
+        if index.segment_version != SEGMENT_FORMAT_VERSION {
+            if !index.index.is_empty() && index.segment_version < MIN_SEGMENT_FORMAT_VERSION {
+                return Err(Error::VersionMismatch(format!(
+                    "The data chunks in {} use segment format {}, but this build only supports formats {} to {}",
+                    storage_path.display(),
+                    index.segment_version,
+                    MIN_SEGMENT_FORMAT_VERSION,
+                    SEGMENT_FORMAT_VERSION
+                )));
+            }
+
+            index.segment_version = SEGMENT_FORMAT_VERSION;
+        }
+
         let keys = index.index.keys().cloned().collect::<Vec<String>>();
 
-        let filter = Filter::new(keys);
+        // Seeded per collection, so collections don't share their false positives.
+        let seed = config
+            .data_path
+            .file_name()
+            .map_or(0, |name| farmhash::hash64(name.as_encoded_bytes()));
+        let prefix_filter = Self::prefix_filter(&keys, &config.storage, seed);
+        let filter = Filter::new(keys, &config.storage, seed);
 
-        let (data_chunk_page, data_chunk_id) = Self::data_chunk(&storage_path, &config);
-        let file = File::new(&storage_path, data_chunk_page, data_chunk_id)?;
+        let (data_chunk_page, data_chunk_id) = Self::data_chunk(&backend, &storage_path, &config)?;
+        let file = File::new(&storage_path, data_chunk_page, data_chunk_id);
+
+        if !read_only {
+            backend.touch(&file.path)?;
+        }
+
+        let live_bytes = index.index.values().map(|entry| entry.length).sum();
+        let total_bytes = Self::chunk_files(&backend, &storage_path)?
+            .iter()
+            .map(|(_, path)| backend.len(path))
+            .sum::<std::io::Result<u64>>()?;
+
+        let sequence = Self::read_sequence(&backend, &storage_path)?;
 
         Ok(Self {
+            backend,
             file,
             filter,
+            prefix_filter,
             index,
             storage_path,
+            config: config.storage,
+            live_bytes,
+            total_bytes,
+            sequence,
+            cleared: false,
         })
     }
 
-    pub fn insert_tuple<T>(&mut self, tuple: StorageTupleEntry<T>) -> Result<()>
+    /// Inserts several tuples with a single write and sync of the data chunk.
+    /// Every key is checked first, so nothing is written if one of them already exists.
+    pub fn insert_tuples<T>(&mut self, tuples: Vec<StorageTupleEntry<T>>) -> Result<()>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static,
     {
-        if self.filter.contains(&tuple.key) {
-            return Err(Error::AlreadyExists(tuple.key));
+        let mut keys = HashSet::with_capacity(tuples.len());
+
+        for tuple in &tuples {
+            if self.contains_exact(&tuple.key) || !keys.insert(tuple.key.as_str()) {
+                return Err(Error::AlreadyExists(tuple.key.clone()));
+            }
         }
 
-        let segment = Storage::serialize_value(&tuple.value);
+        let segments = tuples
+            .iter()
+            .map(|tuple| self.serialize_value(&tuple.key, &tuple.value, 1))
+            .collect::<Result<Vec<_>>>()?;
 
-        self.filter.insert(&tuple.key);
-        let offset = self.file.len().unwrap();
+        self.ensure_quota(segments.iter().map(|segment| segment.len() as u64).sum())?;
 
-        let index_entry = IndexEntry {
-            offset,
-            data_chunk: DataChunk {
-                page: self.file.data_chunk_page,
-                id: self.file.data_chunk_id,
-            },
-        };
-        self.index.insert(tuple.key, index_entry);
+        let index_entries = self.append_segments(&segments)?;
 
-        self.file.write_all(&segment).map_err(Error::IoError)?;
+        for (tuple, index_entry) in tuples.into_iter().zip(index_entries) {
+            self.insert_filter_key(&tuple.key);
+            self.index.insert(tuple.key, index_entry);
+        }
+
+        // A filter holding far more keys than it was sized for returns mostly false positives.
+        if self.index.index.len() > self.filter.capacity * 2 {
+            self.rebuild_filters();
+        }
 
         Ok(())
     }
 
-    pub fn update_tuple<T>(&mut self, tuple: StorageTupleEntry<T>) -> Result<T>
+    /// Returns the old value with its version.
+    pub fn update_tuple<T>(&mut self, tuple: StorageTupleEntry<T>) -> Result<(T, u64)>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        if !self.contains(&tuple.key) {
+        if !self.contains_exact(&tuple.key) {
             return Err(Error::NotFound(tuple.key));
         }
 
-        let segment = Storage::serialize_value(&tuple.value);
+        let old_entry = self.index.get(tuple.key.clone()).unwrap();
+        let (old_value, version) = self.get_versioned_by_entry(&tuple.key, &old_entry)?;
 
-        let offset = self.file.len().unwrap();
+        let segment = self.serialize_value(&tuple.key, &tuple.value, version + 1)?;
+        self.ensure_quota(segment.len() as u64)?;
 
-        let index_entry = IndexEntry {
-            offset,
-            data_chunk: DataChunk {
-                page: self.file.data_chunk_page,
-                id: self.file.data_chunk_id,
-            },
-        };
-        let old_index_value = self.index.insert(tuple.key, index_entry).unwrap();
+        let index_entry = self.append_segment(&segment)?;
+        self.index.insert(tuple.key, index_entry);
+        self.live_bytes -= old_entry.length;
+
+        Ok((old_value, version))
+    }
+
+    /// Writes a value back at the version it had, replacing the key's value if it exists.
+    /// Used to undo a failed transaction, so the quota isn't checked.
+    pub fn restore_tuple<T>(&mut self, tuple: StorageTupleEntry<T>, version: u64) -> Result<()>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static,
+    {
+        let segment = self.serialize_value(&tuple.key, &tuple.value, version)?;
+        let index_entry = self.append_segment(&segment)?;
 
-        self.file.write_all(&segment).map_err(Error::IoError)?;
+        match self.index.get(tuple.key.clone()) {
+            Some(old_entry) => self.live_bytes -= old_entry.length,
+            None => self.insert_filter_key(&tuple.key),
+        }
 
-        let old_value = self
-            .get_tuple_by_offset_and_data_chunk(old_index_value.offset, old_index_value.data_chunk)?
-            .unwrap();
+        self.index.insert(tuple.key, index_entry);
 
-        Ok(old_value)
+        Ok(())
     }
 
-    pub fn remove_tuple<T>(&mut self, key: String) -> Result<T>
+    /// Returns the old value with its version.
+    pub fn remove_tuple<T>(&mut self, key: String) -> Result<(T, u64)>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        if !self.contains(&key) {
+        if !self.contains_exact(&key) {
             return Err(Error::NotFound(key));
         }
 
-        self.filter.remove(&key);
-        let entry = self.index.remove(key).unwrap();
+        // Read first, so a value that fails to decode is still stored.
+        let entry = self.index.get(key.clone()).unwrap();
+        let old_value = self.get_versioned_by_entry(&key, &entry)?;
+
+        // The tombstone keeps a rebuilt index from restoring the key.
+        let tombstone = self.append_segment(&Self::segment(SEGMENT_TOMBSTONE, &key, &[]))?;
+        self.live_bytes -= tombstone.length;
 
-        let old_value = self
-            .get_tuple_by_offset_and_data_chunk(entry.offset, entry.data_chunk)?
-            .unwrap();
+        self.index.remove(key);
+        self.live_bytes -= entry.length;
 
         Ok(old_value)
     }
@@ -128,7 +280,7 @@ impl Storage {
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        let offset = self.index.get(key);
+        let offset = self.index.get(key.clone());
 
         if offset.is_none() {
             return Ok(None);
@@ -136,35 +288,249 @@ impl Storage {
 
         let entry = offset.unwrap();
 
-        self.get_tuple_by_offset_and_data_chunk(entry.offset, entry.data_chunk)
+        self.get_tuple_by_offset_and_data_chunk(&key, entry.offset, entry.data_chunk)
+    }
+
+    /// Returns the value of a key with its version, which starts at 1 and grows by one on
+    /// every update. Values written before versions existed are at version 0.
+    pub fn get_versioned_tuple<T>(&self, key: &str) -> Result<Option<(T, u64)>>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+    {
+        let Some(entry) = self.index.get(key.to_string()) else {
+            return Ok(None);
+        };
+
+        Ok(Some(self.get_versioned_by_entry(key, &entry)?))
+    }
+
+    fn get_versioned_by_entry<T>(&self, key: &str, entry: &IndexEntry) -> Result<(T, u64)>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+    {
+        let (filename, mut file) = self.chunk_reader(entry.data_chunk)?;
+
+        self.deserialize_versioned_value(&mut *file, key, entry.offset, &filename)
+    }
+
+    /// Returns a reader of the serialized value of a key, decompressed while it's read.
+    /// The reader holds its own file handle, so it can be used after the storage lock is
+    /// released. Blobs aren't checked against their hash, since they aren't read whole.
+    pub fn value_reader(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let Some(entry) = self.index.get(key.to_string()) else {
+            return Ok(None);
+        };
+
+        let filename = entry.data_chunk.filename();
+        let corrupted = |e: &dyn std::fmt::Display| {
+            Error::CorruptedData(format!(
+                "Corrupted data chunk {} and offset {}. Error: {}",
+                filename, entry.offset, e
+            ))
+        };
+
+        let mut file = self
+            .backend
+            .owned_reader(&self.storage_path.join(&filename))
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::MissingDataChunk {
+                    chunk: filename.clone(),
+                },
+                _ => corrupted(&e),
+            })?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        // length u64 LE + flag + key length u32 LE, see `serialize_value`
+        let mut header = [0; 13];
+        file.read_exact(&mut header).map_err(|e| corrupted(&e))?;
+
+        let length = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let flag = header[8] & !(SEGMENT_VERSIONED | SEGMENT_BYTES);
+        let bytes = header[8] & SEGMENT_BYTES != 0;
+        let key_len = u32::from_le_bytes(header[9..].try_into().unwrap()) as u64;
+        let version_len = if header[8] & SEGMENT_VERSIONED != 0 {
+            8
+        } else {
+            0
+        };
+
+        if length & FLAGGED_SEGMENT == 0 {
+            return Err(corrupted(
+                &"segment without a key, written by an older format",
+            ));
+        }
+
+        let payload_len = (length & !FLAGGED_SEGMENT)
+            .checked_sub(5 + key_len + version_len)
+            .ok_or_else(|| corrupted(&"invalid segment length"))?;
+
+        let mut segment_key = vec![0; key_len as usize];
+        file.read_exact(&mut segment_key)
+            .map_err(|e| corrupted(&e))?;
+
+        if segment_key != key.as_bytes() {
+            return Err(corrupted(&format!(
+                "segment belongs to key {}, expected {}",
+                String::from_utf8_lossy(&segment_key),
+                key
+            )));
+        }
+
+        file.seek(SeekFrom::Current(version_len as i64))?;
+
+        let payload = file.take(payload_len);
+
+        let mut reader: Box<dyn Read + Send> = match flag {
+            SEGMENT_RAW => Box::new(payload),
+            SEGMENT_COMPRESSED => codec::decoder(payload)?,
+            SEGMENT_BLOB => {
+                let mut reference = Vec::new();
+                payload.take(16).read_to_end(&mut reference)?;
+                let (hash, length) = Self::blob_reference(&reference).map_err(|e| corrupted(&e))?;
+
+                let blob_filename = format!("{:016x}.blob", hash);
+                let blob = self
+                    .backend
+                    .owned_reader(&self.storage_path.join(BLOBS_DIRNAME).join(&blob_filename))
+                    .map_err(|e| {
+                        Error::CorruptedData(format!(
+                            "Blob {} is unreadable. Error: {}",
+                            blob_filename, e
+                        ))
+                    })?;
+
+                codec::decoder(blob.take(length))?
+            }
+            _ => return Err(corrupted(&"invalid segment flag")),
+        };
+
+        if !bytes {
+            return Ok(Some(reader));
+        }
+
+        // Raw bytes are read back behind the length prefix bincode would have written.
+        let (length, reader) = match flag {
+            SEGMENT_RAW => (payload_len, reader),
+            _ => {
+                let mut value = Vec::new();
+                reader.read_to_end(&mut value)?;
+
+                let length = value.len() as u64;
+                let reader: Box<dyn Read + Send> = Box::new(std::io::Cursor::new(value));
+
+                (length, reader)
+            }
+        };
+
+        Ok(Some(Box::new(
+            std::io::Cursor::new(length.to_le_bytes()).chain(reader),
+        )))
     }
 
     pub fn get_tuple_by_offset_and_data_chunk<T>(
         &self,
+        key: &str,
         offset: u64,
         data_chunk: DataChunk,
     ) -> Result<Option<T>>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        let filename = format!("Data_{}_{}.db", data_chunk.page, data_chunk.id);
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .open(self.storage_path.join(filename.clone()))
+        let (filename, mut file) = self.chunk_reader(data_chunk)?;
+
+        Ok(Some(
+            self.deserialize_value(&mut *file, key, offset, &filename)?,
+        ))
+    }
+
+    /// Deserializes every value, calling `f` with its key, in the order the values were
+    /// written so each data chunk is opened once and read front to back.
+    pub fn for_each_value<T, F>(&self, mut f: F) -> Result<()>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+        F: FnMut(&str, T),
+    {
+        let mut entries = self.index.index.iter().collect::<Vec<_>>();
+        entries
+            .sort_by_key(|(_, entry)| (entry.data_chunk.page, entry.data_chunk.id, entry.offset));
+
+        let mut current = None;
+
+        for (key, entry) in entries {
+            let chunk = (entry.data_chunk.page, entry.data_chunk.id);
+
+            if current
+                .as_ref()
+                .is_none_or(|(current_chunk, _, _)| *current_chunk != chunk)
+            {
+                let (filename, file) = self.chunk_reader(entry.data_chunk)?;
+                current = Some((chunk, filename, file));
+            }
+
+            let (_, filename, file) = current.as_mut().unwrap();
+
+            f(
+                key,
+                self.deserialize_value(&mut **file, key, entry.offset, filename)?,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn chunk_reader(&self, data_chunk: DataChunk) -> Result<(String, Box<dyn ReadSeek + '_>)> {
+        let filename = data_chunk.filename();
+        let file = self
+            .backend
+            .reader(&self.storage_path.join(&filename))
             .map_err(|r| match r.kind() {
-                std::io::ErrorKind::NotFound => Error::CorruptedData(format!(
-                    "Data chunk {} not found, but index contains it",
-                    filename
-                )),
+                std::io::ErrorKind::NotFound => Error::MissingDataChunk {
+                    chunk: filename.clone(),
+                },
                 _ => Error::IoError(r),
             })?;
 
-        Ok(Some(Self::deserialize_value(&mut file, offset, &filename)?))
+        Ok((filename, file))
     }
 
-    pub fn clear(&mut self) -> Result<()> {
+    /// Removes every key, returning the removed index entries so `restore` can undo it.
+    pub fn clear(&mut self) -> Result<ClearedEntries> {
+        let marker = self.append_segment(&Self::segment(SEGMENT_CLEAR, "", &[]))?;
+        self.live_bytes -= marker.length;
+
         self.filter.clear();
-        self.index.clear();
+        if let Some(prefix_filter) = &mut self.prefix_filter {
+            prefix_filter.clear();
+        }
+        let entries = std::mem::take(&mut self.index.index);
+        self.live_bytes = 0;
+        self.cleared = true;
+
+        Ok(ClearedEntries(entries))
+    }
+
+    /// Brings back the keys removed by a `clear`, keys written since keep their value.
+    /// Their segments are copied after the clear marker, so a rebuilt index keeps them.
+    pub fn restore(&mut self, cleared: ClearedEntries) -> Result<()> {
+        let mut entries = cleared
+            .0
+            .into_iter()
+            .filter(|(key, _)| !self.index.index.contains_key(key))
+            .collect::<Vec<_>>();
+        entries
+            .sort_by_key(|(_, entry)| (entry.data_chunk.page, entry.data_chunk.id, entry.offset));
+
+        let segments = entries
+            .iter()
+            .map(|(_, entry)| self.read_segment(entry))
+            .collect::<Result<Vec<_>>>()?;
+
+        let index_entries = self.append_segments(&segments)?;
+
+        for ((key, _), index_entry) in entries.into_iter().zip(index_entries) {
+            self.insert_filter_key(&key);
+            self.index.insert(key, index_entry);
+        }
 
         Ok(())
     }
@@ -173,231 +539,1492 @@ impl Storage {
         self.filter.contains(key)
     }
 
-    fn serialize_value<T>(value: &T) -> Vec<u8>
-    where
-        T: Sync + Send + Clone + Debug + Serialize + 'static,
-    {
-        let mut bytes = Vec::new();
-        let serialized_value = bincode::serialize(value).unwrap();
-        bytes.extend(serialized_value.len().to_le_bytes().iter());
-        bytes.extend_from_slice(&serialized_value);
+    /// Checks the bloom filter first and confirms a "maybe" against the index.
+    pub fn contains_exact(&self, key: &str) -> bool {
+        self.filter.contains(key) && self.index.index.contains_key(key)
+    }
 
-        bytes
+    /// Number of keys in the index.
+    pub fn len(&self) -> usize {
+        self.index.index.len()
+    }
+
+    /// The most recently written keys, newest first, whose segments add up to at most
+    /// `budget` bytes.
+    pub fn recent_keys(&self, budget: usize) -> Vec<String> {
+        let mut entries = self.index.index.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(_, entry)| {
+            std::cmp::Reverse((entry.data_chunk.page, entry.data_chunk.id, entry.offset))
+        });
+
+        let mut size = 0;
+
+        entries
+            .into_iter()
+            .take_while(|(_, entry)| {
+                size += entry.length as usize;
+                size <= budget
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether a key may start with `prefix`, false positives are possible with a prefix
+    /// filter. Prefixes longer than its depth are checked by their first `depth`
+    /// characters. Without a prefix filter the index is checked instead.
+    pub fn prefix_exists(&self, prefix: &str) -> bool {
+        match (&self.prefix_filter, self.config.prefix_filter_depth) {
+            (Some(prefix_filter), Some(depth)) if !prefix.is_empty() => {
+                prefix_filter.contains(Self::prefixes(prefix, depth).last().unwrap())
+            }
+            _ => self
+                .index
+                .index
+                .range(prefix.to_string()..)
+                .next()
+                .is_some_and(|(key, _)| key.starts_with(prefix)),
+        }
     }
 
-    fn deserialize_value<T>(file: &mut fs::File, offset: u64, filename: &str) -> Result<T>
+    /// Returns the keys within a range, in ascending order.
+    pub fn keys_in_range<R>(&self, range: R) -> Vec<String>
     where
-        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+        R: RangeBounds<String>,
     {
-        file.seek(SeekFrom::Start(offset)).map_err(Error::IoError)?;
+        self.index
+            .index
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
 
-        let mut length = [0; 8];
-        file.read_exact(&mut length).map_err(Error::IoError)?;
-        let length = u64::from_le_bytes(length) as usize;
+    /// Returns the keys starting with `prefix`, in ascending order.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.index
+            .index
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
 
-        let mut value = vec![0; length];
-        file.read_exact(&mut value).map_err(Error::IoError)?;
+    /// Returns the smallest key, or the smallest key after `after`.
+    pub fn next_key(&self, after: Option<&str>) -> Option<String> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_string()),
+            None => Bound::Unbounded,
+        };
 
-        let value = bincode::deserialize(&value).map_err(|e| {
-            Error::CorruptedData(format!(
-                "Corrupted data chunk {} and offset {}. Error: {}",
-                filename, offset, e
-            ))
-        })?;
+        self.index
+            .index
+            .range((start, Bound::Unbounded))
+            .next()
+            .map(|(key, _)| key.clone())
+    }
 
-        Ok(value)
+    /// Returns the largest key, or the largest key before `before`.
+    pub fn prev_key(&self, before: Option<&str>) -> Option<String> {
+        let end = match before {
+            Some(before) => Bound::Excluded(before.to_string()),
+            None => Bound::Unbounded,
+        };
+
+        self.index
+            .index
+            .range((Bound::Unbounded, end))
+            .next_back()
+            .map(|(key, _)| key.clone())
     }
 
-    fn data_chunk(path: &path::Path, config: &config::DustDataConfig) -> (usize, usize) {
-        let mut data_chunk = 0;
-        let mut chunk_index = 0;
+    /// Returns `Error::QuotaExceeded` if writing `bytes` would grow the data chunks past
+    /// `max_collection_bytes`.
+    fn ensure_quota(&self, bytes: u64) -> Result<()> {
+        let Some(max_bytes) = self.config.max_collection_bytes else {
+            return Ok(());
+        };
 
-        loop {
-            let filename = format!("Data_{}_{}.db", data_chunk, chunk_index);
-            let file_path = path.join(filename);
-            if !file_path.exists() {
-                break (data_chunk, chunk_index);
-            }
+        if self.total_bytes + bytes > max_bytes as u64 {
+            return Err(Error::QuotaExceeded(format!(
+                "writing {} bytes would grow {} past {} bytes, compact it to reclaim deleted values",
+                bytes,
+                self.storage_path.display(),
+                max_bytes
+            )));
+        }
 
-            let metadata = fs::metadata(file_path).unwrap();
-            if metadata.len() < config.storage.max_data_chunk_size as u64 {
-                break (data_chunk, chunk_index);
-            }
+        Ok(())
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Bytes in the data chunks that are no longer referenced by the index.
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.live_bytes)
+    }
 
-            if chunk_index == config.storage.max_data_chunks - 1 {
-                data_chunk += 1;
-                chunk_index = 0;
+    /// Ratio of dead bytes to live bytes.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.live_bytes == 0 {
+            return if self.dead_bytes() == 0 {
+                0.0
             } else {
-                chunk_index += 1;
-            }
+                f64::INFINITY
+            };
         }
+
+        self.dead_bytes() as f64 / self.live_bytes as f64
     }
-}
 
-struct File {
-    file: fs::File,
-    data_chunk_page: usize,
-    data_chunk_id: usize,
-}
+    pub fn bloom_stats(&self) -> BloomStats {
+        let bloom = &self.filter.bloom;
+        let set_bits = bloom.population();
 
-impl File {
-    pub fn new(path: &path::Path, data_chunk_page: usize, data_chunk_id: usize) -> Result<Self> {
-        let file = fs::OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path.join(format!("Data_{}_{}.db", data_chunk_page, data_chunk_id)))
-            .map_err(Error::IoError)?;
+        BloomStats {
+            bytes: bloom.get_bitvec().len(),
+            hashes: bloom.get_hashes(),
+            set_bits,
+            fill_ratio: set_bits as f64 / (bloom.get_bitvec().len() * 8) as f64,
+            fp_rate: bloom.estimated_fp_rate(),
+        }
+    }
 
-        Ok(Self {
-            file,
-            data_chunk_page,
-            data_chunk_id,
+    /// Measures the data chunks and the index, the WAL fields are left unset.
+    pub fn stats(&self) -> Result<CollectionStats> {
+        let chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        let chunk_bytes = chunks
+            .iter()
+            .map(|(_, path)| self.backend.len(path))
+            .collect::<std::io::Result<Vec<u64>>>()?;
+        let data_chunk_bytes = chunk_bytes.iter().sum::<u64>();
+
+        let index_bytes = if self.backend.is_memory() {
+            0
+        } else {
+            match fs::metadata(self.storage_path.join(INDEX_FILENAME)) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        // A chunk grows past its maximum size when a single write doesn't fit in it.
+        let capacity = chunk_bytes
+            .iter()
+            .map(|&bytes| bytes.max(self.config.max_data_chunk_size as u64))
+            .fold(0u64, u64::saturating_add);
+        let chunk_utilization = if capacity == 0 {
+            0.0
+        } else {
+            data_chunk_bytes as f64 / capacity as f64
+        };
+
+        Ok(CollectionStats {
+            live_keys: self.len(),
+            data_chunks: chunks.len(),
+            data_chunk_bytes,
+            index_bytes,
+            filter_bytes: (self.filter.bloom.get_bitvec().len()
+                + self
+                    .prefix_filter
+                    .as_ref()
+                    .map_or(0, |filter| filter.bloom.get_bitvec().len()))
+                as u64,
+            filter_fp_rate: self.filter.bloom.estimated_fp_rate(),
+            dead_bytes: data_chunk_bytes.saturating_sub(self.live_bytes),
+            chunk_utilization,
+            wal_log_chunks: 0,
         })
     }
 
-    pub fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
-        self.file.write_all(bytes)?;
-        Ok(())
+    /// Writes the live segments to new data chunks after the current ones, without changing
+    /// anything readers see. `finish_compaction` swaps them in, and nothing may be written
+    /// to the storage in between, which an upgradable read lock ensures.
+    pub fn prepare_compaction(&self) -> Result<PreparedCompaction> {
+        let started_at = std::time::Instant::now();
+        let orphaned_bytes = self
+            .crash_orphans()?
+            .iter()
+            .map(|(_, _, length)| length)
+            .sum();
+
+        let old_chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        let first_page = old_chunks
+            .iter()
+            .map(|(c, _)| c.page + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut entries = self
+            .index
+            .index
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect::<Vec<_>>();
+        entries
+            .sort_by_key(|(_, entry)| (entry.data_chunk.page, entry.data_chunk.id, entry.offset));
+
+        let mut live_blobs = HashSet::new();
+        let rewrite = self.rewrite_segments(entries, first_page, &mut live_blobs)?;
+
+        Ok(PreparedCompaction {
+            rewrite,
+            old_chunks,
+            live_blobs,
+            bytes_before: self.total_bytes,
+            orphaned_bytes,
+            started_at,
+        })
     }
 
-    pub fn metadata(&self) -> std::io::Result<std::fs::Metadata> {
-        self.file.metadata()
-    }
+    /// Points the index at the chunks written by `prepare_compaction`, persists it, then
+    /// removes the old chunks and the blobs no live segment references.
+    /// The new chunks use pages after every existing one and the index is persisted
+    /// before the old chunks are removed, so a crash never leaves the index pointing at
+    /// missing data.
+    pub fn finish_compaction(&mut self, prepared: PreparedCompaction) -> Result<CompactionStats> {
+        debug_assert_eq!(
+            self.total_bytes, prepared.bytes_before,
+            "storage written while a compaction was prepared"
+        );
+
+        let bytes_before = prepared.bytes_before;
+        let orphaned_bytes = prepared.orphaned_bytes;
+        let (file, written) = self.install_rewrite(prepared.rewrite)?;
+
+        self.index.write()?;
+
+        for (_, path) in prepared.old_chunks {
+            self.backend.remove(&path)?;
+        }
 
-    pub fn len(&self) -> std::io::Result<u64> {
-        self.metadata().map(|m| m.len())
+        self.remove_unreferenced_blobs(&prepared.live_blobs)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            path = %self.storage_path.display(),
+            bytes_before,
+            bytes_after = written,
+            orphaned_bytes,
+            live_segments = self.index.index.len(),
+            "Compacted storage"
+        );
+
+        self.file = file;
+        self.total_bytes = written;
+        self.live_bytes = written;
+
+        Ok(CompactionStats {
+            bytes_before,
+            bytes_after: self.total_bytes,
+            live_segments: self.index.index.len(),
+            orphaned_bytes,
+            duration: prepared.started_at.elapsed(),
+        })
     }
-}
 
-const INDEX_FILENAME: &str = ".index-dustdata";
+    /// Merges the data chunks filled below half of `max_data_chunk_size` into as few new
+    /// chunks as their live segments need, leaving the fuller chunks untouched.
+    /// Like a compaction, the index is persisted before the merged chunks are removed.
+    pub fn coalesce(&mut self) -> Result<CompactionStats> {
+        let started_at = std::time::Instant::now();
+        let bytes_before = self.total_bytes;
 
-struct Index {
-    index: IndexType,
-    path: path::PathBuf,
-    use_compression: bool,
-    compression_lvl: Option<u32>,
-}
+        let chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        let first_page = chunks.iter().map(|(c, _)| c.page + 1).max().unwrap_or(0);
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
-struct IndexEntry {
-    offset: u64,
-    data_chunk: DataChunk,
-}
+        let mut underfull = Vec::new();
+        let mut tombstones = BTreeMap::new();
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
-pub struct DataChunk {
-    page: usize,
-    id: usize,
-}
+        for (chunk, path) in chunks {
+            let len = self.backend.len(&path)?;
 
-type IndexType = HashMap<String, IndexEntry>; // (Data_*_*.db, offset)
+            if len >= self.config.max_data_chunk_size as u64 / 2 {
+                continue;
+            }
 
-impl Index {
-    pub fn new(
-        data_path: &path::Path,
-        use_compression: bool,
-        compression_lvl: Option<u32>,
-    ) -> Result<Self> {
-        let index_path = data_path.join(INDEX_FILENAME);
+            let mut bytes = Vec::new();
+            self.backend.reader(&path)?.read_to_end(&mut bytes)?;
 
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(index_path.clone())
-            .map_err(Error::IoError)?;
-
-        let index = if file.metadata().unwrap().len() == 0 {
-            let index = IndexType::new();
-
-            let bytes = if use_compression {
-                let mut encoder =
-                    GzEncoder::new(Vec::new(), Compression::new(compression_lvl.unwrap()));
-                encoder
-                    .write_all(&bincode::serialize(&index).unwrap())
-                    .unwrap();
-                encoder.finish().unwrap()
-            } else {
-                bincode::serialize(&index).unwrap()
-            };
+            let segments = Self::scan_chunk(&bytes).0;
 
-            file.write_all(&bytes).map_err(Error::IoError)?;
+            // Moving a clear marker after the chunks that follow it would clear their keys
+            // in a rebuilt index, so the chunks holding one are left in place.
+            if segments
+                .iter()
+                .any(|segment| matches!(segment.header, Some((SEGMENT_CLEAR, _))))
+            {
+                continue;
+            }
 
-            index
-        } else {
-            let mut bytes = Vec::new();
+            // The tombstones of deleted keys are moved too, so a rebuilt index doesn't
+            // restore an older value of the key from a chunk that stays.
+            for segment in segments {
+                if let Some((SEGMENT_TOMBSTONE, key)) = segment.header {
+                    if !self.index.index.contains_key(key) {
+                        tombstones
+                            .insert(key.to_string(), Self::segment(SEGMENT_TOMBSTONE, key, &[]));
+                    }
+                }
+            }
 
-            file.read_to_end(&mut bytes).map_err(Error::IoError)?;
+            underfull.push((chunk, path, len));
+        }
 
-            let mut decoder = GzDecoder::new(&bytes[..]);
+        if underfull.len() < 2 {
+            return Ok(CompactionStats {
+                bytes_before,
+                bytes_after: bytes_before,
+                live_segments: 0,
+                orphaned_bytes: 0,
+                duration: started_at.elapsed(),
+            });
+        }
 
-            if decoder.header().is_some() {
-                let mut decoded_bytes = Vec::new();
-                decoder.read_to_end(&mut decoded_bytes).unwrap();
+        let merged = underfull
+            .iter()
+            .map(|(chunk, _, _)| (chunk.page, chunk.id))
+            .collect::<HashSet<_>>();
 
-                bincode::deserialize(&decoded_bytes).unwrap()
-            } else {
-                bincode::deserialize(&bytes).unwrap()
-            }
-        };
+        let mut entries = self
+            .index
+            .index
+            .iter()
+            .filter(|(_, entry)| merged.contains(&(entry.data_chunk.page, entry.data_chunk.id)))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect::<Vec<_>>();
+        entries
+            .sort_by_key(|(_, entry)| (entry.data_chunk.page, entry.data_chunk.id, entry.offset));
 
-        Ok(Self {
-            index,
-            path: index_path,
-            use_compression,
-            compression_lvl,
+        let live_segments = entries.len();
+
+        // The new chunks come after every existing one, so new segments must go there
+        // too for chunk order to keep matching write order.
+        let rewrite = self.rewrite_segments(entries, first_page, &mut HashSet::new())?;
+        let (file, written) = self.install_rewrite(rewrite)?;
+        self.file = file;
+
+        let tombstones = tombstones.into_values().collect::<Vec<_>>();
+        self.append_segments(&tombstones)?;
+        self.live_bytes -= tombstones.iter().map(|t| t.len() as u64).sum::<u64>();
+
+        self.index.write()?;
+
+        let mut removed = 0;
+
+        for (_, path, len) in underfull {
+            self.backend.remove(&path)?;
+            removed += len;
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            path = %self.storage_path.display(),
+            live_segments,
+            bytes_before,
+            bytes_after = self.total_bytes - removed + written,
+            "Coalesced data chunks"
+        );
+
+        self.total_bytes = self.total_bytes - removed + written;
+
+        Ok(CompactionStats {
+            bytes_before,
+            bytes_after: self.total_bytes,
+            live_segments,
+            orphaned_bytes: 0,
+            duration: started_at.elapsed(),
         })
     }
 
-    pub fn insert(&mut self, key: String, index_entry: IndexEntry) -> Option<IndexEntry> {
-        self.index.insert(key, index_entry)
+    fn insert_filter_key(&mut self, key: &str) {
+        self.filter.insert(key);
+
+        if let (Some(prefix_filter), Some(depth)) =
+            (&mut self.prefix_filter, self.config.prefix_filter_depth)
+        {
+            for prefix in Self::prefixes(key, depth) {
+                prefix_filter.insert(prefix);
+            }
+        }
     }
 
-    pub fn remove(&mut self, key: String) -> Option<IndexEntry> {
-        self.index.remove(&key)
+    /// Sizes the bloom filters for the keys in the index again, dropping deleted keys.
+    fn rebuild_filters(&mut self) {
+        let keys = self.index.index.keys().cloned().collect::<Vec<_>>();
+        let seed = self.filter.bloom.get_seed();
+
+        self.prefix_filter = Self::prefix_filter(&keys, &self.config, seed);
+        self.filter = Filter::new(keys, &self.config, seed);
     }
 
-    pub fn clear(&mut self) {
-        self.index.clear();
+    /// Builds the prefix filter if `prefix_filter_depth` is set, its seed is derived from
+    /// the seed of the key filter so the two don't share their false positives.
+    fn prefix_filter(keys: &[String], config: &config::StorageConfig, seed: u64) -> Option<Filter> {
+        let depth = config.prefix_filter_depth?;
+        let prefixes = keys
+            .iter()
+            .flat_map(|key| Self::prefixes(key, depth))
+            .map(str::to_string)
+            .collect::<HashSet<_>>();
+
+        Some(Filter::new(prefixes.into_iter().collect(), config, !seed))
     }
 
-    pub fn get(&self, key: String) -> Option<IndexEntry> {
-        self.index.get(&key).copied()
+    /// The first 1 to `depth` characters of a key, shortest first.
+    fn prefixes(key: &str, depth: usize) -> impl Iterator<Item = &str> {
+        key.char_indices()
+            .skip(1)
+            .map(|(end, _)| end)
+            .chain([key.len()])
+            .take(depth)
+            .map(move |end| &key[..end])
     }
-}
 
-impl Drop for Index {
-    fn drop(&mut self) {
-        let bytes = bincode::serialize(&self.index).unwrap();
+    /// Copies the segments of `entries` to new staged data chunks starting at `first_page`,
+    /// which `install_rewrite` makes visible.
+    fn rewrite_segments(
+        &self,
+        entries: Vec<(String, IndexEntry)>,
+        first_page: usize,
+        live_blobs: &mut HashSet<u64>,
+    ) -> Result<Rewrite> {
+        let mut file = File::new(&self.storage_path, first_page, 0);
+        let mut staged = self.backend.stage(&file.path)?;
+        let mut chunks = Vec::new();
+        let mut index_entries = Vec::with_capacity(entries.len());
+        let mut file_len = 0;
+        let mut written = 0;
+
+        for (key, entry) in entries {
+            let segment = self.read_segment(&entry)?;
+
+            if let Some((SEGMENT_BLOB, _, payload)) = Self::split_segment(&segment[8..]) {
+                live_blobs.insert(Self::blob_reference(payload)?.0);
+            }
 
-        let bytes = if self.use_compression {
-            let mut encoder =
-                GzEncoder::new(Vec::new(), Compression::new(self.compression_lvl.unwrap()));
-            encoder.write_all(&bytes).unwrap();
+            if file_len > 0
+                && file_len + segment.len() as u64 > self.config.max_data_chunk_size as u64
+            {
+                staged.sync()?;
 
-            encoder.finish().unwrap()
-        } else {
-            bytes
-        };
+                let next = self.next_chunk(&file);
+                let next_staged = self.backend.stage(&next.path)?;
+                chunks.push((
+                    file.path.clone(),
+                    std::mem::replace(&mut staged, next_staged),
+                ));
 
-        fs::write(&self.path, bytes).unwrap();
-    }
-}
+                file = next;
+                file_len = 0;
+            }
 
-struct Filter {
-    bloom: bloom::BloomFilter,
-}
+            staged.write_all(&segment)?;
+
+            index_entries.push((
+                key,
+                IndexEntry {
+                    offset: file_len,
+                    length: segment.len() as u64,
+                    data_chunk: DataChunk {
+                        page: file.data_chunk_page,
+                        id: file.data_chunk_id,
+                    },
+                },
+            ));
+
+            file_len += segment.len() as u64;
+            written += segment.len() as u64;
+        }
 
-impl Filter {
-    pub fn new(keys: Vec<String>) -> Self {
-        let mut bloom = bloom::BloomFilter::new(0.01, (keys.len() + 1) * 8);
+        staged.sync()?;
+        chunks.push((file.path.clone(), staged));
+
+        Ok(Rewrite {
+            chunks,
+            entries: index_entries,
+            file,
+            written,
+        })
+    }
+
+    /// Moves the chunks of a rewrite in place and points the index at their segments.
+    /// Returns the last chunk and the bytes written.
+    fn install_rewrite(&mut self, rewrite: Rewrite) -> Result<(File, u64)> {
+        for (path, staged) in rewrite.chunks {
+            self.backend.install(&path, staged)?;
+        }
 
-        for key in keys {
-            bloom.insert(&key);
+        for (key, entry) in rewrite.entries {
+            self.index.index.insert(key, entry);
         }
 
-        Self { bloom }
+        Ok((rewrite.file, rewrite.written))
+    }
+
+    /// Rebuilds the index and the bloom filter by scanning every data chunk in write
+    /// order, returning the number of entries rebuilt.
+    /// Segments written before keys were stored can't be recovered and are skipped, as
+    /// is a torn segment at the end of a chunk.
+    pub fn rebuild_index(&mut self) -> Result<usize> {
+        let mut chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        chunks.sort_by_key(|(chunk, _)| (chunk.page, chunk.id));
+
+        let mut index = IndexType::new();
+
+        for (data_chunk, path) in chunks {
+            let mut bytes = Vec::new();
+            self.backend.reader(&path)?.read_to_end(&mut bytes)?;
+
+            for segment in Self::scan_chunk(&bytes).0 {
+                let entry = IndexEntry {
+                    offset: segment.offset,
+                    length: segment.length,
+                    data_chunk,
+                };
+
+                match segment.header {
+                    Some((SEGMENT_TOMBSTONE, key)) => {
+                        index.remove(key);
+                    }
+                    Some((SEGMENT_CLEAR, _)) => index.clear(),
+                    Some((_, key)) => {
+                        index.insert(key.to_string(), entry);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        self.live_bytes = index.values().map(|entry| entry.length).sum();
+        self.index.index = index;
+        self.rebuild_filters();
+        self.index.write()?;
+
+        Ok(self.index.index.len())
+    }
+
+    /// Checks the index against the data chunks without changing anything.
+    pub fn check_integrity<T>(&self) -> Result<IntegrityReport>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+    {
+        let mut report = IntegrityReport::default();
+        let mut chunks = HashMap::new();
+
+        for (data_chunk, path) in Self::chunk_files(&self.backend, &self.storage_path)? {
+            let mut bytes = Vec::new();
+            self.backend.reader(&path)?.read_to_end(&mut bytes)?;
+
+            chunks.insert(data_chunk.filename(), bytes);
+        }
+
+        let mut referenced = HashSet::new();
+
+        for (key, entry) in &self.index.index {
+            let filename = entry.data_chunk.filename();
+            referenced.insert((filename.clone(), entry.offset));
+
+            if !self.filter.contains(key) {
+                report.filter_misses.push(key.clone());
+            }
+
+            let Some(bytes) = chunks.get(&filename) else {
+                report.missing_chunks.push(key.clone());
+                continue;
+            };
+
+            if entry.offset + entry.length > bytes.len() as u64 {
+                report.out_of_bounds.push(key.clone());
+                continue;
+            }
+
+            let mut reader = std::io::Cursor::new(bytes.as_slice());
+
+            if self
+                .deserialize_value::<T>(&mut reader, key, entry.offset, &filename)
+                .is_err()
+            {
+                report.undecodable.push(key.clone());
+            }
+        }
+
+        let mut stale_filter_keys = BTreeSet::new();
+
+        for (filename, bytes) in &chunks {
+            let (segments, scanned) = Self::scan_chunk(bytes);
+
+            for segment in segments {
+                if !referenced.contains(&(filename.clone(), segment.offset)) {
+                    report
+                        .orphaned_segments
+                        .push((filename.clone(), segment.offset));
+                }
+
+                if let Some((_, key)) = segment.header {
+                    if !self.index.index.contains_key(key) && self.filter.contains(key) {
+                        stale_filter_keys.insert(key.to_string());
+                    }
+                }
+            }
+
+            if scanned < bytes.len() {
+                // The bytes of a torn segment at the end of the chunk.
+                report
+                    .orphaned_segments
+                    .push((filename.clone(), scanned as u64));
+            }
+        }
+
+        report.orphaned_segments.sort();
+        report.unindexed_segments = self
+            .crash_orphans()?
+            .into_iter()
+            .map(|(data_chunk, offset, _)| (data_chunk.filename(), offset))
+            .collect();
+        report.unindexed_segments.sort();
+        report.stale_filter_keys = stale_filter_keys.into_iter().collect();
+
+        Ok(report)
+    }
+
+    /// Finds the segments a crash between appending to a data chunk and persisting the
+    /// index left behind, as (data chunk, offset, length): the last segment of a key when
+    /// the index doesn't reference it, and torn segments at the end of a chunk. Older
+    /// segments of a key were superseded by the later ones, so they aren't counted.
+    fn crash_orphans(&self) -> Result<Vec<(DataChunk, u64, u64)>> {
+        let mut chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        chunks.sort_by_key(|(chunk, _)| (chunk.page, chunk.id));
+
+        let mut orphans = Vec::new();
+        let mut latest = HashMap::new(); // None when the key's last segment is a tombstone
+
+        for (data_chunk, path) in chunks {
+            let mut bytes = Vec::new();
+            self.backend.reader(&path)?.read_to_end(&mut bytes)?;
+
+            let (segments, scanned) = Self::scan_chunk(&bytes);
+
+            for segment in segments {
+                match segment.header {
+                    Some((SEGMENT_TOMBSTONE, key)) => {
+                        latest.insert(key.to_string(), None);
+                    }
+                    Some((SEGMENT_CLEAR, _)) => latest.clear(),
+                    Some((_, key)) => {
+                        latest.insert(
+                            key.to_string(),
+                            Some((data_chunk, segment.offset, segment.length)),
+                        );
+                    }
+                    None => {}
+                }
+            }
+
+            if scanned < bytes.len() {
+                orphans.push((data_chunk, scanned as u64, (bytes.len() - scanned) as u64));
+            }
+        }
+
+        for (key, segment) in latest {
+            let Some((data_chunk, offset, length)) = segment else {
+                continue;
+            };
+
+            let indexed = self.index.index.get(&key).is_some_and(|entry| {
+                entry.offset == offset
+                    && (entry.data_chunk.page, entry.data_chunk.id)
+                        == (data_chunk.page, data_chunk.id)
+            });
+
+            if !indexed {
+                orphans.push((data_chunk, offset, length));
+            }
+        }
+
+        Ok(orphans)
+    }
+
+    /// Splits a data chunk into its segments, returning them with the number of bytes
+    /// they cover. Anything after that is a torn segment.
+    fn scan_chunk(bytes: &[u8]) -> (Vec<ScannedSegment<'_>>, usize) {
+        let mut segments = Vec::new();
+        let mut offset = 0;
+
+        while let Some(prefix) = bytes.get(offset..offset + 8) {
+            let length = u64::from_le_bytes(prefix.try_into().unwrap());
+            let body_length = (length & !FLAGGED_SEGMENT) as usize;
+
+            let Some(body) = bytes.get(offset + 8..offset + 8 + body_length) else {
+                break;
+            };
+
+            let header = if length & FLAGGED_SEGMENT != 0 {
+                Self::split_segment(body).map(|(flag, key, _)| (flag, key))
+            } else {
+                None
+            };
+
+            segments.push(ScannedSegment {
+                offset: offset as u64,
+                length: 8 + body_length as u64,
+                header,
+            });
+
+            offset += 8 + body_length;
+        }
+
+        (segments, offset)
+    }
+
+    fn append_segment(&mut self, segment: &[u8]) -> Result<IndexEntry> {
+        let mut index_entries = self.append_segments(&[segment])?;
+
+        Ok(index_entries.pop().unwrap())
+    }
+
+    /// Appends segments in a single write and syncs them, so index entries are only
+    /// created for segments that fully landed in the data chunk.
+    fn append_segments<S: AsRef<[u8]>>(&mut self, segments: &[S]) -> Result<Vec<IndexEntry>> {
+        let offset = self.backend.len(&self.file.path)?;
+        let bytes = segments
+            .iter()
+            .flat_map(|s| s.as_ref())
+            .copied()
+            .collect::<Vec<u8>>();
+
+        self.backend.append(&self.file.path, &bytes)?;
+        self.backend.sync(&self.file.path)?;
+
+        let written = self.backend.len(&self.file.path)? - offset;
+
+        if written != bytes.len() as u64 {
+            return Err(Error::CorruptedData(format!(
+                "Short write to data chunk {}, wrote {} of {} bytes",
+                self.file.path.display(),
+                written,
+                bytes.len()
+            )));
+        }
+
+        self.total_bytes += bytes.len() as u64;
+        self.live_bytes += bytes.len() as u64;
+
+        let data_chunk = DataChunk {
+            page: self.file.data_chunk_page,
+            id: self.file.data_chunk_id,
+        };
+        let mut segment_offset = offset;
+
+        Ok(segments
+            .iter()
+            .map(|segment| {
+                let length = segment.as_ref().len() as u64;
+                let index_entry = IndexEntry {
+                    offset: segment_offset,
+                    length,
+                    data_chunk,
+                };
+
+                segment_offset += length;
+                index_entry
+            })
+            .collect())
+    }
+
+    /// Reads a whole segment, including its length prefix.
+    fn read_segment(&self, entry: &IndexEntry) -> Result<Vec<u8>> {
+        let (filename, mut file) = self.chunk_reader(entry.data_chunk)?;
+
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        let mut segment = vec![0; entry.length as usize];
+        file.read_exact(&mut segment).map_err(|e| {
+            Error::CorruptedData(format!(
+                "Corrupted data chunk {} and offset {}. Error: {}",
+                filename, entry.offset, e
+            ))
+        })?;
+
+        Ok(segment)
+    }
+
+    fn next_chunk(&self, file: &File) -> File {
+        if file.data_chunk_id + 1 >= self.config.max_data_chunks {
+            File::new(&self.storage_path, file.data_chunk_page + 1, 0)
+        } else {
+            File::new(
+                &self.storage_path,
+                file.data_chunk_page,
+                file.data_chunk_id + 1,
+            )
+        }
+    }
+
+    /// Increments the persisted sequence and returns it as a zero-padded key.
+    /// The sequence survives `clear`, so a key is never generated twice.
+    pub fn next_auto_key(&mut self) -> Result<String> {
+        let sequence = self
+            .sequence
+            .checked_add(1)
+            .ok_or_else(|| Error::Other("The key sequence is exhausted".to_string()))?;
+
+        if !self.backend.is_memory() {
+            super::write_file_atomically(
+                &self.storage_path.join(SEQUENCE_FILENAME),
+                &sequence.to_le_bytes(),
+            )?;
+        }
+
+        self.sequence = sequence;
+
+        Ok(format!(
+            "{:0width$}",
+            sequence,
+            width = self.config.auto_key_width
+        ))
+    }
+
+    fn read_sequence(backend: &Backend, storage_path: &path::Path) -> Result<u64> {
+        if backend.is_memory() {
+            return Ok(0);
+        }
+
+        match fs::read(storage_path.join(SEQUENCE_FILENAME)) {
+            Ok(bytes) => bytes
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| Error::CorruptedData("Corrupted key sequence".to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lists every `Data_*_*.db` file in the storage directory.
+    fn chunk_files(
+        backend: &Backend,
+        storage_path: &path::Path,
+    ) -> Result<Vec<(DataChunk, path::PathBuf)>> {
+        let mut chunks = Vec::new();
+
+        if !backend.is_memory() && !backend.exists(storage_path) {
+            return Ok(chunks);
+        }
+
+        for path in backend.list(storage_path)? {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+
+            if let Some(data_chunk) = DataChunk::from_filename(name) {
+                chunks.push((data_chunk, path));
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    /// Syncs the current data chunk and persists the index.
+    pub fn flush(&mut self) -> Result<()> {
+        self.backend.sync(&self.file.path)?;
+        self.index.write()?;
+
+        // The chunks are only reclaimed once the clear is persisted, and a failed commit
+        // can still restore the cleared keys from them until then.
+        if std::mem::take(&mut self.cleared) {
+            self.reclaim_cleared()?;
+        }
+
+        Ok(())
+    }
+
+    /// Frees the space of the data chunks written before a clear. Without keys every chunk
+    /// and blob is removed and the chunks start again at `Data_0_0.db`, otherwise the
+    /// keys written since are compacted.
+    fn reclaim_cleared(&mut self) -> Result<()> {
+        if !self.index.index.is_empty() {
+            let prepared = self.prepare_compaction()?;
+            self.finish_compaction(prepared)?;
+
+            return Ok(());
+        }
+
+        let mut chunks = Self::chunk_files(&self.backend, &self.storage_path)?;
+        chunks.sort_by_key(|(chunk, _)| (chunk.page, chunk.id));
+
+        // Oldest first, so a crash midway never leaves older values without the clear
+        // marker that follows them for `rebuild_index`.
+        for (_, path) in chunks {
+            self.backend.remove(&path)?;
+        }
+
+        self.remove_unreferenced_blobs(&HashSet::new())?;
+
+        self.file = File::new(&self.storage_path, 0, 0);
+        self.backend.touch(&self.file.path)?;
+        self.total_bytes = 0;
+        self.live_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Copies the data chunks, the index, the sequence and the blobs to `path`, after
+    /// persisting the index so the copy opens with every value.
+    pub fn copy_to(&mut self, path: &path::Path) -> Result<()> {
+        self.flush()?;
+
+        super::copy_dir_all(&self.storage_path, path)?;
+
+        Ok(())
+    }
+
+    /// Segments are `length u64 LE + flag + key length u32 LE + key + value`. The flag
+    /// tells whether the value is compressed, stored in a blob or the segment is a
+    /// tombstone. The high bit of the length marks the format, segments without it were
+    /// written before keys were stored and aren't readable anymore.
+    /// Values over the blob threshold are written to a blob file first, and the segment
+    /// holds its hash and length.
+    /// `Vec<u8>` values, like those of a `RawCollection`, are written as they are instead
+    /// of bincode, and flagged with `SEGMENT_BYTES`.
+    fn serialize_value<T>(&mut self, key: &str, value: &T, version: u64) -> Result<Vec<u8>>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static,
+    {
+        let (bytes, serialized_value) = match (value as &dyn Any).downcast_ref::<Vec<u8>>() {
+            Some(value) => (SEGMENT_BYTES, value.clone()),
+            None => (0, bincode::serialize(value).unwrap()),
+        };
+
+        let compression = self
+            .config
+            .value_compression
+            .as_ref()
+            .filter(|_| serialized_value.len() >= self.config.min_compress_size);
+
+        let (flag, payload) = match (self.config.blob_threshold, compression) {
+            (Some(threshold), _) if serialized_value.len() > threshold => {
+                let blob = codec::encode(&serialized_value, compression)?;
+                let hash = self.write_blob(&blob)?;
+
+                let mut reference = hash.to_le_bytes().to_vec();
+                reference.extend((blob.len() as u64).to_le_bytes());
+
+                (SEGMENT_BLOB, reference)
+            }
+            (_, Some(compression)) => (
+                SEGMENT_COMPRESSED,
+                codec::encode(&serialized_value, Some(compression))?,
+            ),
+            _ => (SEGMENT_RAW, serialized_value),
+        };
+
+        let mut versioned_payload = Vec::with_capacity(8 + payload.len());
+        versioned_payload.extend(version.to_le_bytes());
+        versioned_payload.extend(payload);
+
+        Ok(Self::segment(
+            flag | SEGMENT_VERSIONED | bytes,
+            key,
+            &versioned_payload,
+        ))
+    }
+
+    /// Writes a blob named after the hash of its contents, returning the hash.
+    /// Values with the same contents share a blob.
+    fn write_blob(&mut self, blob: &[u8]) -> Result<u64> {
+        let hash = farmhash::hash64(blob);
+        let blobs_path = self.storage_path.join(BLOBS_DIRNAME);
+        let path = blobs_path.join(format!("{:016x}.blob", hash));
+
+        if self.backend.exists(&path) {
+            // A blob torn by a crash is written again.
+            if self.backend.len(&path)? == blob.len() as u64 {
+                return Ok(hash);
+            }
+
+            self.backend.remove(&path)?;
+        }
+
+        self.backend.create_dir_all(&blobs_path)?;
+        self.backend.append(&path, blob)?;
+        self.backend.sync(&path)?;
+
+        Ok(hash)
+    }
+
+    fn read_blob(&self, hash: u64, length: u64) -> Result<Vec<u8>> {
+        let filename = format!("{:016x}.blob", hash);
+
+        let mut blob = Vec::new();
+        self.backend
+            .reader(&self.storage_path.join(BLOBS_DIRNAME).join(&filename))
+            .and_then(|mut reader| reader.read_to_end(&mut blob))
+            .map_err(|e| {
+                Error::CorruptedData(format!("Blob {} is unreadable. Error: {}", filename, e))
+            })?;
+
+        if blob.len() as u64 != length || farmhash::hash64(&blob) != hash {
+            return Err(Error::CorruptedData(format!(
+                "Blob {} doesn't match its reference",
+                filename
+            )));
+        }
+
+        codec::decode(blob)
+    }
+
+    /// Splits the payload of a blob segment into the hash and the length of the blob.
+    fn blob_reference(payload: &[u8]) -> Result<(u64, u64)> {
+        if payload.len() != 16 {
+            return Err(Error::CorruptedData("Invalid blob reference".to_string()));
+        }
+
+        let (hash, length) = payload.split_at(8);
+
+        Ok((
+            u64::from_le_bytes(hash.try_into().unwrap()),
+            u64::from_le_bytes(length.try_into().unwrap()),
+        ))
+    }
+
+    fn remove_unreferenced_blobs(&mut self, live_blobs: &HashSet<u64>) -> Result<()> {
+        let blobs_path = self.storage_path.join(BLOBS_DIRNAME);
+
+        if !self.backend.is_memory() && !self.backend.exists(&blobs_path) {
+            return Ok(());
+        }
+
+        for path in self.backend.list(&blobs_path)? {
+            let hash = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".blob"))
+                .and_then(|hash| u64::from_str_radix(hash, 16).ok());
+
+            if hash.is_some_and(|hash| !live_blobs.contains(&hash)) {
+                self.backend.remove(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn segment(flag: u8, key: &str, payload: &[u8]) -> Vec<u8> {
+        let length = 1 + 4 + key.len() + payload.len();
+
+        let mut bytes = Vec::with_capacity(8 + length);
+        bytes.extend((length as u64 | FLAGGED_SEGMENT).to_le_bytes());
+        bytes.push(flag);
+        bytes.extend((key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(payload);
+
+        bytes
+    }
+
+    /// Splits the body of a flagged segment into its flag, key and payload, clearing the
+    /// bytes bit from the flag.
+    fn split_segment(body: &[u8]) -> Option<(u8, &str, &[u8])> {
+        Self::split_versioned_segment(body)
+            .map(|(flag, key, _, payload)| (flag & !SEGMENT_BYTES, key, payload))
+    }
+
+    /// Splits the body of a flagged segment into its flag, key, version and payload.
+    /// The versioned bit is cleared from the flag, and unversioned segments are at version 0.
+    fn split_versioned_segment(body: &[u8]) -> Option<(u8, &str, u64, &[u8])> {
+        let (&flag, body) = body.split_first()?;
+        let (key_len, body) = body.split_at_checked(4)?;
+        let key_len = u32::from_le_bytes(key_len.try_into().unwrap()) as usize;
+        let (key, mut payload) = body.split_at_checked(key_len)?;
+
+        let mut version = 0;
+
+        if flag & SEGMENT_VERSIONED != 0 {
+            let (version_bytes, rest) = payload.split_at_checked(8)?;
+            version = u64::from_le_bytes(version_bytes.try_into().unwrap());
+            payload = rest;
+        }
+
+        Some((
+            flag & !SEGMENT_VERSIONED,
+            std::str::from_utf8(key).ok()?,
+            version,
+            payload,
+        ))
+    }
+
+    fn deserialize_value<T>(
+        &self,
+        file: &mut dyn ReadSeek,
+        key: &str,
+        offset: u64,
+        filename: &str,
+    ) -> Result<T>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+    {
+        Ok(self
+            .deserialize_versioned_value(file, key, offset, filename)?
+            .0)
+    }
+
+    fn deserialize_versioned_value<T>(
+        &self,
+        file: &mut dyn ReadSeek,
+        key: &str,
+        offset: u64,
+        filename: &str,
+    ) -> Result<(T, u64)>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
+    {
+        file.seek(SeekFrom::Start(offset))?;
+
+        let corrupted = |e: &dyn std::fmt::Display| {
+            Error::CorruptedData(format!(
+                "Corrupted data chunk {} and offset {}. Error: {}",
+                filename, offset, e
+            ))
+        };
+
+        let mut length = [0; 8];
+        file.read_exact(&mut length).map_err(|e| corrupted(&e))?;
+        let length = u64::from_le_bytes(length);
+
+        if length & FLAGGED_SEGMENT == 0 {
+            return Err(corrupted(
+                &"segment without a key, written by an older format",
+            ));
+        }
+
+        let mut segment = vec![0; (length & !FLAGGED_SEGMENT) as usize];
+        file.read_exact(&mut segment).map_err(|e| corrupted(&e))?;
+
+        let Some((flag, segment_key, version, payload)) = Self::split_versioned_segment(&segment)
+        else {
+            return Err(corrupted(&"invalid segment header"));
+        };
+
+        if segment_key != key {
+            return Err(corrupted(&format!(
+                "segment belongs to key {}, expected {}",
+                segment_key, key
+            )));
+        }
+
+        let value = match flag & !SEGMENT_BYTES {
+            SEGMENT_RAW => payload.to_vec(),
+            SEGMENT_COMPRESSED => codec::decode(payload.to_vec()).map_err(|e| corrupted(&e))?,
+            SEGMENT_BLOB => {
+                let (hash, length) = Self::blob_reference(payload).map_err(|e| corrupted(&e))?;
+
+                self.read_blob(hash, length)?
+            }
+            _ => return Err(corrupted(&"invalid segment flag")),
+        };
+
+        let value = if flag & SEGMENT_BYTES != 0 {
+            Self::from_bytes(value)
+        } else {
+            bincode::deserialize(&value)
+        }
+        .map_err(|e| corrupted(&e))?;
+
+        Ok((value, version))
+    }
+
+    /// Returns the value of a `SEGMENT_BYTES` segment, decoding it like its bincode
+    /// encoding when it's read as another type than `Vec<u8>`.
+    fn from_bytes<T: DeserializeOwned + 'static>(value: Vec<u8>) -> bincode::Result<T> {
+        match (Box::new(value) as Box<dyn Any>).downcast::<T>() {
+            Ok(value) => Ok(*value),
+            Err(value) => {
+                let value = value.downcast::<Vec<u8>>().unwrap();
+
+                bincode::deserialize(&bincode::serialize(&*value).unwrap())
+            }
+        }
+    }
+
+    /// Picks the chunk new segments are appended to, continuing after the last one so
+    /// chunk order matches write order.
+    fn data_chunk(
+        backend: &Backend,
+        path: &path::Path,
+        config: &config::DustDataConfig,
+    ) -> Result<(usize, usize)> {
+        let last = Self::chunk_files(backend, path)?
+            .into_iter()
+            .max_by_key(|(chunk, _)| (chunk.page, chunk.id));
+
+        let Some((chunk, chunk_path)) = last else {
+            return Ok((0, 0));
+        };
+
+        if backend.len(&chunk_path)? < config.storage.max_data_chunk_size as u64 {
+            Ok((chunk.page, chunk.id))
+        } else if chunk.id + 1 >= config.storage.max_data_chunks {
+            Ok((chunk.page + 1, 0))
+        } else {
+            Ok((chunk.page, chunk.id + 1))
+        }
+    }
+}
+
+const SEGMENT_FORMAT_VERSION: u8 = 4; // 4 added `SEGMENT_BYTES`, formats 2 and 3 read as is
+const MIN_SEGMENT_FORMAT_VERSION: u8 = 2; // oldest format readable without a migration
+const FLAGGED_SEGMENT: u64 = 1 << 63;
+const SEGMENT_RAW: u8 = 0;
+const SEGMENT_COMPRESSED: u8 = 1;
+const SEGMENT_TOMBSTONE: u8 = 2;
+const SEGMENT_CLEAR: u8 = 3;
+const SEGMENT_BLOB: u8 = 4;
+const SEGMENT_VERSIONED: u8 = 1 << 7; // set on values whose version follows their key
+const SEGMENT_BYTES: u8 = 1 << 6; // set on `Vec<u8>` values written without bincode
+
+/// The index entries removed by `Storage::clear`.
+pub struct ClearedEntries(IndexType);
+
+/// A compaction written by `Storage::prepare_compaction`, swapped in by `Storage::finish_compaction`.
+pub struct PreparedCompaction {
+    rewrite: Rewrite,
+    old_chunks: Vec<(DataChunk, path::PathBuf)>,
+    live_blobs: HashSet<u64>,
+    bytes_before: u64,
+    orphaned_bytes: u64,
+    started_at: std::time::Instant,
+}
+
+/// Data chunks written by `Storage::rewrite_segments`, not referenced by the index yet.
+struct Rewrite {
+    chunks: Vec<(path::PathBuf, StagedFile)>,
+    entries: Vec<(String, IndexEntry)>,
+    file: File, // the last chunk written
+    written: u64,
+}
+
+/// The data chunk new segments are appended to.
+struct File {
+    path: path::PathBuf,
+    data_chunk_page: usize,
+    data_chunk_id: usize,
+}
+
+impl File {
+    pub fn new(path: &path::Path, data_chunk_page: usize, data_chunk_id: usize) -> Self {
+        Self {
+            path: path.join(format!("Data_{}_{}.db", data_chunk_page, data_chunk_id)),
+            data_chunk_page,
+            data_chunk_id,
+        }
+    }
+}
+
+const INDEX_FILENAME: &str = ".index-dustdata";
+const SEQUENCE_FILENAME: &str = ".sequence-dustdata";
+const BLOBS_DIRNAME: &str = "blobs";
+
+struct Index {
+    index: IndexType,
+    path: Option<path::PathBuf>, // None when the index isn't persisted
+    compression: Option<config::CompressionConfig>,
+    segment_version: u8, // format of the segments the index points at
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct IndexEntry {
+    offset: u64,
+    length: u64, // segment length, including the length prefix
+    data_chunk: DataChunk,
+}
+
+#[derive(Deserialize)]
+struct LegacyIndexEntry {
+    offset: u64,
+    data_chunk: DataChunk,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct DataChunk {
+    page: usize,
+    id: usize,
+}
+
+impl DataChunk {
+    fn filename(&self) -> String {
+        format!("Data_{}_{}.db", self.page, self.id)
+    }
+
+    fn from_filename(filename: &str) -> Option<Self> {
+        let (page, id) = filename
+            .strip_prefix("Data_")?
+            .strip_suffix(".db")?
+            .split_once('_')?;
+
+        Some(Self {
+            page: page.parse().ok()?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+type IndexType = BTreeMap<String, IndexEntry>; // (Data_*_*.db, offset)
+
+const INDEX_MAGIC: &[u8; 4] = b"DIX2"; // followed by the segment format version
+const INDEX_V1_MAGIC: &[u8; 4] = b"DIX1";
+
+impl Index {
+    /// Loads the index, a read-only index is never written to.
+    pub fn new(
+        data_path: Option<&path::Path>,
+        compression: Option<config::CompressionConfig>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mut index = Self {
+            index: IndexType::new(),
+            path: None,
+            compression,
+            segment_version: SEGMENT_FORMAT_VERSION,
+        };
+
+        let Some(data_path) = data_path else {
+            return Ok(index);
+        };
+
+        let index_path = data_path.join(INDEX_FILENAME);
+
+        if read_only && !index_path.exists() {
+            return Ok(index);
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(&index_path)?;
+
+        if !read_only {
+            index.path = Some(index_path);
+        }
+
+        if file.metadata()?.len() == 0 {
+            drop(file);
+            index.write()?;
+
+            return Ok(index);
+        }
+
+        let mut bytes = Vec::new();
+
+        file.read_to_end(&mut bytes)?;
+
+        let bytes = codec::decode(bytes)?;
+
+        let corrupted = |e| Error::CorruptedData(format!("Corrupted index. Error: {}", e));
+
+        if let Some(bytes) = bytes.strip_prefix(INDEX_MAGIC) {
+            let (&segment_version, bytes) = bytes
+                .split_first()
+                .ok_or_else(|| Error::CorruptedData("Corrupted index header".to_string()))?;
+
+            // The Drop of an index of another format leaves the file as it was.
+            index.segment_version = segment_version;
+
+            // Newer formats may lay out the index entries differently too.
+            if segment_version > SEGMENT_FORMAT_VERSION {
+                return Err(Error::VersionMismatch(format!(
+                    "The index {} uses segment format {}, but this build only supports up to format {}",
+                    data_path.join(INDEX_FILENAME).display(),
+                    segment_version,
+                    SEGMENT_FORMAT_VERSION
+                )));
+            }
+
+            index.index = bincode::deserialize(bytes).map_err(corrupted)?;
+        } else if let Some(bytes) = bytes.strip_prefix(INDEX_V1_MAGIC) {
+            index.segment_version = 1;
+            index.index = bincode::deserialize(bytes).map_err(corrupted)?;
+        } else {
+            let legacy_index: HashMap<String, LegacyIndexEntry> =
+                bincode::deserialize(&bytes).map_err(corrupted)?;
+
+            index.index = legacy_index
+                .into_iter()
+                .map(|(key, entry)| {
+                    let entry = IndexEntry {
+                        offset: entry.offset,
+                        length: 0,
+                        data_chunk: entry.data_chunk,
+                    };
+
+                    (key, entry)
+                })
+                .collect();
+            index.segment_version = 0;
+        }
+
+        Ok(index)
+    }
+
+    pub fn insert(&mut self, key: String, index_entry: IndexEntry) -> Option<IndexEntry> {
+        self.index.insert(key, index_entry)
+    }
+
+    pub fn remove(&mut self, key: String) -> Option<IndexEntry> {
+        self.index.remove(&key)
+    }
+
+    pub fn get(&self, key: String) -> Option<IndexEntry> {
+        self.index.get(&key).copied()
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut bytes = INDEX_MAGIC.to_vec();
+        bytes.push(self.segment_version);
+        bytes.extend(bincode::serialize(&self.index).unwrap());
+
+        let bytes = codec::encode(&bytes, self.compression.as_ref())?;
+
+        super::write_file_atomically(path, &bytes)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        // Best effort, `Collection::close` reports persistence errors.
+        // An index of an older segment format is left as it was.
+        if self.segment_version == SEGMENT_FORMAT_VERSION {
+            self.write().ok();
+        }
+    }
+}
+
+struct Filter {
+    bloom: bloom::BloomFilter,
+    capacity: usize, // number of keys the filter was sized for
+}
+
+impl Filter {
+    /// Sizes the filter for `bloom_capacity` keys, or 8 times `keys` if that's more, and
+    /// inserts `keys`.
+    pub fn new(keys: Vec<String>, config: &config::StorageConfig, seed: u64) -> Self {
+        let capacity = config.bloom_capacity.max((keys.len() + 1) * 8);
+        let mut bloom = bloom::BloomFilter::with_seed(config.bloom_fp_rate, capacity, seed);
+
+        for key in &keys {
+            bloom.insert(key);
+        }
+
+        Self { bloom, capacity }
     }
 
     pub fn insert(&mut self, key: &str) {
@@ -408,10 +2035,6 @@ impl Filter {
         self.bloom.contains(key)
     }
 
-    pub fn remove(&mut self, key: &str) {
-        self.bloom.remove(key);
-    }
-
     pub fn clear(&mut self) {
         self.bloom.clear();
     }
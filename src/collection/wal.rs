@@ -1,14 +1,16 @@
 use crate::error::{Error, Result};
 
-use super::{config, Operation, Transaction};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use super::backend::{Backend, ReadSeek};
+use super::{codec, config, Operation, Transaction};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Read, SeekFrom, Write};
 use std::ops::RangeBounds;
-use std::{fs, path};
+use std::sync::Arc;
+use std::{fs, io, path};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionLog<T> {
@@ -35,6 +37,18 @@ pub enum WalOperation<T> {
 }
 
 impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> WalOperation<T> {
+    /// Returns the operation that replays this one
+    pub fn operation(&self) -> Operation<T> {
+        match self {
+            WalOperation::Insert { key, value } => Operation::Insert(key.clone(), value.clone()),
+            WalOperation::Update { key, new_value, .. } => {
+                Operation::Update(key.clone(), new_value.clone())
+            }
+            WalOperation::Delete { key, .. } => Operation::Delete(key.clone()),
+            WalOperation::Drop => Operation::Drop,
+        }
+    }
+
     pub fn reverse_operation(&self) -> Operation<T> {
         match self {
             WalOperation::Insert { key, .. } => Operation::Delete(key.clone()),
@@ -47,75 +61,234 @@ impl<T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned> Wa
     }
 }
 
+/// The log chunk new transactions are appended to.
 struct LogFile {
     pub id: usize,
-    pub file: fs::File,
+    pub path: path::PathBuf,
 }
 
 impl LogFile {
-    pub fn new(log_path: &path::Path, max_log_size: u64) -> Self {
-        let id = LogFile::log_chunk(log_path, max_log_size);
+    pub fn new(
+        backend: &mut Backend,
+        log_path: &path::Path,
+        max_log_size: u64,
+        read_only: bool,
+    ) -> Result<Self> {
+        let id = LogFile::log_chunk(backend, log_path, max_log_size)?;
+        let path = log_path.join(format!("DustDataLog_{}", id));
+
+        if !read_only {
+            backend.touch(&path)?;
+        }
+
+        Ok(Self { id, path })
+    }
 
-        let file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path.join(format!("DustDataLog_{}", id)))
-            .unwrap();
+    /// Picks the chunk new records are appended to, continuing after the last one so ids
+    /// keep growing once the oldest chunks are pruned.
+    fn log_chunk(backend: &Backend, log_path: &path::Path, max_log_size: u64) -> Result<usize> {
+        let Some(last) = Self::chunk_ids(backend, log_path)?.pop() else {
+            return Ok(0);
+        };
 
-        Self { id, file }
+        if backend.len(&log_path.join(format!("DustDataLog_{}", last)))? < max_log_size {
+            Ok(last)
+        } else {
+            Ok(last + 1)
+        }
     }
 
-    fn log_chunk(log_path: &path::Path, max_log_size: u64) -> usize {
-        let mut id = 0;
+    /// Ids of the `DustDataLog_*` chunks in the log directory, in ascending order.
+    fn chunk_ids(backend: &Backend, log_path: &path::Path) -> Result<Vec<usize>> {
+        if !backend.is_memory() && !backend.exists(log_path) {
+            return Ok(Vec::new());
+        }
+
+        let mut ids = backend
+            .list(log_path)?
+            .iter()
+            .filter_map(|path| {
+                path.file_name()?
+                    .to_str()?
+                    .strip_prefix("DustDataLog_")?
+                    .parse()
+                    .ok()
+            })
+            .collect::<Vec<usize>>();
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+}
+
+/// Batches the appends of concurrent commits, see `WALConfig::group_commit`.
+/// Commits queue their records while holding the write-ahead log lock, and the first one
+/// to wait after releasing it becomes the leader that writes and syncs everything queued.
+pub struct GroupCommit {
+    log: Mutex<fs::File>, // append handle of the current log chunk
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+struct GroupState {
+    pending: Vec<u8>,
+    index: Option<Arc<fs::File>>, // the index file the pending records were written to
+    end: u64,                     // offset right after the last queued record
+    queued: usize,
+    synced: usize,
+    writing: bool, // whether a leader is writing a batch
+    failed: Option<String>,
+}
+
+impl GroupCommit {
+    fn open(path: &path::Path) -> Result<Self> {
+        let log = fs::OpenOptions::new().append(true).open(path)?;
+        let end = log.metadata()?.len();
+
+        Ok(Self {
+            log: Mutex::new(log),
+            state: Mutex::new(GroupState {
+                pending: Vec::new(),
+                index: None,
+                end,
+                queued: 0,
+                synced: 0,
+                writing: false,
+                failed: None,
+            }),
+            synced: Condvar::new(),
+        })
+    }
+
+    /// Appends the next records to the chunk at `path`. Only call it once every queued
+    /// record is synced.
+    fn switch(&self, path: &path::Path) -> Result<()> {
+        let log = fs::OpenOptions::new().append(true).open(path)?;
+
+        self.state.lock().end = log.metadata()?.len();
+        *self.log.lock() = log;
+
+        Ok(())
+    }
+
+    /// The offset the next queued record is written at.
+    fn end(&self) -> u64 {
+        self.state.lock().end
+    }
+
+    fn queue(&self, bytes: &[u8], index: Option<Arc<fs::File>>) {
+        let mut state = self.state.lock();
+
+        state.pending.extend_from_slice(bytes);
+        state.index = index;
+        state.end += bytes.len() as u64;
+        state.queued += 1;
+    }
+
+    /// Waits until every record queued so far is synced, writing them if no other commit is.
+    /// Returns the number of records synced, which can include ones queued after the wait began.
+    /// Once a batch fails to be written the log may be torn, so every later wait fails too.
+    pub fn wait(&self) -> Result<usize> {
+        let mut state = self.state.lock();
+        let target = state.queued;
 
         loop {
-            let file_path = log_path.join(format!("DustDataLog_{}", id));
+            if let Some(err) = &state.failed {
+                return Err(Error::Other(format!(
+                    "Failed to write the wal log: {}",
+                    err
+                )));
+            }
 
-            if !file_path.exists() {
-                break;
+            if state.synced >= target {
+                return Ok(state.synced);
             }
 
-            let metadata = fs::metadata(file_path).unwrap();
+            if state.writing {
+                self.synced.wait(&mut state);
+                continue;
+            }
+
+            state.writing = true;
+
+            let batch = std::mem::take(&mut state.pending);
+            let index = state.index.take();
+            let queued = state.queued;
+
+            let result = MutexGuard::unlocked(&mut state, || self.write(&batch, index.as_deref()));
+
+            state.writing = false;
 
-            if metadata.len() < max_log_size {
-                break;
+            match result {
+                Ok(()) => state.synced = queued,
+                Err(err) => state.failed = Some(err.to_string()),
             }
 
-            id += 1;
+            self.synced.notify_all();
         }
+    }
+
+    fn write(&self, batch: &[u8], index: Option<&fs::File>) -> io::Result<()> {
+        let mut log = self.log.lock();
+
+        log.write_all(batch)?;
+        log.sync_data()?;
 
-        id
+        if let Some(index) = index {
+            index.sync_data()?;
+        }
+
+        Ok(())
     }
 }
 
 pub struct Wal {
-    config: config::DustDataConfig,
+    backend: Backend,
+    log_path: path::PathBuf,
     current_file: LogFile,
+    group_commit: Option<Arc<GroupCommit>>,
+    max_log_size: u64,
+    max_retained_logs: Option<usize>, // None when read-only
+    last_tx_id: Option<usize>,        // the greatest id logged, kept when its chunk is pruned
     pub index: WALIndex,
 }
 
 impl Wal {
     pub fn new(config: config::DustDataConfig) -> Result<Self> {
         let log_path = config.data_path.join(&config.wal.log_path);
+        let mut backend = Backend::new(config.backend, config.sync_dir_on_create);
+        let read_only = config.lock_mode == config::LockMode::ReadOnly;
 
-        fs::create_dir_all(&log_path).ok();
+        if !read_only {
+            backend.create_dir_all(&log_path).ok();
+        }
 
-        let current_file = LogFile::new(&log_path, config.wal.max_log_size);
+        let current_file =
+            LogFile::new(&mut backend, &log_path, config.wal.max_log_size, read_only)?;
 
         let index = WALIndex::new(
-            &log_path,
-            config.wal.compression.is_some(),
-            config.wal.compression.as_ref().map(|c| c.level),
+            (!backend.is_memory()).then_some(log_path.as_path()),
+            config.wal.compression.clone(),
+            read_only,
         )?;
 
+        let group_commit = (config.wal.group_commit && !backend.is_memory() && !read_only)
+            .then(|| GroupCommit::open(&current_file.path).map(Arc::new))
+            .transpose()?;
+
         Ok(Self {
-            config,
+            backend,
+            log_path,
             current_file,
+            group_commit,
+            max_log_size: config.wal.max_log_size,
+            max_retained_logs: config.wal.max_retained_logs.filter(|_| !read_only),
+            last_tx_id: index.get_head(),
             index,
         })
     }
 
-    pub fn revert<T>(&mut self, tx_id: usize) -> Result<Transaction<T>>
+    pub fn revert<T>(&self, tx_id: usize) -> Result<Transaction<T>>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
@@ -136,16 +309,147 @@ impl Wal {
         Ok(transaction)
     }
 
-    pub fn write<T>(&mut self, transaction: TransactionLog<T>)
+    /// The id to log a transaction under: `requested`, or the id after the last logged one
+    /// if `requested` isn't greater. Ids stay unique and in commit order even when
+    /// transactions start in the same microsecond or the clock goes back.
+    pub fn next_tx_id(&self, requested: usize) -> usize {
+        match self.last_tx_id {
+            Some(last) if requested <= last => last + 1,
+            _ => requested,
+        }
+    }
+
+    pub fn write<T>(&mut self, transaction: &TransactionLog<T>) -> Result<()>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        let offset = self.current_file.file.metadata().unwrap().len() as usize;
-        let bytes = Self::serialize_value(&transaction);
+        self.last_tx_id = self.last_tx_id.max(Some(transaction.id));
+
+        let bytes = Self::serialize_value(transaction);
+
+        // The record is only queued, the commit waits for it with `GroupCommit::wait`
+        // once the write-ahead log lock is released.
+        if let Some(group_commit) = self.group_commit.clone() {
+            if group_commit.end() >= self.max_log_size {
+                self.rotate()?;
+            }
+
+            let offset = group_commit.end() as usize;
+
+            self.index
+                .write(transaction.id, self.current_file.id, offset)?;
+            group_commit.queue(&bytes, self.index.file());
+
+            return Ok(());
+        }
+
+        if self.backend.len(&self.current_file.path)? >= self.max_log_size {
+            self.rotate()?;
+        }
+
+        let offset = self.backend.len(&self.current_file.path)? as usize;
 
         self.index
-            .write(transaction.id, self.current_file.id, offset);
-        self.current_file.file.write_all(&bytes).unwrap();
+            .write(transaction.id, self.current_file.id, offset)?;
+        self.backend.append(&self.current_file.path, &bytes)?;
+
+        Ok(())
+    }
+
+    /// Moves on to a new log chunk once the current one reaches `max_log_size`, syncing the
+    /// records written to the current one first.
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+
+        let id = self.current_file.id + 1;
+        let path = self.log_path.join(format!("DustDataLog_{}", id));
+
+        self.backend.touch(&path)?;
+
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.switch(&path)?;
+        }
+
+        self.current_file = LogFile { id, path };
+
+        Ok(())
+    }
+
+    /// The number of records queued for group commit so far, 0 without group commit.
+    pub fn queued(&self) -> usize {
+        self.group_commit
+            .as_ref()
+            .map_or(0, |group_commit| group_commit.state.lock().queued)
+    }
+
+    /// The directory of the log chunks and the index.
+    pub fn log_path(&self) -> &path::Path {
+        &self.log_path
+    }
+
+    /// Returns the batcher of the commits' appends, or `None` without group commit.
+    pub fn group_commit(&self) -> Option<Arc<GroupCommit>> {
+        self.group_commit.clone()
+    }
+
+    /// Writes the queued records, so they can be read back.
+    fn write_queued(&self) -> Result<()> {
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.wait()?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the current log chunk and the index to stable storage.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_queued()?;
+        self.backend.sync(&self.current_file.path)?;
+        self.index.sync()?;
+
+        Ok(())
+    }
+
+    /// Counts the `DustDataLog_*` chunks in the log directory.
+    pub fn log_chunks(&self) -> Result<usize> {
+        Ok(LogFile::chunk_ids(&self.backend, &self.log_path)?.len())
+    }
+
+    /// Removes the oldest log chunks past `max_retained_logs`, and their transactions from
+    /// the index. Only call it once the storage is flushed, so every pruned transaction is
+    /// persisted in the data chunks. Returns the number of chunks removed.
+    pub fn prune(&mut self) -> Result<usize> {
+        let Some(max_retained_logs) = self.max_retained_logs else {
+            return Ok(0);
+        };
+
+        let older = LogFile::chunk_ids(&self.backend, &self.log_path)?
+            .into_iter()
+            .filter(|id| *id != self.current_file.id)
+            .collect::<Vec<_>>();
+        let pruned = &older[..(older.len() + 1).saturating_sub(max_retained_logs)];
+
+        if pruned.is_empty() {
+            return Ok(0);
+        }
+
+        // The index is persisted first, so a crash can leave unreferenced chunks behind
+        // but never an index pointing at removed ones.
+        self.index.remove_log_chunks(pruned)?;
+
+        for id in pruned {
+            self.backend
+                .remove(&self.log_path.join(format!("DustDataLog_{}", id)))?;
+        }
+
+        Ok(pruned.len())
+    }
+
+    /// Whether the oldest log chunks were pruned, so the log no longer holds every transaction.
+    pub fn is_pruned(&self) -> Result<bool> {
+        Ok(LogFile::chunk_ids(&self.backend, &self.log_path)?
+            .first()
+            .is_some_and(|id| *id > 0))
     }
 
     pub fn read<T>(&self, tx_id: usize) -> Result<Option<TransactionLog<T>>>
@@ -171,19 +475,20 @@ impl Wal {
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
+        self.write_queued()?;
+
         let filename = format!("DustDataLog_{}", log_chunk);
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .open(self.config.data_path.join("log").join(&filename))
+        let mut file = self
+            .backend
+            .reader(&self.log_path.join(&filename))
             .map_err(|r| match r.kind() {
-                std::io::ErrorKind::NotFound => Error::CorruptedData(format!(
-                    "WAL Log {} not found, but wal index contains it",
-                    filename
-                )),
+                std::io::ErrorKind::NotFound => Error::MissingWalChunk {
+                    chunk: filename.clone(),
+                },
                 _ => Error::IoError(r),
             })?;
 
-        Self::deserialize_value(&mut file, offset, &filename)
+        Self::deserialize_value(&mut *file, offset, &filename)
     }
 
     fn serialize_value<T>(value: &T) -> Vec<u8>
@@ -201,22 +506,34 @@ impl Wal {
     }
 
     fn deserialize_value<T>(
-        file: &mut fs::File,
+        file: &mut dyn ReadSeek,
         offset: usize,
         filename: &str,
     ) -> Result<Option<TransactionLog<T>>>
     where
         T: Sync + Send + Clone + Debug + Serialize + 'static + DeserializeOwned,
     {
-        file.seek(SeekFrom::Start(offset as u64))
-            .map_err(Error::IoError)?;
+        // A record cut short by a crash during the append reads as corrupted data.
+        let truncated = || {
+            Error::CorruptedData(format!(
+                "Truncated wal log {} at offset {}",
+                filename, offset
+            ))
+        };
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(offset as u64))?;
 
         let mut length = [0; 8];
-        file.read_exact(&mut length).unwrap();
-        let length = u64::from_le_bytes(length) as usize;
+        file.read_exact(&mut length).map_err(|_| truncated())?;
+        let length = u64::from_le_bytes(length);
+
+        if length > file_len.saturating_sub(offset as u64 + 8) {
+            return Err(truncated());
+        }
 
-        let mut value = vec![0; length];
-        file.read_exact(&mut value).unwrap();
+        let mut value = vec![0; length as usize];
+        file.read_exact(&mut value).map_err(|_| truncated())?;
 
         let value = bincode::deserialize(&value).map_err(|e| {
             Error::CorruptedData(format!(
@@ -230,92 +547,211 @@ impl Wal {
 }
 
 const WAL_INDEX_FILENAME: &str = ".wal-index-dustdata";
-
-#[derive(Serialize, Deserialize)]
-struct WALIndexEntry<T> {
-    tx_id: usize,
-    data: Vec<WalOperation<T>>,
-}
-
+const WAL_INDEX_MAGIC: &[u8; 4] = b"DWI2";
+const WAL_INDEX_V1_MAGIC: &[u8; 4] = b"DWI1";
+const WAL_INDEX_RECORD_SIZE: usize = 24;
+const WAL_INDEX_MIN_COMPACTION_RECORDS: usize = 1024;
+
+/// The WAL index is persisted as a compressed snapshot of `(tx_id, log_chunk, offset)`
+/// records followed by an append-only log of the records written since.
+/// The file starts with a magic and the snapshot length as u64 LE. On open the records
+/// are replayed to rebuild the map, and the file is compacted into a new snapshot once
+/// it holds too many superseded records.
 pub struct WALIndex {
     index: BTreeMap<usize, (usize, usize)>, // tx_id -> (DustDataLog_*, offset)
     index_path: path::PathBuf,
-    use_compression: bool,
-    compression_lvl: Option<u32>,
+    file: Option<Arc<fs::File>>, // None when the index isn't persisted
+    records: usize,
+    compression: Option<config::CompressionConfig>,
 }
 
 impl WALIndex {
+    /// Loads the index, a read-only index is never written to.
     pub fn new(
-        path: &path::Path,
-        use_compression: bool,
-        compression_lvl: Option<u32>,
+        path: Option<&path::Path>,
+        compression: Option<config::CompressionConfig>,
+        read_only: bool,
     ) -> Result<Self> {
-        let index_path = path.join(WAL_INDEX_FILENAME);
-
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(index_path.clone())
-            .map_err(Error::IoError)?;
-
-        let index = if file.metadata().unwrap().len() == 0 {
-            let index = BTreeMap::new();
-
-            let bytes = if use_compression {
-                let mut encoder =
-                    GzEncoder::new(Vec::new(), Compression::new(compression_lvl.unwrap()));
-                encoder
-                    .write_all(&bincode::serialize(&index).unwrap())
-                    .unwrap();
-                encoder.finish().unwrap()
-            } else {
-                bincode::serialize(&index).unwrap()
-            };
-
-            file.write_all(&bytes).map_err(Error::IoError)?;
+        let mut wal_index = Self {
+            index: BTreeMap::new(),
+            index_path: path::PathBuf::new(),
+            file: None,
+            records: 0,
+            compression,
+        };
 
-            index
-        } else {
-            let mut bytes = Vec::new();
+        let Some(path) = path else {
+            return Ok(wal_index);
+        };
 
-            file.read_to_end(&mut bytes).map_err(Error::IoError)?;
+        wal_index.index_path = path.join(WAL_INDEX_FILENAME);
 
-            let mut decoder = GzDecoder::new(&bytes[..]);
+        let mut bytes = Vec::new();
 
-            if decoder.header().is_some() {
-                let mut decoded_bytes = Vec::new();
-                decoder.read_to_end(&mut decoded_bytes).unwrap();
+        if wal_index.index_path.exists() {
+            fs::File::open(&wal_index.index_path)?.read_to_end(&mut bytes)?;
+        }
 
-                bincode::deserialize(&decoded_bytes).unwrap()
-            } else {
-                bincode::deserialize(&bytes).unwrap()
-            }
+        let rewrite = if let Some(bytes) = bytes.strip_prefix(WAL_INDEX_MAGIC) {
+            let corrupted = || Error::CorruptedData("Corrupted wal index snapshot".to_string());
+
+            let (snapshot_len, bytes) = bytes.split_at_checked(8).ok_or_else(corrupted)?;
+            let snapshot_len = u64::from_le_bytes(snapshot_len.try_into().unwrap()) as usize;
+            let (snapshot, tail) = bytes.split_at_checked(snapshot_len).ok_or_else(corrupted)?;
+
+            let (index, _) = Self::replay(&codec::decode(snapshot.to_vec())?);
+            wal_index.index = index;
+            wal_index.records = wal_index.index.len();
+
+            let (index, records) = Self::replay(tail);
+            wal_index.index.extend(index);
+            wal_index.records += records;
+
+            // Drops a torn record so new appends stay aligned.
+            tail.len() % WAL_INDEX_RECORD_SIZE != 0
+        } else if let Some(records) = bytes.strip_prefix(WAL_INDEX_V1_MAGIC) {
+            // Uncompressed append-only records without a snapshot.
+            wal_index.index = Self::replay(records).0;
+            true
+        } else if bytes.starts_with(&WAL_INDEX_MAGIC[..3]) {
+            return Err(Error::VersionMismatch(format!(
+                "The wal index {} was written by a newer format",
+                wal_index.index_path.display()
+            )));
+        } else if !bytes.is_empty() {
+            // Indexes written before the append-only format hold the whole map.
+            wal_index.index = Self::decode_legacy(bytes)?;
+            true
+        } else {
+            true
         };
 
-        Ok(Self {
-            index,
-            index_path,
-            use_compression,
-            compression_lvl,
-        })
+        if read_only {
+            return Ok(wal_index);
+        }
+
+        if rewrite {
+            wal_index.write_snapshot()?;
+        }
+
+        wal_index.file = Some(Arc::new(
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&wal_index.index_path)?,
+        ));
+
+        Ok(wal_index)
     }
 
-    pub fn write(&mut self, id: usize, log_chunk: usize, offset: usize) {
+    pub fn write(&mut self, id: usize, log_chunk: usize, offset: usize) -> Result<()> {
+        // Written first, so a failed write leaves no record of the transaction in memory.
+        if let Some(file) = &self.file {
+            (&**file).write_all(&Self::encode_record(id, log_chunk, offset))?;
+            self.records += 1;
+        }
+
         self.index.insert(id, (log_chunk, offset));
 
-        let bytes = bincode::serialize(&self.index).unwrap();
+        if self.records >= WAL_INDEX_MIN_COMPACTION_RECORDS && self.records > self.index.len() * 2 {
+            self.compact()?;
+        }
 
-        let bytes = if self.use_compression {
-            let mut encoder =
-                GzEncoder::new(Vec::new(), Compression::new(self.compression_lvl.unwrap()));
-            encoder.write_all(&bytes).unwrap();
-            encoder.finish().unwrap()
-        } else {
-            bytes
-        };
+        Ok(())
+    }
+
+    /// The file new records are appended to, shared so group commit can sync it.
+    fn file(&self) -> Option<Arc<fs::File>> {
+        self.file.clone()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes a transaction, rewriting the index file if it was logged.
+    pub fn remove(&mut self, tx_id: usize) -> Result<()> {
+        if self.index.remove(&tx_id).is_none() {
+            return Ok(());
+        }
+
+        self.compact()
+    }
+
+    /// Removes the transactions logged in the given chunks, rewriting the index file.
+    pub fn remove_log_chunks(&mut self, log_chunks: &[usize]) -> Result<()> {
+        self.index
+            .retain(|_, (log_chunk, _)| !log_chunks.contains(log_chunk));
+
+        self.compact()
+    }
+
+    /// Rewrites the index file so it only holds the live records.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        self.write_snapshot()?;
+        self.file = Some(Arc::new(
+            fs::OpenOptions::new().append(true).open(&self.index_path)?,
+        ));
+
+        Ok(())
+    }
+
+    fn write_snapshot(&mut self) -> Result<()> {
+        let mut records = Vec::with_capacity(self.index.len() * WAL_INDEX_RECORD_SIZE);
+
+        for (id, (log_chunk, offset)) in &self.index {
+            records.extend_from_slice(&Self::encode_record(*id, *log_chunk, *offset));
+        }
+
+        let snapshot = codec::encode(&records, self.compression.as_ref())?;
+
+        let mut bytes = WAL_INDEX_MAGIC.to_vec();
+        bytes.extend_from_slice(&(snapshot.len() as u64).to_le_bytes());
+        bytes.extend(snapshot);
+
+        super::write_file_atomically(&self.index_path, &bytes)?;
+        self.records = self.index.len();
+
+        Ok(())
+    }
+
+    fn replay(bytes: &[u8]) -> (BTreeMap<usize, (usize, usize)>, usize) {
+        let mut index = BTreeMap::new();
+        let mut records = 0;
+
+        for record in bytes.chunks_exact(WAL_INDEX_RECORD_SIZE) {
+            let field = |i: usize| {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&record[i * 8..(i + 1) * 8]);
+                u64::from_le_bytes(buf) as usize
+            };
+
+            index.insert(field(0), (field(1), field(2)));
+            records += 1;
+        }
+
+        (index, records)
+    }
+
+    fn encode_record(id: usize, log_chunk: usize, offset: usize) -> [u8; WAL_INDEX_RECORD_SIZE] {
+        let mut record = [0; WAL_INDEX_RECORD_SIZE];
+        record[..8].copy_from_slice(&(id as u64).to_le_bytes());
+        record[8..16].copy_from_slice(&(log_chunk as u64).to_le_bytes());
+        record[16..].copy_from_slice(&(offset as u64).to_le_bytes());
+
+        record
+    }
 
-        fs::write(&self.index_path, bytes).unwrap();
+    fn decode_legacy(bytes: Vec<u8>) -> Result<BTreeMap<usize, (usize, usize)>> {
+        bincode::deserialize(&codec::decode(bytes)?)
+            .map_err(|e| Error::CorruptedData(format!("Corrupted wal index. Error: {}", e)))
     }
 
     pub fn get_head(&self) -> Option<usize> {
@@ -1,22 +1,140 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::ops::{Add, Sub};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DustDataConfig {
     pub wal: WALConfig,
     pub data_path: PathBuf,
     pub storage: StorageConfig,
+    pub backend: StorageBackend,
+    pub compaction: Option<CompactionConfig>,
+    pub lock_timeout: Duration,
+    pub lock_mode: LockMode,
+    pub max_transaction_ops: Option<usize>,
+    pub key_constraints: KeyConstraints,
+    pub sync_dir_on_create: bool,
+    pub snapshot_compression: CompressionConfig,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockMode {
+    /// Fails with `Error::DatabaseLocked` if another instance holds the lock.
+    FailFast,
+    /// Waits up to the duration for another instance to release the lock.
+    Wait(Duration),
+    /// Shares the lock with other read-only instances and rejects writes.
+    /// Fails with `Error::DatabaseLocked` while a writing instance holds the lock.
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageBackend {
+    /// Data chunks, logs and indexes are persisted under the data directory.
+    Disk,
+    /// Everything is kept in memory and lost when the instance is dropped.
+    Memory,
+}
+
+/// Which memtable entries are evicted once it grows past `StorageConfig::flush_threshold`.
+/// Every memtable entry is already written to the data chunks, so an evicted value is
+/// only read back from disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemtableEviction {
+    /// Evicts the least recently read or written entries, for read-skewed workloads.
+    Lru,
+    /// Evicts the oldest written entries, for write-heavy workloads.
+    Fifo,
+    /// Never evicts, every value stays in memory whatever the threshold.
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WALConfig {
     pub log_path: PathBuf,
     pub max_log_size: u64,
     pub compression: Option<CompressionConfig>,
+    pub group_commit: bool,
+    pub max_retained_logs: Option<usize>,
+    #[serde(skip)]
+    oversized: Option<&'static str>, // the first size setting that didn't fit, see `validate`
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyConstraints {
+    pub max_len: Option<usize>,
+    pub allow_empty: bool,
 }
 
-#[derive(Debug, Clone)]
+impl Default for KeyConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyConstraints {
+    pub fn new() -> Self {
+        Self {
+            max_len: Some(1024),
+            allow_empty: false,
+        }
+    }
+
+    /// The maximum length of a key in bytes, or None for no limit.
+    /// Default: Some(1024)
+    pub fn max_len(&mut self, max_len: Option<usize>) -> &mut Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Whether the empty key is accepted.
+    /// Default: false
+    pub fn allow_empty(&mut self, allow_empty: bool) -> &mut Self {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    /// Returns `Error::Other` if the key breaks a constraint.
+    pub fn check(&self, key: &str) -> Result<()> {
+        if key.is_empty() && !self.allow_empty {
+            return Err(Error::Other("Invalid key: keys can't be empty".to_string()));
+        }
+
+        if let Some(max_len) = self.max_len {
+            if key.len() > max_len {
+                return Err(Error::Other(format!(
+                    "Invalid key: {} bytes is longer than the limit of {} bytes",
+                    key.len(),
+                    max_len
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CompressionConfig {
     pub level: u32,
+    pub algorithm: CompressionAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgorithm {
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
 }
 
 impl Default for CompressionConfig {
@@ -27,15 +145,26 @@ impl Default for CompressionConfig {
 
 impl CompressionConfig {
     pub fn new() -> Self {
-        Self { level: 6 }
+        Self {
+            level: 6,
+            algorithm: CompressionAlgorithm::Gzip,
+        }
     }
 
     /// The compression level.
     /// Default: 6
+    /// Lz4 doesn't use levels.
     pub fn level(&mut self, level: u32) -> &mut Self {
         self.level = level;
         self
     }
+
+    /// The compression algorithm.
+    /// Default: CompressionAlgorithm::Gzip
+    pub fn algorithm(&mut self, algorithm: CompressionAlgorithm) -> &mut Self {
+        self.algorithm = algorithm;
+        self
+    }
 }
 
 impl Default for DustDataConfig {
@@ -50,6 +179,14 @@ impl DustDataConfig {
             wal: WALConfig::new(),
             data_path: PathBuf::from("./data"),
             storage: StorageConfig::new(),
+            backend: StorageBackend::Disk,
+            compaction: None,
+            lock_timeout: Duration::from_secs(5),
+            lock_mode: LockMode::FailFast,
+            max_transaction_ops: None,
+            key_constraints: KeyConstraints::new(),
+            sync_dir_on_create: true,
+            snapshot_compression: CompressionConfig::new(),
         }
     }
 
@@ -80,16 +217,197 @@ impl DustDataConfig {
         self
     }
 
+    /// Where the collections keep their data.
+    /// Default: StorageBackend::Disk
+    pub fn backend(&mut self, backend: StorageBackend) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Enables background compaction of the data chunks.
+    /// Default: None
+    pub fn compaction<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&mut CompactionConfig) -> &mut CompactionConfig,
+    {
+        self.compaction = Some(f(&mut CompactionConfig::new()).clone());
+        self
+    }
+
+    /// How long a commit waits for a collection held by another commit before
+    /// failing with `Error::Deadlock`.
+    /// Default: 5 seconds
+    pub fn lock_timeout(&mut self, lock_timeout: Duration) -> &mut Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// What opening the data directory does when another instance holds its lock.
+    /// Default: LockMode::FailFast
+    pub fn lock_mode(&mut self, lock_mode: LockMode) -> &mut Self {
+        self.lock_mode = lock_mode;
+        self
+    }
+
+    /// The most operations a transaction can hold, larger transactions are rejected
+    /// with `Error::TransactionTooLarge`.
+    /// Default: None, no limit
+    pub fn max_transaction_ops(&mut self, max_transaction_ops: usize) -> &mut Self {
+        self.max_transaction_ops = Some(max_transaction_ops);
+        self
+    }
+
+    /// The keys accepted by commits.
+    /// Default: KeyConstraints::new()
+    pub fn key_constraints<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&mut KeyConstraints) -> &mut KeyConstraints,
+    {
+        self.key_constraints = f(&mut self.key_constraints).clone();
+        self
+    }
+
+    /// Whether creating a data chunk or log chunk also syncs its directory, so the new
+    /// file isn't lost in a crash right after it's created. Only has an effect on Unix.
+    /// Default: true
+    pub fn sync_dir_on_create(&mut self, sync_dir_on_create: bool) -> &mut Self {
+        self.sync_dir_on_create = sync_dir_on_create;
+        self
+    }
+
+    /// The compression of the files written by `Collection::snapshot`. Snapshots record
+    /// their algorithm, so they can be restored whatever this is set to later.
+    /// Default: CompressionConfig::new()
+    pub fn snapshot_compression<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&mut CompressionConfig) -> &mut CompressionConfig,
+    {
+        self.snapshot_compression = f(&mut CompressionConfig::new()).clone();
+        self
+    }
+
     pub fn build(&self) -> Self {
         self.clone()
     }
+
+    /// Checks the configuration for values that can't work.
+    /// `DustData::new` validates its configuration, so this only needs to be called to
+    /// catch errors earlier.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::Other(format!("Invalid config: {}", message)));
+
+        if self.storage.max_data_chunks == 0 {
+            return invalid("storage.max_data_chunks must be greater than 0");
+        }
+
+        if self.storage.max_data_chunk_size == 0 {
+            return invalid("storage.max_data_chunk_size must be greater than 0");
+        }
+
+        if !(self.storage.bloom_fp_rate > 0.0 && self.storage.bloom_fp_rate < 1.0) {
+            return invalid("storage.bloom_fp_rate must be between 0 and 1 exclusive");
+        }
+
+        // Blob files are outside the data chunks, so the quota couldn't cap them.
+        if self.storage.max_collection_bytes.is_some() && self.storage.blob_threshold.is_some() {
+            return invalid(
+                "storage.max_collection_bytes can't be combined with storage.blob_threshold",
+            );
+        }
+
+        if self.storage.prefix_filter_depth == Some(0) {
+            return invalid("storage.prefix_filter_depth must be greater than 0");
+        }
+
+        if self.max_transaction_ops == Some(0) {
+            return invalid("max_transaction_ops must be greater than 0");
+        }
+
+        if self.wal.max_log_size == 0 {
+            return invalid("wal.max_log_size must be greater than 0");
+        }
+
+        if self.wal.max_retained_logs == Some(0) {
+            return invalid("wal.max_retained_logs must be greater than 0");
+        }
+
+        // The setters can't fail, so they remember a size that didn't fit instead.
+        if let Some(name) = self.storage.oversized.or(self.wal.oversized) {
+            return invalid(&format!("{} doesn't fit in 64 bits", name));
+        }
+
+        let compressions = [
+            ("storage.compression", self.storage.compression.as_ref()),
+            (
+                "storage.value_compression",
+                self.storage.value_compression.as_ref(),
+            ),
+            ("wal.compression", self.wal.compression.as_ref()),
+            ("snapshot_compression", Some(&self.snapshot_compression)),
+        ];
+
+        for (name, compression) in compressions {
+            let Some(compression) = compression else {
+                continue;
+            };
+
+            let max_level = match compression.algorithm {
+                CompressionAlgorithm::Gzip => 9,
+                CompressionAlgorithm::Zstd => 22,
+                CompressionAlgorithm::Lz4 | CompressionAlgorithm::None => continue,
+            };
+
+            if compression.level > max_level {
+                return invalid(&format!(
+                    "{}.level {} is out of range for {:?}, which supports 0 to {}",
+                    name, compression.level, compression.algorithm, max_level
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads a configuration from a TOML file.
+    /// Missing fields keep their defaults, so a file can only set `data_path`, the `[wal]`
+    /// block or the `[storage]` block.
+    #[cfg(feature = "toml")]
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path.as_ref())?;
+
+        let config: Self = toml::from_str(&content).map_err(|e| {
+            Error::Other(format!(
+                "Invalid config file {}. Error: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct StorageConfig {
     pub max_data_chunk_size: usize,
     pub max_data_chunks: usize,
     pub compression: Option<CompressionConfig>,
+    pub value_compression: Option<CompressionConfig>,
+    pub min_compress_size: usize,
+    pub flush_threshold: Option<usize>,
+    pub memtable_eviction: MemtableEviction,
+    pub warm_start: Option<usize>,
+    pub auto_key_width: usize,
+    pub blob_threshold: Option<usize>,
+    pub bloom_fp_rate: f64,
+    pub bloom_capacity: usize,
+    pub max_collection_bytes: Option<usize>,
+    pub prefix_filter_depth: Option<usize>,
+    #[serde(skip)]
+    oversized: Option<&'static str>, // the first size setting that didn't fit, see `validate`
 }
 
 impl Default for StorageConfig {
@@ -101,16 +419,52 @@ impl Default for StorageConfig {
 impl StorageConfig {
     pub fn new() -> Self {
         Self {
-            max_data_chunk_size: 10 * 1028 * 1028, // 10MB
+            max_data_chunk_size: Size::Megabytes(10).to_bytes() as usize,
             max_data_chunks: 10,
             compression: None,
+            value_compression: None,
+            min_compress_size: 256,
+            flush_threshold: None,
+            memtable_eviction: MemtableEviction::Fifo,
+            warm_start: None,
+            auto_key_width: 20,
+            blob_threshold: None,
+            bloom_fp_rate: 0.01,
+            bloom_capacity: 8,
+            max_collection_bytes: None,
+            prefix_filter_depth: None,
+            oversized: None,
         }
     }
 
-    /// The maximum size of a data chunk.
+    /// The bytes of a size setting, remembering the setting if they don't fit in a `usize`.
+    fn size_bytes(&mut self, name: &'static str, size: Size) -> usize {
+        let bytes = size
+            .checked_to_bytes()
+            .and_then(|bytes| usize::try_from(bytes).ok());
+
+        bytes.unwrap_or_else(|| {
+            self.oversized.get_or_insert(name);
+            usize::MAX
+        })
+    }
+
+    /// The maximum size of a data chunk, in bytes or as a `Size` like `"10MB".parse()?`.
     /// Default: 10MB
-    pub fn max_data_chunk_size(&mut self, max_data_chunk_size: usize) -> &mut Self {
-        self.max_data_chunk_size = max_data_chunk_size;
+    pub fn max_data_chunk_size<S: Into<Size>>(&mut self, max_data_chunk_size: S) -> &mut Self {
+        self.max_data_chunk_size =
+            self.size_bytes("storage.max_data_chunk_size", max_data_chunk_size.into());
+        self
+    }
+
+    /// The maximum size of the data chunks of a collection, in bytes or as a `Size`.
+    /// Inserts and updates that would grow the data chunks past it return
+    /// `Error::QuotaExceeded`. Deletes are always accepted, and the space they free counts
+    /// once compaction reclaims it. Can't be combined with `blob_threshold`.
+    /// Default: None (no limit)
+    pub fn max_collection_bytes<S: Into<Size>>(&mut self, max_collection_bytes: S) -> &mut Self {
+        self.max_collection_bytes =
+            Some(self.size_bytes("storage.max_collection_bytes", max_collection_bytes.into()));
         self
     }
 
@@ -121,7 +475,31 @@ impl StorageConfig {
         self
     }
 
-    /// The compression configuration for the data chunks and indexes.
+    /// The estimated size of the values kept in memory before some are evicted, see
+    /// `memtable_eviction`. Evicted values are read back from the data chunks.
+    /// Default: None (every value is kept in memory)
+    pub fn flush_threshold(&mut self, flush_threshold: usize) -> &mut Self {
+        self.flush_threshold = Some(flush_threshold);
+        self
+    }
+
+    /// Which values are evicted from memory once they pass the `flush_threshold`.
+    /// Default: `MemtableEviction::Fifo`
+    pub fn memtable_eviction(&mut self, memtable_eviction: MemtableEviction) -> &mut Self {
+        self.memtable_eviction = memtable_eviction;
+        self
+    }
+
+    /// Loads the most recently written values into memory when a collection is opened,
+    /// up to this many bytes of data segments, so the first reads of hot data skip the disk.
+    /// The budget bounds the work done on open, whatever the size of the collection.
+    /// Default: None (the memtable starts empty)
+    pub fn warm_start<S: Into<Size>>(&mut self, budget: S) -> &mut Self {
+        self.warm_start = Some(self.size_bytes("storage.warm_start", budget.into()));
+        self
+    }
+
+    /// The compression configuration for the index.
     /// Default: None
     pub fn compression<F>(&mut self, f: F) -> &mut Self
     where
@@ -130,6 +508,103 @@ impl StorageConfig {
         self.compression = Some(f(&mut CompressionConfig::new()).clone());
         self
     }
+
+    /// The compression configuration for the values stored in the data chunks.
+    /// Default: None
+    pub fn value_compression<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&mut CompressionConfig) -> &mut CompressionConfig,
+    {
+        self.value_compression = Some(f(&mut CompressionConfig::new()).clone());
+        self
+    }
+
+    /// The serialized size from which values are compressed, smaller values are stored raw.
+    /// Only used with `value_compression`.
+    /// Default: 256 bytes
+    pub fn min_compress_size(&mut self, min_compress_size: usize) -> &mut Self {
+        self.min_compress_size = min_compress_size;
+        self
+    }
+
+    /// The serialized size above which values are written to their own file under
+    /// `data/blobs`, leaving only a reference in the data chunk. Blobs that are no longer
+    /// referenced are removed by compaction. Can't be combined with `max_collection_bytes`.
+    /// Default: None (every value is stored in the data chunks)
+    pub fn blob_threshold(&mut self, blob_threshold: usize) -> &mut Self {
+        self.blob_threshold = Some(blob_threshold);
+        self
+    }
+
+    /// The false-positive rate of the bloom filter in front of the index, between 0 and 1
+    /// exclusive. A lower rate saves index lookups for missing keys but takes more memory.
+    /// Default: 0.01
+    pub fn bloom_fp_rate(&mut self, bloom_fp_rate: f64) -> &mut Self {
+        self.bloom_fp_rate = bloom_fp_rate;
+        self
+    }
+
+    /// The number of keys the bloom filter is sized for when the collection is opened.
+    /// The filter is sized for 8 times the stored keys instead if that's more.
+    /// Default: 8
+    pub fn bloom_capacity(&mut self, bloom_capacity: usize) -> &mut Self {
+        self.bloom_capacity = bloom_capacity;
+        self
+    }
+
+    /// Keeps a second bloom filter holding the first 1 to `depth` characters of every key,
+    /// which `Collection::prefix_exists` checks. Each insert then sets the bits of up to
+    /// `depth` more entries, and the filter takes up to `depth` times the memory of the
+    /// key filter.
+    /// Default: None (no prefix filter)
+    pub fn prefix_filter_depth(&mut self, depth: usize) -> &mut Self {
+        self.prefix_filter_depth = Some(depth);
+        self
+    }
+
+    /// The number of digits of the keys generated by `Collection::insert_auto`, zero-padded
+    /// so they sort like the numbers they hold.
+    /// Default: 20, enough for every `u64`
+    pub fn auto_key_width(&mut self, auto_key_width: usize) -> &mut Self {
+        self.auto_key_width = auto_key_width;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    pub interval: Duration,
+    pub dead_ratio_threshold: f64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompactionConfig {
+    pub fn new() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            dead_ratio_threshold: 0.5,
+        }
+    }
+
+    /// How often the dead bytes ratio is checked.
+    /// Default: 60 seconds
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The ratio of dead bytes to live bytes that triggers a compaction.
+    /// Default: 0.5
+    pub fn dead_ratio_threshold(&mut self, dead_ratio_threshold: f64) -> &mut Self {
+        self.dead_ratio_threshold = dead_ratio_threshold;
+        self
+    }
 }
 
 impl Default for WALConfig {
@@ -142,8 +617,11 @@ impl WALConfig {
     pub fn new() -> Self {
         Self {
             log_path: PathBuf::from("./log"),
-            max_log_size: 5 * 1024 * 1024, // 5MB
+            max_log_size: Size::Megabytes(5).to_bytes(),
             compression: None,
+            group_commit: false,
+            max_retained_logs: None,
+            oversized: None,
         }
     }
 
@@ -154,11 +632,16 @@ impl WALConfig {
         self
     }
 
-    /// The maximum size of the log file.
+    /// The maximum size of the log file, in bytes or as a `Size` like `"5MB".parse()?`.
     /// Default: 5MB
-    /// This is the maximum size of the log file before it is rotated.
-    pub fn max_log_size(&mut self, max_log_size: u64) -> &mut Self {
-        self.max_log_size = max_log_size;
+    /// Commits move on to a new log chunk once the current one reaches this size.
+    pub fn max_log_size<S: Into<Size>>(&mut self, max_log_size: S) -> &mut Self {
+        let max_log_size = max_log_size.into();
+
+        self.max_log_size = max_log_size.checked_to_bytes().unwrap_or_else(|| {
+            self.oversized.get_or_insert("wal.max_log_size");
+            u64::MAX
+        });
         self
     }
 
@@ -171,4 +654,164 @@ impl WALConfig {
         self.compression = Some(f(&mut CompressionConfig::new()).clone());
         self
     }
+    /// Whether commits wait until their log is synced to disk before returning.
+    /// Concurrent commits share one write and one sync, so they cost about as much as a single commit.
+    /// Ignored by the in-memory backend.
+    /// Default: false
+    pub fn group_commit(&mut self, group_commit: bool) -> &mut Self {
+        self.group_commit = group_commit;
+        self
+    }
+
+    /// The number of log chunks kept, the current one included. The oldest chunks past it
+    /// are removed on every flush, once their transactions are persisted in the data chunks.
+    /// Removed transactions can't be read by `wal_since` or replayed by `recover_to`.
+    /// Default: None (every log chunk is kept)
+    pub fn max_retained_logs(&mut self, max_retained_logs: usize) -> &mut Self {
+        self.max_retained_logs = Some(max_retained_logs);
+        self
+    }
+}
+
+/// A size in bytes, parsed from strings like `"512KiB"`, `"10MB"` or `"1GB"`.
+/// Units are powers of 1024, with or without the `i`.
+/// Sizes compare by their number of bytes, so `Size::Kilobytes(1) == Size::Bytes(1024)`.
+#[derive(Debug, Clone, Copy)]
+pub enum Size {
+    Bytes(u64),
+    Kilobytes(u64),
+    Megabytes(u64),
+    Gigabytes(u64),
+}
+
+impl Size {
+    /// Saturates at `u64::MAX` for sizes that don't fit, see `checked_to_bytes`.
+    pub fn to_bytes(&self) -> u64 {
+        self.checked_to_bytes().unwrap_or(u64::MAX)
+    }
+
+    /// Returns `None` if the size doesn't fit in a `u64`.
+    pub fn checked_to_bytes(&self) -> Option<u64> {
+        match self {
+            Size::Bytes(size) => Some(*size),
+            Size::Kilobytes(size) => size.checked_mul(1024),
+            Size::Megabytes(size) => size.checked_mul(1024 * 1024),
+            Size::Gigabytes(size) => size.checked_mul(1024 * 1024 * 1024),
+        }
+    }
+
+    /// Returns `None` if the sum doesn't fit in a `u64`.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.checked_to_bytes()?
+            .checked_add(other.checked_to_bytes()?)
+            .map(Size::from_bytes)
+    }
+
+    /// Uses the largest unit that holds the size exactly, `2048` is `Size::Kilobytes(2)`.
+    pub fn from_bytes(bytes: u64) -> Self {
+        const KB: u64 = 1024;
+
+        match bytes {
+            0 => Size::Bytes(0),
+            b if b % (KB * KB * KB) == 0 => Size::Gigabytes(b / (KB * KB * KB)),
+            b if b % (KB * KB) == 0 => Size::Megabytes(b / (KB * KB)),
+            b if b % KB == 0 => Size::Kilobytes(b / KB),
+            b => Size::Bytes(b),
+        }
+    }
+}
+
+impl From<usize> for Size {
+    fn from(bytes: usize) -> Self {
+        Size::from_bytes(bytes as u64)
+    }
+}
+
+impl From<Size> for u64 {
+    fn from(size: Size) -> Self {
+        size.to_bytes()
+    }
+}
+
+/// Saturates at `usize::MAX`, like `Size::to_bytes`.
+impl From<Size> for usize {
+    fn from(size: Size) -> Self {
+        usize::try_from(size.to_bytes()).unwrap_or(usize::MAX)
+    }
+}
+
+impl PartialEq for Size {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+impl Eq for Size {}
+
+impl PartialOrd for Size {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Size {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.to_bytes().cmp(&other.to_bytes())
+    }
+}
+
+/// Saturates at `u64::MAX` bytes, see `Size::checked_add`.
+impl Add for Size {
+    type Output = Size;
+
+    fn add(self, other: Self) -> Size {
+        self.checked_add(other).unwrap_or(Size::Bytes(u64::MAX))
+    }
+}
+
+/// Subtracting a larger size gives zero.
+impl Sub for Size {
+    type Output = Size;
+
+    fn sub(self, other: Self) -> Size {
+        Size::from_bytes(self.to_bytes().saturating_sub(other.to_bytes()))
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Size::Bytes(size) => write!(f, "{} B", size),
+            Size::Kilobytes(size) => write!(f, "{} KB", size),
+            Size::Megabytes(size) => write!(f, "{} MB", size),
+            Size::Gigabytes(size) => write!(f, "{} GB", size),
+        }
+    }
+}
+
+impl FromStr for Size {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (size, unit) = s.split_at(split);
+
+        let size = size
+            .parse::<u64>()
+            .map_err(|_| Error::Other(format!("Invalid size {}", s)))?;
+
+        let size = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => Size::Bytes(size),
+            "KB" | "KIB" => Size::Kilobytes(size),
+            "MB" | "MIB" => Size::Megabytes(size),
+            "GB" | "GIB" => Size::Gigabytes(size),
+            _ => return Err(Error::Other(format!("Invalid size unit in {}", s))),
+        };
+
+        match size.checked_to_bytes() {
+            Some(_) => Ok(size),
+            None => Err(Error::Other(format!("Size {} doesn't fit in 64 bits", s))),
+        }
+    }
 }
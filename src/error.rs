@@ -1,16 +1,33 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
 pub enum Error {
     IoError(std::io::Error),
     Deadlock,
     DatabaseLocked,
+    ReadOnly,
     AlreadyExists(String),
     NotFound(String),
     CorruptedData(String),
+    TransactionAlreadyCommitted,
+    TransactionTooLarge(usize), // the limit of operations
+    InvalidTransactionState(String),
+    VersionMismatch(String), // files written in a format this build doesn't support
+    QuotaExceeded(String),
+    VersionConflict(u64), // the version stored for the key
+    MissingDataChunk {
+        chunk: String,
+    }, // referenced by the index, `rebuild_index` repairs it
+    MissingWalChunk {
+        chunk: String,
+    }, // referenced by the write-ahead log index
+    RevertFailed {
+        error: Box<Error>,             // why the transaction failed
+        reverts: Vec<(String, Error)>, // the collections that couldn't be reverted, and why
+    },
     Other(String),
 }
 
-impl Debug for Error {
+impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IoError(err) => write!(f, "IO Error: {}", err),
@@ -18,12 +35,62 @@ impl Debug for Error {
             Error::DatabaseLocked => {
                 write!(f, "Database is locked, maybe another instance is running?")
             }
+            Error::ReadOnly => write!(f, "Database is opened read-only"),
             Error::Other(err) => write!(f, "Other Error: {}", err),
             Error::CorruptedData(err) => write!(f, "Corrupted data: {}", err),
             Error::AlreadyExists(message) => write!(f, "{} already exists", message),
             Error::NotFound(message) => write!(f, "{} not found", message),
+            Error::TransactionAlreadyCommitted => write!(f, "Transaction already committed"),
+            Error::TransactionTooLarge(limit) => {
+                write!(f, "Transaction exceeds the limit of {} operations", limit)
+            }
+            Error::InvalidTransactionState(message) => {
+                write!(f, "Invalid transaction state: {}", message)
+            }
+            Error::VersionMismatch(message) => write!(f, "Unsupported format version: {}", message),
+            Error::QuotaExceeded(message) => write!(f, "Quota exceeded: {}", message),
+            Error::VersionConflict(version) => {
+                write!(f, "Version conflict, the stored version is {}", version)
+            }
+            Error::MissingDataChunk { chunk } => {
+                write!(f, "Data chunk {} not found, but index contains it", chunk)
+            }
+            Error::MissingWalChunk { chunk } => {
+                write!(f, "WAL Log {} not found, but wal index contains it", chunk)
+            }
+            Error::RevertFailed { error, reverts } => {
+                write!(f, "{}", error)?;
+
+                for (collection, err) in reverts {
+                    write!(f, ", and reverting {} failed: {}", collection, err)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            Error::RevertFailed { error, .. } => Some(&**error),
+            _ => None,
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -20,7 +20,7 @@
 //!     age: u32,
 //! }
 //!
-//! let collection = dustdata.collection::<User>("users");
+//! let collection = dustdata.collection::<User>("users").unwrap();
 //!
 //! let user = User {
 //!     name: "Pedro".to_string(),
@@ -46,59 +46,441 @@
 //! let user = collection.get("user:1").unwrap();
 //! ```
 
+/// Emits a warning as a `tracing` event with the `tracing` feature, and does nothing without it.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        let _ = format_args!($($arg)*);
+    };
+}
+
 pub mod bloom;
 pub mod collection;
 pub mod config;
 pub mod error;
 
-pub use collection::Collection;
+pub use collection::{Collection, MultiTransaction, RawCollection};
 pub use config::*;
 
 pub use bincode;
 use error::Result;
 use fs2::FileExt;
+use parking_lot::RwLock;
 use serde::{de::DeserializeOwned, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::time;
+
+type Collections = RwLock<HashMap<String, Box<dyn CollectionHandle>>>;
+
+/// Type-erased handle to a cached collection.
+trait CollectionHandle: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn flush(&self) -> Result<()>;
+    fn copy_data_to(&self, path: &Path) -> Result<()>;
+    fn handle_count(&self) -> usize;
+    fn stop_compaction(&self);
+}
+
+impl<T> CollectionHandle for collection::Collection<T>
+where
+    T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flush(&self) -> Result<()> {
+        collection::Collection::flush(self)
+    }
+
+    fn copy_data_to(&self, path: &Path) -> Result<()> {
+        collection::Collection::copy_data_to(self, path)
+    }
+
+    fn handle_count(&self) -> usize {
+        collection::Collection::handle_count(self)
+    }
+
+    fn stop_compaction(&self) {
+        collection::Collection::stop_compaction(self)
+    }
+}
 
 pub struct DustData {
     config: config::DustDataConfig,
+    collections: Collections,
+    lock_file: Option<fs::File>, // None for in-memory instances
+    coordinator: Option<collection::Coordinator>, // None for in-memory and read-only instances
 }
 
 impl DustData {
+    /// Opens a DustData instance, locking the data directory.
+    /// Returns `Error::DatabaseLocked` if another instance holds the lock, see `LockMode`.
+    /// Returns `Error::Other` if the configuration is invalid.
     pub fn new(config: config::DustDataConfig) -> Result<Self> {
+        config.validate()?;
+
+        if config.backend == config::StorageBackend::Memory {
+            return Ok(Self {
+                config,
+                collections: RwLock::new(HashMap::new()),
+                lock_file: None,
+                coordinator: None,
+            });
+        }
+
         fs::create_dir_all(&config.data_path).ok();
 
-        if !config.data_path.join(".dustdata-lock").exists() {
-            let file = fs::File::create(config.data_path.join(".dustdata-lock")).unwrap();
+        let lock_path = config.data_path.join(".dustdata-lock");
+        let read_only = config.lock_mode == config::LockMode::ReadOnly;
 
-            file.lock_exclusive().unwrap();
+        let mut options = fs::OpenOptions::new();
+        options.read(true);
+
+        if !read_only || !lock_path.exists() {
+            options.write(true).create(true).truncate(false);
         }
 
-        Ok(Self { config })
+        let mut lock_file = options.open(&lock_path)?;
+
+        match config.lock_mode {
+            config::LockMode::FailFast => lock_file
+                .try_lock_exclusive()
+                .map_err(|_| error::Error::DatabaseLocked)?,
+            config::LockMode::Wait(timeout) => {
+                let started_at = time::Instant::now();
+
+                while lock_file.try_lock_exclusive().is_err() {
+                    if started_at.elapsed() >= timeout {
+                        return Err(error::Error::DatabaseLocked);
+                    }
+
+                    std::thread::sleep(time::Duration::from_millis(10));
+                }
+            }
+            config::LockMode::ReadOnly => lock_file
+                .try_lock_shared()
+                .map_err(|_| error::Error::DatabaseLocked)?,
+        }
+
+        // The owner is only recorded by the instance that writes.
+        if !read_only {
+            Self::claim_lock(&mut lock_file, &lock_path)?;
+        }
+
+        let coordinator = (!read_only)
+            .then(|| {
+                collection::Coordinator::open(&config.data_path, config.wal.compression.clone())
+            })
+            .transpose()?;
+
+        Ok(Self {
+            config,
+            collections: RwLock::new(HashMap::new()),
+            lock_file: Some(lock_file),
+            coordinator,
+        })
     }
 
+    /// Same as `DustData::new`, which returns the lock errors too.
+    #[deprecated(note = "use `DustData::new`, which returns the same errors")]
     pub fn try_new(config: config::DustDataConfig) -> Result<Self> {
-        fs::create_dir_all(&config.data_path).ok();
+        Self::new(config)
+    }
 
-        if !config.data_path.join(".dustdata-lock").exists() {
-            let file = fs::File::create(config.data_path.join(".dustdata-lock")).unwrap();
+    /// Gets a collection, creating it if it doesn't exist.
+    /// Repeated calls with the same name return handles that share the same state.
+    pub fn collection<T>(&self, name: &str) -> Result<collection::Collection<T>>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
+    {
+        self.collection_with_config(name, |_| {})
+    }
+
+    /// Same as `DustData::collection`, but `f` can change the configuration of the
+    /// collection, like its compression or chunk sizes. The collection is still stored
+    /// under `data_path/name`, whatever `f` sets `data_path` to.
+    /// The configuration is only used when the collection is opened, a collection that is
+    /// already open is returned as it is.
+    pub fn collection_with_config<T, F>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> Result<collection::Collection<T>>
+    where
+        T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
+        F: FnOnce(&mut config::DustDataConfig),
+    {
+        let mut collections = self.collections.write();
 
-            file.try_lock_exclusive()
-                .map_err(|_| error::Error::DatabaseLocked)?;
+        if let Some(collection) = collections.get(name) {
+            return collection
+                .as_any()
+                .downcast_ref::<collection::Collection<T>>()
+                .cloned()
+                .ok_or_else(|| {
+                    error::Error::Other(format!(
+                        "Collection {} was opened with a different type",
+                        name
+                    ))
+                });
         }
 
-        Ok(Self { config })
+        let mut config = self.config.clone();
+        f(&mut config);
+        config.data_path = self.config.data_path.join(name);
+        config.validate()?;
+
+        let collection = collection::Collection::new(config)?;
+        collections.insert(name.to_string(), Box::new(collection.clone()));
+
+        Ok(collection)
     }
 
-    pub fn collection<T>(&self, name: &str) -> collection::Collection<T>
+    /// Same as `DustData::collection`, but returns `Error::NotFound` instead of creating a
+    /// collection that doesn't exist, see `DustData::collection_exists`.
+    pub fn open_collection<T>(&self, name: &str) -> Result<collection::Collection<T>>
     where
         T: Sync + Send + Clone + Debug + Serialize + DeserializeOwned + 'static,
     {
-        let mut config = self.config.clone();
-        config.data_path.push(name);
+        if !self.collection_exists(name) {
+            return Err(error::Error::NotFound(name.to_string()));
+        }
+
+        self.collection(name)
+    }
+
+    /// Whether a collection is open or has data under `data_path/name/data`.
+    /// Collections of the in-memory backend only exist while they're open.
+    pub fn collection_exists(&self, name: &str) -> bool {
+        if self.collections.read().contains_key(name) {
+            return true;
+        }
+
+        self.config.backend == config::StorageBackend::Disk
+            && Self::is_collection_name(name)
+            && self.config.data_path.join(name).join("data").is_dir()
+    }
+
+    /// Gets a collection of raw byte values, creating it if it doesn't exist.
+    /// Values are stored as they are, see `RawCollection`.
+    pub fn raw_collection(&self, name: &str) -> Result<collection::RawCollection> {
+        Ok(collection::RawCollection::new(self.collection(name)?))
+    }
+
+    /// Starts a transaction that commits operations on several collections all or nothing.
+    pub fn transaction(&self) -> collection::MultiTransaction<'_> {
+        collection::MultiTransaction::new(self)
+    }
+
+    /// Drops a collection, removing its data chunks, index and write-ahead log from disk.
+    /// This is a path-level operation, so an open `Collection` handle isn't required.
+    /// Returns `Error::Other` while a handle of the collection is still alive, since it
+    /// would keep writing to the removed files.
+    pub fn drop_collection(&self, name: &str) -> Result<()> {
+        if self.config.lock_mode == config::LockMode::ReadOnly {
+            return Err(error::Error::ReadOnly);
+        }
+
+        if !Self::is_collection_name(name) {
+            return Err(error::Error::NotFound(name.to_string()));
+        }
+
+        // Holding the collections keeps the collection from being opened again until the
+        // directory is gone.
+        let mut collections = self.collections.write();
+
+        if collections
+            .get(name)
+            .is_some_and(|collection| collection.handle_count() > 1)
+        {
+            return Err(error::Error::Other(format!(
+                "Collection {} is still in use, drop its handles first",
+                name
+            )));
+        }
 
-        collection::Collection::new(config)
+        if self.config.backend == config::StorageBackend::Memory {
+            return match collections.remove(name) {
+                Some(_) => Ok(()),
+                None => Err(error::Error::NotFound(name.to_string())),
+            };
+        }
+
+        let collection_path = self.config.data_path.join(name);
+
+        if !collection_path.is_dir() {
+            return Err(error::Error::NotFound(name.to_string()));
+        }
+
+        // Dropping the last handle persists the index, so it goes before the directory.
+        collections.remove(name);
+
+        fs::remove_dir_all(collection_path)?;
+
+        Ok(())
+    }
+
+    /// Copies the collection `src` to a new collection `dst` file by file, which is much faster
+    /// than reinserting its values. The copy gets the data chunks, index and blobs of `src`,
+    /// but starts with an empty write-ahead log and no secondary indexes.
+    /// An open `src` is flushed first, and its commits wait until the copy is done.
+    /// Returns `Error::AlreadyExists` if `dst` exists, and `Error::NotFound` if `src` doesn't.
+    pub fn clone_collection(&self, src: &str, dst: &str) -> Result<()> {
+        if self.config.lock_mode == config::LockMode::ReadOnly {
+            return Err(error::Error::ReadOnly);
+        }
+
+        if self.config.backend == config::StorageBackend::Memory {
+            return Err(error::Error::Other(
+                "Collections of the in-memory backend can't be cloned".to_string(),
+            ));
+        }
+
+        if !Self::is_collection_name(src) {
+            return Err(error::Error::NotFound(src.to_string()));
+        }
+
+        if !Self::is_collection_name(dst) {
+            return Err(error::Error::Other(format!(
+                "Invalid collection name {}",
+                dst
+            )));
+        }
+
+        // Holding the collections keeps `src` and `dst` from being opened during the copy.
+        let collections = self.collections.write();
+
+        let src_path = self.config.data_path.join(src);
+        let dst_path = self.config.data_path.join(dst);
+
+        if !src_path.is_dir() {
+            return Err(error::Error::NotFound(src.to_string()));
+        }
+
+        if dst_path.exists() || collections.contains_key(dst) {
+            return Err(error::Error::AlreadyExists(dst.to_string()));
+        }
+
+        // The copy is only moved to `dst` once it's complete.
+        let tmp_path = self.config.data_path.join(format!(".{}.clone-tmp", dst));
+
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path)?;
+        }
+
+        let copied = match collections.get(src) {
+            Some(collection) => collection.copy_data_to(&tmp_path.join("data")),
+            None => collection::copy_dir_all(&src_path.join("data"), &tmp_path.join("data"))
+                .map_err(Into::into),
+        };
+
+        if let Err(err) = copied {
+            fs::remove_dir_all(&tmp_path).ok();
+
+            return Err(err);
+        }
+
+        fs::rename(tmp_path, dst_path)?;
+
+        Ok(())
+    }
+
+    /// Flushes every collection opened by this instance, stopping at the first error.
+    /// Call it from a shutdown handler to persist everything without waiting for `Drop`.
+    pub fn flush_all(&self) -> Result<()> {
+        for collection in self.collections.read().values() {
+            collection.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops the background compactions, flushes every open collection and releases the
+    /// lock on the data directory, returning any error instead of ignoring it like `Drop` does.
+    /// Dropping without calling `close` flushes and unlocks too, but errors are discarded, or
+    /// logged with the `tracing` feature.
+    pub fn close(mut self) -> Result<()> {
+        self.release_collections()?;
+
+        if let Some(lock_file) = self.lock_file.take() {
+            self.release_lock(&lock_file)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops the background compactions, flushes every open collection and drops the cached
+    /// handles, so their indexes are written before the lock is released.
+    /// Handles held elsewhere keep their collection open, but stop compacting.
+    fn release_collections(&self) -> Result<()> {
+        let collections = std::mem::take(&mut *self.collections.write());
+
+        for collection in collections.values() {
+            collection.stop_compaction();
+        }
+
+        collections
+            .values()
+            .try_for_each(|collection| collection.flush())
+    }
+
+    /// Whether `name` is a single path component, so the collection stays inside `data_path`.
+    fn is_collection_name(name: &str) -> bool {
+        let mut components = Path::new(name).components();
+
+        matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+    }
+
+    /// Records the owning process in the lock file.
+    /// The owner is cleared when the lock is released, so an owner left in a lock file
+    /// whose `flock` is free belongs to a process that crashed.
+    fn claim_lock(lock_file: &mut fs::File, lock_path: &Path) -> Result<()> {
+        let mut owner = String::new();
+        lock_file.read_to_string(&mut owner)?;
+
+        if !owner.trim().is_empty() {
+            warn!(
+                "Reclaiming stale lock {} ({})",
+                lock_path.display(),
+                owner.trim().replace('\n', ", ")
+            );
+        }
+
+        let started_at = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        lock_file.set_len(0)?;
+        lock_file.seek(SeekFrom::Start(0))?;
+        write!(
+            lock_file,
+            "pid={}\nstarted_at={}\n",
+            std::process::id(),
+            started_at
+        )?;
+        lock_file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn release_lock(&self, lock_file: &fs::File) -> std::io::Result<()> {
+        if self.config.lock_mode != config::LockMode::ReadOnly {
+            lock_file.set_len(0)?;
+        }
+
+        lock_file.unlock()
     }
 
     pub fn config(&self) -> &config::DustDataConfig {
@@ -108,8 +490,16 @@ impl DustData {
 
 impl Drop for DustData {
     fn drop(&mut self) {
-        let file = fs::File::open(self.config.data_path.join(".dustdata-lock")).unwrap();
+        // The collections are released first, so the lock is only released once their
+        // data is durable and nothing writes to it anymore.
+        if let Err(err) = self.release_collections() {
+            warn!("Failed to flush the collections: {}", err);
+        }
 
-        file.unlock().unwrap();
+        if let Some(lock_file) = &self.lock_file {
+            if let Err(err) = self.release_lock(lock_file) {
+                warn!("Failed to unlock the data directory: {}", err);
+            }
+        }
     }
 }
@@ -0,0 +1,36 @@
+use dustdata::bloom::BloomFilter;
+
+#[test]
+pub fn seeded_filters_are_reproducible() {
+    let mut unseeded = BloomFilter::new(0.01, 100);
+    let mut first = BloomFilter::with_seed(0.01, 100, 42);
+    let mut second = BloomFilter::with_seed(0.01, 100, 42);
+    let mut other = BloomFilter::with_seed(0.01, 100, 7);
+
+    for filter in [&mut unseeded, &mut first, &mut second, &mut other] {
+        filter.insert("key");
+        assert!(filter.contains("key"));
+    }
+
+    assert_eq!(unseeded.get_seed(), 0);
+    assert_eq!(first.get_bitvec(), second.get_bitvec());
+    assert_ne!(first.get_bitvec(), other.get_bitvec());
+    assert_ne!(first.get_bitvec(), unseeded.get_bitvec());
+}
+
+#[test]
+pub fn population_tracks_the_fill() {
+    let mut filter = BloomFilter::new(0.01, 100);
+    assert_eq!(filter.population(), 0);
+    assert_eq!(filter.estimated_fp_rate(), 0.0);
+
+    filter.insert("key");
+    let population = filter.population();
+    assert!(population > 0 && population <= filter.get_hashes() as u64);
+
+    for i in 0..100 {
+        filter.insert(&format!("key:{}", i));
+    }
+    assert!(filter.population() > population);
+    assert!(filter.estimated_fp_rate() > 0.0 && filter.estimated_fp_rate() < 0.1);
+}
@@ -1,15 +1,17 @@
 use dustdata::DustData;
 
-pub fn test_config() -> dustdata::DustDataConfig {
-    dustdata::DustDataConfig::default()
-        .data_path("./test_data")
-        .build()
+// Removes what a previous run left, so clone the config to reopen the same data.
+pub fn test_config(name: &str) -> dustdata::DustDataConfig {
+    let path = format!("./test_data/{}", name);
+    std::fs::remove_dir_all(&path).ok();
+
+    dustdata::DustDataConfig::default().data_path(path).build()
 }
 
 #[test]
 pub fn collection_insert_operation() {
-    let dustdata = DustData::new(test_config()).unwrap();
-    let collection = dustdata.collection::<String>("insert_collection");
+    let dustdata = DustData::new(test_config("collection_insert_operation")).unwrap();
+    let collection = dustdata.collection::<String>("insert_collection").unwrap();
 
     collection
         .start_lazy(|t| {
@@ -24,8 +26,8 @@ pub fn collection_insert_operation() {
 
 #[test]
 pub fn collection_update_operation() {
-    let dustdata = DustData::new(test_config()).unwrap();
-    let collection = dustdata.collection::<String>("update_collection");
+    let dustdata = DustData::new(test_config("collection_update_operation")).unwrap();
+    let collection = dustdata.collection::<String>("update_collection").unwrap();
 
     collection
         .start_lazy(|t| {
@@ -46,8 +48,8 @@ pub fn collection_update_operation() {
 
 #[test]
 pub fn collection_delete_operation() {
-    let dustdata = DustData::new(test_config()).unwrap();
-    let collection = dustdata.collection::<String>("delete_collection");
+    let dustdata = DustData::new(test_config("collection_delete_operation")).unwrap();
+    let collection = dustdata.collection::<String>("delete_collection").unwrap();
 
     collection
         .start_lazy(|t| {
@@ -68,8 +70,10 @@ pub fn collection_delete_operation() {
 
 #[test]
 pub fn collection_revert_operation() {
-    let dustdata = DustData::new(test_config()).unwrap();
-    let collection = dustdata.collection::<String>("revert_operation_collection");
+    let dustdata = DustData::new(test_config("collection_revert_operation")).unwrap();
+    let collection = dustdata
+        .collection::<String>("revert_operation_collection")
+        .unwrap();
 
     let mut rolledback_transaction = collection
         .start_lazy(|t| {
@@ -85,3 +89,3372 @@ pub fn collection_revert_operation() {
 
     assert!(value.is_none());
 }
+
+#[test]
+pub fn drop_collection_removes_data() {
+    let dustdata = DustData::new(test_config("drop_collection_removes_data")).unwrap();
+
+    {
+        let collection = dustdata.collection::<String>("drop_collection").unwrap();
+
+        collection
+            .start_lazy(|t| {
+                t.insert("key", "value".to_string());
+            })
+            .unwrap();
+    }
+
+    dustdata.drop_collection("drop_collection").unwrap();
+
+    assert!(!dustdata.config().data_path.join("drop_collection").exists());
+    assert!(dustdata.config().data_path.join(".dustdata-lock").exists());
+
+    let collection = dustdata.collection::<String>("drop_collection").unwrap();
+    assert!(collection.get("key").unwrap().is_none());
+}
+
+#[test]
+pub fn drop_collection_with_live_handles() {
+    let config = test_config("drop_collection_with_live_handles");
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("live").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("key", "value".to_string()))
+        .unwrap();
+
+    assert!(matches!(
+        dustdata.drop_collection("live"),
+        Err(dustdata::error::Error::Other(_))
+    ));
+    assert_eq!(collection.get("key").unwrap().unwrap(), "value");
+
+    drop(collection);
+    dustdata.drop_collection("live").unwrap();
+
+    let collection = dustdata.collection::<String>("live").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("other", "value".to_string()))
+        .unwrap();
+    drop((collection, dustdata));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("live").unwrap();
+
+    assert!(collection.get("key").unwrap().is_none());
+    assert_eq!(collection.get("other").unwrap().unwrap(), "value");
+}
+
+#[test]
+pub fn drop_missing_collection() {
+    let dustdata = DustData::new(test_config("drop_missing_collection")).unwrap();
+
+    let result = dustdata.drop_collection("missing_collection");
+
+    assert!(matches!(result, Err(dustdata::error::Error::NotFound(_))));
+}
+
+#[test]
+pub fn collection_handles_share_state() {
+    let dustdata = DustData::new(test_config("collection_handles_share_state")).unwrap();
+
+    let writer = dustdata.collection::<String>("shared_collection").unwrap();
+    let reader = dustdata.collection::<String>("shared_collection").unwrap();
+
+    writer
+        .start_lazy(|t| {
+            t.insert("key", "value".to_string());
+        })
+        .unwrap();
+
+    let value = reader.get("key").unwrap().unwrap();
+
+    assert_eq!(value, "value");
+}
+
+#[test]
+pub fn database_lock_is_exclusive() {
+    let config = test_config("database_lock_is_exclusive");
+
+    let _dustdata = DustData::new(config.clone()).unwrap();
+    let result = DustData::new(config);
+
+    assert!(matches!(
+        result,
+        Err(dustdata::error::Error::DatabaseLocked)
+    ));
+}
+
+#[test]
+pub fn transaction_reads_staged_operations() {
+    let mut transaction = dustdata::collection::Transaction::<String>::new();
+
+    transaction.insert("key", "value".to_string());
+    transaction.insert("other_key", "other_value".to_string());
+    assert_eq!(transaction.get("key").unwrap(), "value");
+
+    transaction.update("key", "new_value".to_string());
+    assert_eq!(transaction.get("key").unwrap(), "new_value");
+
+    transaction.delete("key");
+    assert!(transaction.get("key").is_none());
+    assert_eq!(transaction.get("other_key").unwrap(), "other_value");
+
+    transaction.clear();
+    assert!(transaction.get("other_key").is_none());
+}
+
+#[test]
+pub fn double_commit_returns_error() {
+    let dustdata = DustData::new(test_config("double_commit_returns_error")).unwrap();
+    let collection = dustdata.collection::<String>("double_commit").unwrap();
+
+    let mut transaction = collection
+        .start_lazy(|t| {
+            t.insert("key", "value".to_string());
+        })
+        .unwrap();
+
+    assert!(matches!(
+        collection.commit(&mut transaction),
+        Err(dustdata::error::Error::TransactionAlreadyCommitted)
+    ));
+    assert!(matches!(
+        collection.abort_transaction(&mut transaction),
+        Err(dustdata::error::Error::TransactionAlreadyCommitted)
+    ));
+}
+
+#[test]
+pub fn rollback_invalid_state_returns_error() {
+    let dustdata = DustData::new(test_config("rollback_invalid_state_returns_error")).unwrap();
+    let collection = dustdata.collection::<String>("rollback_invalid").unwrap();
+
+    let mut transaction = collection.start();
+    transaction.insert("key", "value".to_string());
+
+    assert!(matches!(
+        collection.rollback_transaction(&mut transaction),
+        Err(dustdata::error::Error::InvalidTransactionState(_))
+    ));
+
+    collection.abort_transaction(&mut transaction).unwrap();
+
+    assert!(matches!(
+        collection.rollback_transaction(&mut transaction),
+        Err(dustdata::error::Error::InvalidTransactionState(_))
+    ));
+}
+
+#[test]
+pub fn partial_index_write_keeps_previous_index() {
+    let config = test_config("partial_index_write_keeps_previous_index");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<String>("atomic_index").unwrap();
+
+        collection
+            .start_lazy(|t| {
+                t.insert("key", "value".to_string());
+            })
+            .unwrap();
+    }
+
+    let collection_path = config.data_path.join("atomic_index");
+
+    // Simulates a crash in the middle of writing the new indexes.
+    std::fs::write(collection_path.join("data/.index-dustdata.tmp"), [1, 2, 3]).unwrap();
+    std::fs::write(
+        collection_path.join("log/.wal-index-dustdata.tmp"),
+        [1, 2, 3],
+    )
+    .unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("atomic_index").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), "value");
+}
+
+#[test]
+pub fn wal_index_grows_by_constant_size_per_commit() {
+    let config = test_config("wal_index_grows_by_constant_size_per_commit");
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<usize>("wal_index_append").unwrap();
+
+    let index_path = config
+        .data_path
+        .join("wal_index_append/log/.wal-index-dustdata");
+    let index_size = || std::fs::metadata(&index_path).unwrap().len();
+
+    collection.start_lazy(|t| t.insert("key", 0)).unwrap();
+
+    let mut sizes = vec![index_size()];
+
+    for i in 1..100 {
+        collection.start_lazy(|t| t.update("key", i)).unwrap();
+
+        sizes.push(index_size());
+    }
+
+    let growth = sizes.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+
+    assert!(growth.iter().all(|g| *g == growth[0]));
+}
+
+#[test]
+pub fn memory_backend_never_touches_disk() {
+    let config = test_config("memory_backend_never_touches_disk")
+        .backend(dustdata::StorageBackend::Memory)
+        .build();
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("memory_collection").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("key", "value".to_string());
+            t.insert("other_key", "other_value".to_string());
+        })
+        .unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.update("key", "new_value".to_string());
+            t.delete("other_key");
+        })
+        .unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), "new_value");
+    assert!(collection.get("other_key").unwrap().is_none());
+
+    let mut transaction = collection
+        .start_lazy(|t| {
+            t.insert("temporary", "value".to_string());
+        })
+        .unwrap();
+    collection.rollback_transaction(&mut transaction).unwrap();
+
+    assert!(collection.get("temporary").unwrap().is_none());
+    assert!(!config.data_path.exists());
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+    std::fs::create_dir_all(to).unwrap();
+
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+#[test]
+pub fn flush_persists_without_dropping() {
+    let config = test_config("flush_persists_without_dropping");
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("flush_collection").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("key", "value".to_string());
+        })
+        .unwrap();
+
+    collection.flush().unwrap();
+
+    // The original instance is still alive, so its drops haven't persisted anything.
+    let copy_config = test_config("flush_persists_without_dropping_copy");
+    copy_dir(
+        &config.data_path.join("flush_collection"),
+        &copy_config.data_path.join("flush_collection"),
+    );
+
+    {
+        let copy = DustData::new(copy_config).unwrap();
+        let collection = copy.collection::<String>("flush_collection").unwrap();
+
+        assert_eq!(collection.get("key").unwrap().unwrap(), "value");
+    }
+}
+
+#[test]
+pub fn close_releases_the_lock() {
+    let config = test_config("close_releases_the_lock");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("close_collection").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("key", "value".to_string());
+        })
+        .unwrap();
+
+    collection.close().unwrap();
+    dustdata.close().unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("close_collection").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), "value");
+}
+
+#[test]
+pub fn compaction_reclaims_overwritten_values() {
+    let config = test_config("compaction_reclaims_overwritten_values");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("compaction").unwrap();
+
+        collection.start_lazy(|t| t.insert("key", 0)).unwrap();
+        collection.start_lazy(|t| t.insert("other_key", 0)).unwrap();
+
+        for i in 1..50 {
+            collection.start_lazy(|t| t.update("key", i)).unwrap();
+        }
+
+        let stats = collection.compact().unwrap();
+
+        assert!(stats.reclaimed_bytes() > 0);
+        assert_eq!(stats.live_segments, 2);
+        assert_eq!(collection.compaction_stats(), Some(stats));
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("compaction").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), 49);
+    assert_eq!(collection.get("other_key").unwrap().unwrap(), 0);
+}
+
+#[test]
+pub fn background_compaction_runs() {
+    let config = test_config("background_compaction_runs")
+        .compaction(|c| c.interval(std::time::Duration::from_millis(10)))
+        .build();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("compaction").unwrap();
+
+    collection.start_lazy(|t| t.insert("key", 0)).unwrap();
+
+    for i in 1..10 {
+        collection.start_lazy(|t| t.update("key", i)).unwrap();
+    }
+
+    for _ in 0..100 {
+        if collection.compaction_stats().is_some() {
+            break;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    assert!(collection.compaction_stats().unwrap().reclaimed_bytes() > 0);
+    assert_eq!(collection.get("key").unwrap().unwrap(), 9);
+}
+
+#[test]
+pub fn background_compaction_failures_are_counted() {
+    // Full chunks aren't reopened for appends, so each open writes to its own chunk.
+    let config = test_config("background_compaction_failures_are_counted")
+        .storage(|s| s.max_data_chunk_size(1).max_data_chunks(100))
+        .compaction(|c| c.interval(std::time::Duration::from_millis(10)))
+        .build();
+
+    for (key, value) in [("a", 0), ("b", 1)] {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("compaction").unwrap();
+
+        collection.start_lazy(|t| t.insert(key, value)).unwrap();
+    }
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<usize>("compaction").unwrap();
+
+    // Nothing is dead before the delete, so compaction only runs once "a" is missing.
+    std::fs::remove_file(config.data_path.join("compaction/data/Data_0_0.db")).unwrap();
+    collection.delete_now("b").unwrap();
+
+    for _ in 0..100 {
+        if collection.metrics().compaction_failures > 0 {
+            break;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    assert!(collection.metrics().compaction_failures > 0);
+    assert_eq!(collection.metrics().compactions, 0);
+}
+
+#[test]
+pub fn memtable_evicts_over_flush_threshold() {
+    let config = test_config("memtable_evicts_over_flush_threshold")
+        .storage(|s| s.flush_threshold(1024))
+        .build();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("memtable_flush").unwrap();
+
+    for i in 0..20 {
+        collection
+            .start_lazy(|t| t.insert(&format!("key:{}", i), "x".repeat(200)))
+            .unwrap();
+
+        assert!(collection.memtable_size().unwrap() <= 1024);
+    }
+
+    for i in 0..20 {
+        let value = collection.get(&format!("key:{}", i)).unwrap().unwrap();
+        assert_eq!(value, "x".repeat(200));
+    }
+}
+
+#[test]
+pub fn memtable_eviction_policies_keep_reads_correct() {
+    use dustdata::MemtableEviction;
+
+    for (eviction, cached) in [
+        (MemtableEviction::Fifo, [false, true, true]),
+        (MemtableEviction::Lru, [true, false, true]),
+        (MemtableEviction::None, [true, true, true]),
+    ] {
+        let config = test_config(&format!("memtable_eviction_{:?}", eviction))
+            .storage(|s| s.flush_threshold(500).memtable_eviction(eviction))
+            .build();
+
+        let dustdata = DustData::new(config).unwrap();
+        let collection = dustdata.collection::<String>("eviction").unwrap();
+
+        collection
+            .start_lazy(|t| t.insert("a", "a".repeat(200)))
+            .unwrap();
+        collection
+            .start_lazy(|t| t.insert("b", "b".repeat(200)))
+            .unwrap();
+        collection.get("a").unwrap();
+        collection
+            .start_lazy(|t| t.insert("c", "c".repeat(200)))
+            .unwrap();
+
+        for (key, cached) in ["a", "b", "c"].into_iter().zip(cached) {
+            let hits = collection.metrics().cache_hits;
+
+            assert_eq!(collection.get(key).unwrap().unwrap(), key.repeat(200));
+            assert_eq!(
+                collection.metrics().cache_hits > hits,
+                cached,
+                "{} cached with {:?}",
+                key,
+                eviction
+            );
+        }
+    }
+}
+
+#[test]
+pub fn delete_range_deletes_keys_in_range() {
+    let dustdata = DustData::new(test_config("delete_range_deletes_keys_in_range")).unwrap();
+    let collection = dustdata.collection::<usize>("delete_range").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("event:2023-01-01", 1);
+            t.insert("event:2023-01-15", 2);
+            t.insert("event:2023-02-01", 3);
+        })
+        .unwrap();
+
+    let deleted = collection
+        .delete_range("event:2023-01".to_string().."event:2023-02".to_string())
+        .unwrap();
+
+    assert_eq!(deleted, 2);
+    assert!(collection.get("event:2023-01-01").unwrap().is_none());
+    assert!(collection.get("event:2023-01-15").unwrap().is_none());
+    assert_eq!(collection.get("event:2023-02-01").unwrap().unwrap(), 3);
+}
+
+#[test]
+pub fn recover_to_replays_up_to_transaction() {
+    let dustdata = DustData::new(test_config("recover_to_replays_up_to_transaction")).unwrap();
+    let collection = dustdata.collection::<usize>("recover_to").unwrap();
+
+    let first = collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    collection.start_lazy(|t| t.update("a", 2)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    collection.start_lazy(|t| t.insert("b", 3)).unwrap();
+
+    let head = collection.wal_head().unwrap();
+    collection.recover_to(first.id()).unwrap();
+
+    assert_eq!(collection.get("a").unwrap().unwrap(), 1);
+    assert!(collection.get("b").unwrap().is_none());
+
+    // The recovery is logged, so a follower replaying the log ends up in the same state.
+    let logs = collection.wal_since(head).unwrap();
+    assert_eq!(logs.len(), 1);
+
+    let follower = dustdata.collection::<usize>("recover_to_follower").unwrap();
+    follower
+        .apply_wal(collection.wal_since(0).unwrap())
+        .unwrap();
+
+    assert_eq!(follower.get("a").unwrap().unwrap(), 1);
+    assert!(follower.get("b").unwrap().is_none());
+
+    assert!(matches!(
+        collection.recover_to(0),
+        Err(dustdata::error::Error::NotFound(_))
+    ));
+}
+
+#[test]
+pub fn recover_to_stops_at_a_truncated_record() {
+    let config = test_config("recover_to_stops_at_a_truncated_record");
+    let log_file = config.data_path.join("truncated/log/DustDataLog_0");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<usize>("truncated").unwrap();
+
+    collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    let torn = collection.start_lazy(|t| t.insert("b", 2)).unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    // Tears the last append, like a crash halfway through writing it.
+    let len = std::fs::metadata(&log_file).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("truncated").unwrap();
+
+    assert!(matches!(
+        collection.wal_since(0),
+        Err(dustdata::error::Error::CorruptedData(_))
+    ));
+
+    collection.recover_to(torn.id()).unwrap();
+
+    assert_eq!(collection.get("a").unwrap().unwrap(), 1);
+    assert!(collection.get("b").unwrap().is_none());
+}
+
+#[test]
+pub fn compression_algorithm_roundtrip() {
+    use dustdata::CompressionAlgorithm;
+
+    let base = test_config("compression_algorithm_roundtrip");
+    let config = |algorithm| {
+        base.clone()
+            .storage(|s| s.compression(|c| c.algorithm(algorithm).level(3)))
+            .wal(|w| w.compression(|c| c.algorithm(algorithm).level(3)))
+            .build()
+    };
+
+    let dustdata = DustData::new(config(CompressionAlgorithm::Zstd)).unwrap();
+    let collection = dustdata.collection::<String>("compression").unwrap();
+
+    let transaction = collection
+        .start_lazy(|t| t.insert("key", "value".to_string()))
+        .unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    // The algorithm tag lets files written with zstd be read with another config.
+    let dustdata = DustData::new(config(CompressionAlgorithm::Lz4)).unwrap();
+    let collection = dustdata.collection::<String>("compression").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), "value");
+    assert!(collection.wal.read().index.get(transaction.id()).is_some());
+}
+
+#[test]
+pub fn value_compression_shrinks_large_values() {
+    let config = test_config("value_compression_shrinks_large_values")
+        .storage(|s| {
+            s.value_compression(|c| c.algorithm(dustdata::CompressionAlgorithm::Zstd))
+                .flush_threshold(0)
+        })
+        .build();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("value_compression").unwrap();
+
+    let large = "{\"name\": \"dustdata\"}".repeat(500);
+
+    collection
+        .start_lazy(|t| {
+            t.insert("large", large.clone());
+            t.insert("small", "value".to_string());
+        })
+        .unwrap();
+
+    let chunk = std::fs::metadata(
+        "./test_data/value_compression_shrinks_large_values/value_compression/data/Data_0_0.db",
+    )
+    .unwrap();
+
+    assert!(chunk.len() < large.len() as u64 / 5);
+    assert_eq!(collection.get("large").unwrap().unwrap(), large);
+    assert_eq!(collection.get("small").unwrap().unwrap(), "value");
+}
+
+#[test]
+pub fn truncated_segment_is_reported_as_corrupted() {
+    let config = test_config("truncated_segment_is_reported_as_corrupted");
+    let chunk = config.data_path.join("truncated/data/Data_0_0.db");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("truncated").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("intact", "value".to_string()))
+        .unwrap();
+    let intact_len = std::fs::metadata(&chunk).unwrap().len();
+
+    collection
+        .start_lazy(|t| t.insert("torn", "value".repeat(10)))
+        .unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    // Keeps the length prefix of the second segment but drops most of its value.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&chunk)
+        .unwrap()
+        .set_len(intact_len + 10)
+        .unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("truncated").unwrap();
+
+    assert!(matches!(
+        collection.get("torn"),
+        Err(dustdata::error::Error::CorruptedData(_))
+    ));
+    assert_eq!(collection.get("intact").unwrap().unwrap(), "value");
+}
+
+#[test]
+pub fn failed_delete_keeps_the_key() {
+    let config = test_config("failed_delete_keeps_the_key");
+    let chunk = config.data_path.join("corrupted/data/Data_0_0.db");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("corrupted").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("key", "value".to_string()))
+        .unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    // Turns the value into invalid UTF-8, so it can't be decoded.
+    let mut bytes = std::fs::read(&chunk).unwrap();
+    *bytes.last_mut().unwrap() = 0xff;
+    std::fs::write(&chunk, bytes).unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("corrupted").unwrap();
+
+    assert!(matches!(
+        collection.delete_now("key"),
+        Err(dustdata::error::Error::CorruptedData(_))
+    ));
+    assert!(collection.contains_exact("key").unwrap());
+}
+
+#[test]
+pub fn rebuild_index_from_data_chunks() {
+    let config = test_config("rebuild_index_from_data_chunks");
+    let index_path = config.data_path.join("rebuild/data/.index-dustdata");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("rebuild").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("a", "1".to_string());
+            t.insert("b", "2".to_string());
+            t.insert("c", "3".to_string());
+        })
+        .unwrap();
+    collection
+        .start_lazy(|t| {
+            t.update("a", "4".to_string());
+            t.delete("b");
+        })
+        .unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    std::fs::write(&index_path, b"garbage").unwrap();
+    assert!(DustData::new(config.clone())
+        .unwrap()
+        .collection::<String>("rebuild")
+        .is_err());
+
+    std::fs::remove_file(&index_path).unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("rebuild").unwrap();
+
+    assert!(collection.get("a").unwrap().is_none());
+    assert_eq!(collection.rebuild_index().unwrap(), 2);
+
+    assert_eq!(collection.get("a").unwrap().unwrap(), "4");
+    assert!(collection.get("b").unwrap().is_none());
+    assert_eq!(collection.get("c").unwrap().unwrap(), "3");
+}
+
+#[test]
+pub fn old_segment_format_is_rejected() {
+    let config = test_config("old_segment_format_is_rejected");
+    let index_path = config.data_path.join("old_format/data/.index-dustdata");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("old_format").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("key", "value".to_string()))
+        .unwrap();
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    // Rewrites the header as the first index format, whose segments have no keys.
+    let bytes = std::fs::read(&index_path).unwrap();
+    let (container, index) = bytes.split_at(5);
+    assert_eq!(&index[..4], b"DIX2");
+
+    let mut old_bytes = container.to_vec();
+    old_bytes.extend_from_slice(b"DIX1");
+    old_bytes.extend_from_slice(&index[5..]);
+    std::fs::write(&index_path, old_bytes).unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+
+    assert!(matches!(
+        dustdata.collection::<String>("old_format"),
+        Err(dustdata::error::Error::VersionMismatch(_))
+    ));
+}
+
+#[test]
+pub fn check_integrity_reports_problems() {
+    let config = test_config("check_integrity_reports_problems");
+    let chunk = config.data_path.join("integrity/data/Data_0_0.db");
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("integrity").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("a", "1".to_string());
+            t.insert("b", "2".to_string());
+        })
+        .unwrap();
+    collection.start_lazy(|t| t.delete("a")).unwrap();
+    collection
+        .start_lazy(|t| t.insert("c", "3".to_string()))
+        .unwrap();
+
+    let report = collection.check_integrity().unwrap();
+
+    assert!(report.is_clean());
+    assert_eq!(report.stale_filter_keys, vec!["a".to_string()]);
+    assert_eq!(report.orphaned_segments.len(), 2); // "a" and its tombstone
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    let len = std::fs::metadata(&chunk).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&chunk)
+        .unwrap()
+        .set_len(len - 1)
+        .unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("integrity").unwrap();
+    let report = collection.check_integrity().unwrap();
+
+    assert!(!report.is_clean());
+    assert_eq!(report.out_of_bounds, vec!["c".to_string()]);
+}
+
+#[test]
+pub fn subscribe_receives_committed_changes() {
+    use dustdata::collection::ChangeEvent;
+
+    let dustdata = DustData::new(test_config("subscribe_receives_committed_changes")).unwrap();
+    let collection = dustdata.collection::<String>("subscribe").unwrap();
+
+    let events = collection.subscribe();
+    let dropped = collection.subscribe();
+    drop(dropped);
+
+    let mut transaction = collection.start();
+    transaction.insert("key", "value".to_string());
+
+    assert!(events.try_recv().is_err());
+
+    collection.commit(&mut transaction).unwrap();
+    collection
+        .start_lazy(|t| t.update("key", "new_value".to_string()))
+        .unwrap();
+
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ChangeEvent::Inserted { key, value } if key == "key" && value == "value"
+    ));
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ChangeEvent::Updated { old, new, .. } if old == "value" && new == "new_value"
+    ));
+    assert!(events.try_recv().is_err());
+}
+
+#[test]
+pub fn contains_exact_rejects_false_positives() {
+    // A loose filter makes collisions easy to find.
+    let mut config = test_config("contains_exact_rejects_false_positives");
+    config.storage(|s| s.bloom_fp_rate(0.5));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("contains_exact").unwrap();
+
+    let mut false_positive = None;
+
+    for i in 0..100 {
+        collection
+            .start_lazy(|t| t.insert(&format!("key:{}", i), i))
+            .unwrap();
+
+        false_positive = (0..1000)
+            .map(|j| format!("missing:{}", j))
+            .find(|key| collection.contains(key).unwrap());
+
+        if false_positive.is_some() {
+            break;
+        }
+    }
+
+    let false_positive = false_positive.expect("no bloom filter collision found");
+
+    assert!(!collection.contains_exact(&false_positive).unwrap());
+    assert!(collection.contains_exact("key:0").unwrap());
+}
+
+#[test]
+pub fn contains_many_checks_keys_in_order() {
+    let mut config = test_config("contains_many_checks_keys_in_order");
+    config.storage(|s| s.bloom_fp_rate(0.5));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("contains_many").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..50 {
+                t.insert(&format!("key:{}", i), i);
+            }
+        })
+        .unwrap();
+    collection.delete_now("key:7").unwrap();
+
+    let missing = (0..200)
+        .map(|i| format!("missing:{}", i))
+        .collect::<Vec<_>>();
+    let mut keys = vec!["key:0", "missing:0", "key:7", "key:49", "key:0"];
+    keys.extend(missing.iter().map(String::as_str));
+
+    let found = collection.contains_many(&keys).unwrap();
+
+    assert_eq!(found.len(), keys.len());
+    assert_eq!(&found[..5], &[true, false, false, true, true]);
+    assert!(found[5..].iter().all(|found| !found));
+    assert!(collection.contains_many(&[]).unwrap().is_empty());
+}
+
+#[test]
+pub fn delete_now_and_update_now_return_previous_values() {
+    let dustdata = DustData::new(test_config(
+        "delete_now_and_update_now_return_previous_values",
+    ))
+    .unwrap();
+    let collection = dustdata.collection::<usize>("now").unwrap();
+
+    assert_eq!(collection.update_now("counter", 1).unwrap(), None);
+    assert_eq!(collection.update_now("counter", 2).unwrap(), Some(1));
+
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    let update = collection.start_lazy(|t| t.update("counter", 3)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+
+    assert_eq!(collection.delete_now("counter").unwrap(), Some(3));
+    assert_eq!(collection.delete_now("counter").unwrap(), None);
+    assert!(collection.get("counter").unwrap().is_none());
+
+    // The eager operations are logged like batched ones.
+    collection.recover_to(update.id()).unwrap();
+    assert_eq!(collection.get("counter").unwrap().unwrap(), 3);
+}
+
+#[test]
+pub fn commit_waits_for_the_lock_timeout() {
+    use std::time::Duration;
+
+    let config = test_config("commit_waits_for_the_lock_timeout")
+        .lock_timeout(Duration::from_millis(100))
+        .build();
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("lock_timeout").unwrap();
+
+    let hold_lock = |duration| {
+        let wal = collection.wal.clone();
+        let (locked, is_locked) = std::sync::mpsc::channel();
+
+        let handle = std::thread::spawn(move || {
+            let _guard = wal.write();
+            locked.send(()).unwrap();
+            std::thread::sleep(duration);
+        });
+
+        is_locked.recv().unwrap();
+        handle
+    };
+
+    let handle = hold_lock(Duration::from_millis(20));
+    collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+    handle.join().unwrap();
+
+    let handle = hold_lock(Duration::from_millis(500));
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("b", 2)),
+        Err(dustdata::error::Error::Deadlock)
+    ));
+    assert!(matches!(
+        collection.dry_run_rollback(0),
+        Err(dustdata::error::Error::Deadlock)
+    ));
+    handle.join().unwrap();
+}
+
+#[test]
+pub fn bulk_insert_in_one_transaction() {
+    let dustdata = DustData::new(test_config("bulk_insert_in_one_transaction")).unwrap();
+    let collection = dustdata.collection::<usize>("bulk_insert").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..500 {
+                t.insert(&format!("key:{}", i), i);
+            }
+        })
+        .unwrap();
+
+    for i in 0..500 {
+        assert_eq!(collection.get(&format!("key:{}", i)).unwrap().unwrap(), i);
+    }
+
+    let result = collection.start_lazy(|t| {
+        t.insert("new", 1);
+        t.insert("new", 2);
+    });
+
+    assert!(matches!(
+        result,
+        Err(dustdata::error::Error::AlreadyExists(_))
+    ));
+    assert!(!collection.contains_exact("new").unwrap());
+}
+
+#[test]
+pub fn stale_lock_is_reclaimed() {
+    let config = test_config("stale_lock_is_reclaimed");
+    let lock_path = config.data_path.join(".dustdata-lock");
+
+    // A process that crashed leaves its owner behind, but not the flock.
+    std::fs::create_dir_all(&config.data_path).unwrap();
+    std::fs::write(&lock_path, "pid=0\nstarted_at=0\n").unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let owner = std::fs::read_to_string(&lock_path).unwrap();
+
+    assert!(owner.starts_with(&format!("pid={}\n", std::process::id())));
+
+    dustdata.close().unwrap();
+
+    assert!(std::fs::read_to_string(&lock_path).unwrap().is_empty());
+}
+
+#[test]
+pub fn lock_modes() {
+    use dustdata::LockMode;
+    use std::time::Duration;
+
+    let config = test_config("lock_modes");
+    let with_mode = |lock_mode| config.clone().lock_mode(lock_mode).build();
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    dustdata
+        .collection::<usize>("lock_modes")
+        .unwrap()
+        .start_lazy(|t| t.insert("key", 1))
+        .unwrap();
+
+    assert!(matches!(
+        DustData::new(with_mode(LockMode::ReadOnly)),
+        Err(dustdata::error::Error::DatabaseLocked)
+    ));
+
+    let handle = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        dustdata.close().unwrap();
+    });
+
+    let dustdata = DustData::new(with_mode(LockMode::Wait(Duration::from_secs(5)))).unwrap();
+    handle.join().unwrap();
+    dustdata.close().unwrap();
+
+    let reader = DustData::new(with_mode(LockMode::ReadOnly)).unwrap();
+    let other_reader = DustData::new(with_mode(LockMode::ReadOnly)).unwrap();
+
+    assert!(matches!(
+        DustData::new(config),
+        Err(dustdata::error::Error::DatabaseLocked)
+    ));
+
+    let collection = reader.collection::<usize>("lock_modes").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), 1);
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("other_key", 2)),
+        Err(dustdata::error::Error::ReadOnly)
+    ));
+
+    drop(other_reader);
+}
+
+#[test]
+pub fn read_only_open_rejects_mutations() {
+    fn snapshot(path: &std::path::Path, files: &mut Vec<(std::path::PathBuf, u64)>) {
+        for entry in std::fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap();
+
+            if entry.file_type().unwrap().is_dir() {
+                snapshot(&entry.path(), files);
+            } else {
+                files.push((entry.path(), entry.metadata().unwrap().len()));
+            }
+        }
+
+        files.sort();
+    }
+
+    let config = test_config("read_only_open_rejects_mutations");
+    let read_only = config
+        .clone()
+        .lock_mode(dustdata::LockMode::ReadOnly)
+        .build();
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    dustdata
+        .collection::<usize>("read_only")
+        .unwrap()
+        .start_lazy(|t| t.insert("key", 1))
+        .unwrap();
+    dustdata.close().unwrap();
+
+    let mut before = Vec::new();
+    snapshot(&config.data_path, &mut before);
+
+    let reader = DustData::new(read_only.clone()).unwrap();
+    let other_reader = DustData::new(read_only).unwrap();
+    let collection = reader.collection::<usize>("read_only").unwrap();
+    let other_collection = other_reader.collection::<usize>("read_only").unwrap();
+
+    assert_eq!(collection.get("key").unwrap().unwrap(), 1);
+    assert_eq!(other_collection.get("key").unwrap().unwrap(), 1);
+    assert!(collection.contains_exact("key").unwrap());
+
+    assert!(matches!(
+        collection.start_lazy(|t| t.delete("key")),
+        Err(dustdata::error::Error::ReadOnly)
+    ));
+    assert!(matches!(
+        collection.compact(),
+        Err(dustdata::error::Error::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.drop_collection("read_only"),
+        Err(dustdata::error::Error::ReadOnly)
+    ));
+    assert!(collection.start_lazy(|_| {}).is_ok());
+
+    drop((collection, other_collection));
+    reader.close().unwrap();
+    other_reader.close().unwrap();
+
+    let mut after = Vec::new();
+    snapshot(&config.data_path, &mut after);
+
+    assert_eq!(before, after);
+}
+
+#[test]
+pub fn stats_report_keys_and_sizes() {
+    let dustdata = DustData::new(test_config("stats_report_keys_and_sizes")).unwrap();
+    let collection = dustdata.collection::<String>("stats_collection").unwrap();
+
+    let empty = collection.stats().unwrap();
+    assert_eq!(empty.live_keys, 0);
+    assert!(collection.is_empty().unwrap());
+    assert_eq!(empty.data_chunk_bytes, 0);
+    assert_eq!(empty.wal_log_chunks, 1);
+
+    let mut transaction = collection.start();
+    transaction.insert("a", "first".to_string());
+    transaction.insert("b", "second".to_string());
+    collection.commit(&mut transaction).unwrap();
+
+    collection.delete_now("a").unwrap();
+    collection.flush().unwrap();
+
+    let stats = collection.stats().unwrap();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(collection.len().unwrap(), 1);
+    assert_eq!(collection.estimated_len(), 1);
+
+    let bloom_stats = collection.bloom_stats();
+    assert_eq!(bloom_stats.bytes as u64, stats.filter_bytes);
+    assert_eq!(bloom_stats.fp_rate, stats.filter_fp_rate);
+    assert!(bloom_stats.set_bits > 0);
+    assert!(bloom_stats.fill_ratio > 0.0 && bloom_stats.fill_ratio < 1.0);
+    assert_eq!(stats.data_chunks, 1);
+    assert!(stats.data_chunk_bytes > 0);
+    assert!(stats.dead_bytes > 0);
+    assert!(stats.index_bytes > 0);
+    assert!(stats.filter_bytes > 0);
+
+    collection.compact().unwrap();
+    assert_eq!(collection.stats().unwrap().dead_bytes, 0);
+}
+
+#[test]
+pub fn lower_bloom_fp_rate_uses_a_larger_filter() {
+    let dustdata = DustData::new(test_config("lower_bloom_fp_rate_uses_a_larger_filter")).unwrap();
+
+    let filter_bytes = |name: &str, fp_rate: f64| {
+        dustdata
+            .collection_with_config::<usize, _>(name, |config| {
+                config.storage(|s| s.bloom_fp_rate(fp_rate).bloom_capacity(10_000));
+            })
+            .unwrap()
+            .stats()
+            .unwrap()
+            .filter_bytes
+    };
+
+    assert!(filter_bytes("tight", 0.001) > filter_bytes("loose", 0.01));
+}
+
+#[test]
+pub fn bloom_filter_grows_with_the_collection() {
+    let dustdata = DustData::new(test_config("bloom_filter_grows_with_the_collection")).unwrap();
+    let collection = dustdata.collection::<usize>("growing").unwrap();
+
+    let before = collection.stats().unwrap();
+
+    let mut transaction = collection.start();
+    for i in 0..1000 {
+        transaction.insert(&format!("key:{}", i), i);
+    }
+    collection.commit(&mut transaction).unwrap();
+    collection.flush().unwrap();
+
+    let after = collection.stats().unwrap();
+
+    assert!(after.filter_bytes > before.filter_bytes);
+    assert!(after.filter_fp_rate < 0.01);
+    assert!(collection.contains("key:999").unwrap());
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct User {
+    email: String,
+    name: String,
+}
+
+#[test]
+pub fn secondary_index_follows_commits() {
+    let config = test_config("secondary_index_follows_commits");
+    let user = |email: &str, name: &str| User {
+        email: email.to_string(),
+        name: name.to_string(),
+    };
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<User>("users").unwrap();
+
+        collection
+            .start_lazy(|t| t.insert("user:1", user("a@example.com", "Ana")))
+            .unwrap();
+
+        collection
+            .create_index("email", |user: &User| user.email.clone())
+            .unwrap();
+        assert!(matches!(
+            collection.create_index("email", |user: &User| user.email.clone()),
+            Err(dustdata::error::Error::AlreadyExists(_))
+        ));
+
+        collection
+            .start_lazy(|t| {
+                t.insert("user:2", user("b@example.com", "Bruno"));
+                t.insert("user:3", user("b@example.com", "Bia"));
+            })
+            .unwrap();
+        collection
+            .start_lazy(|t| t.update("user:1", user("c@example.com", "Ana")))
+            .unwrap();
+        collection.delete_now("user:3").unwrap();
+
+        let found = collection
+            .find_by_index("email", &"b@example.com".to_string())
+            .unwrap();
+        assert_eq!(
+            found,
+            vec![("user:2".to_string(), user("b@example.com", "Bruno"))]
+        );
+        assert!(collection
+            .find_by_index("email", &"a@example.com".to_string())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            collection
+                .find_by_index("email", &"c@example.com".to_string())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(matches!(
+            collection.find_by_index("email", &1u32),
+            Err(dustdata::error::Error::Other(_))
+        ));
+        assert!(matches!(
+            collection.find_by_index("name", &"Ana".to_string()),
+            Err(dustdata::error::Error::NotFound(_))
+        ));
+
+        drop(collection);
+        dustdata.close().unwrap();
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<User>("users").unwrap();
+
+    collection
+        .create_index("email", |user: &User| user.email.clone())
+        .unwrap();
+
+    assert_eq!(
+        collection
+            .find_by_index("email", &"c@example.com".to_string())
+            .unwrap(),
+        vec![("user:1".to_string(), user("c@example.com", "Ana"))]
+    );
+}
+
+#[cfg(feature = "json")]
+#[test]
+pub fn ndjson_export_round_trips() {
+    let dustdata = DustData::new(test_config("ndjson_export_round_trips")).unwrap();
+    let source = dustdata.collection::<User>("source").unwrap();
+    let target = dustdata.collection::<User>("target").unwrap();
+
+    source
+        .start_lazy(|t| {
+            for i in 0..1500 {
+                t.insert(
+                    &format!("user:{:04}", i),
+                    User {
+                        email: format!("{}@example.com", i),
+                        name: format!("User {}", i),
+                    },
+                );
+            }
+        })
+        .unwrap();
+
+    let mut exported = Vec::new();
+    assert_eq!(source.export_ndjson(&mut exported).unwrap(), 1500);
+
+    let first_line = exported.split(|b| *b == b'\n').next().unwrap();
+    assert_eq!(
+        std::str::from_utf8(first_line).unwrap(),
+        r#"{"key":"user:0000","value":{"email":"0@example.com","name":"User 0"}}"#
+    );
+
+    assert_eq!(target.import_ndjson(exported.as_slice()).unwrap(), 1500);
+    assert_eq!(
+        target.get("user:1499").unwrap(),
+        source.get("user:1499").unwrap()
+    );
+
+    assert!(target.import_ndjson(&b"{\"key\": 1}\n"[..]).is_err());
+}
+
+#[test]
+pub fn bulk_loader_commits_in_batches() {
+    let dustdata = DustData::new(test_config("bulk_loader_commits_in_batches")).unwrap();
+    let collection = dustdata.collection::<usize>("bulk").unwrap();
+
+    collection.start_lazy(|t| t.insert("key:0003", 0)).unwrap();
+
+    let events = collection.subscribe();
+
+    let mut loader = collection.bulk_loader();
+    loader.batch_size(4);
+
+    for i in 0..10 {
+        loader.push(&format!("key:{:04}", i), i).unwrap();
+    }
+
+    // The first two batches are committed, the last one is still staged.
+    assert_eq!(events.try_iter().count(), 7);
+
+    loader.push("key:0009", 99).unwrap();
+
+    let report = loader.finish().unwrap();
+
+    assert_eq!(report.inserted, 9);
+    assert_eq!(report.skipped_duplicates, 2);
+    assert_eq!(collection.get("key:0003").unwrap(), Some(0));
+    assert_eq!(collection.get("key:0009").unwrap(), Some(9));
+}
+
+#[test]
+pub fn bulk_loader_rolls_back_committed_batches() {
+    let config = test_config("bulk_loader_rolls_back_committed_batches")
+        .lock_timeout(std::time::Duration::from_millis(50))
+        .build();
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("bulk").unwrap();
+
+    let mut loader = collection.bulk_loader();
+    loader
+        .batch_size(2)
+        .on_failure(dustdata::collection::OnFailure::RollbackAll);
+
+    loader.push("key:0", 0).unwrap();
+    loader.push("key:1", 1).unwrap();
+    assert!(collection.contains_exact("key:1").unwrap());
+
+    // Holding the write-ahead log lock elsewhere makes the next batch time out.
+    let (locked_sender, locked) = std::sync::mpsc::channel();
+    let holder = {
+        let collection = collection.clone();
+
+        std::thread::spawn(move || {
+            let _wal = collection.wal.write();
+            locked_sender.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        })
+    };
+
+    locked.recv().unwrap();
+
+    loader.push("key:2", 2).unwrap();
+    assert!(matches!(
+        loader.push("key:3", 3),
+        Err(dustdata::error::Error::Deadlock)
+    ));
+
+    holder.join().unwrap();
+
+    assert!(!collection.contains_exact("key:0").unwrap());
+    assert!(!collection.contains_exact("key:1").unwrap());
+    assert!(!collection.contains_exact("key:3").unwrap());
+}
+
+#[test]
+pub fn first_last_and_reverse_iteration() {
+    let dustdata = DustData::new(test_config("first_last_and_reverse_iteration")).unwrap();
+    let collection = dustdata.collection::<usize>("scores").unwrap();
+
+    assert!(collection.first().unwrap().is_none());
+    assert!(collection.last().unwrap().is_none());
+    assert!(collection.iter_rev().next().is_none());
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..20 {
+                t.insert(&format!("score:{:03}", i), i);
+            }
+        })
+        .unwrap();
+
+    assert_eq!(
+        collection.first().unwrap(),
+        Some(("score:000".to_string(), 0))
+    );
+    assert_eq!(
+        collection.last().unwrap(),
+        Some(("score:019".to_string(), 19))
+    );
+
+    let last_three = collection
+        .iter_rev()
+        .take(3)
+        .map(|entry| entry.unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(last_three, vec![19, 18, 17]);
+
+    let mut entries = collection.iter_rev();
+    assert_eq!(entries.next().unwrap().unwrap().1, 19);
+    collection.delete_now("score:018").unwrap();
+    assert_eq!(entries.next().unwrap().unwrap().1, 17);
+    assert_eq!(entries.count(), 17);
+}
+
+#[test]
+pub fn page_resumes_after_token() {
+    let dustdata = DustData::new(test_config("page_resumes_after_token")).unwrap();
+    let collection = dustdata.collection::<usize>("pages").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..5 {
+                t.insert(&format!("item:{}", i), i);
+            }
+        })
+        .unwrap();
+
+    let first = collection.page(None, 2).unwrap();
+    assert_eq!(
+        first.entries,
+        vec![("item:0".to_string(), 0), ("item:1".to_string(), 1)]
+    );
+    assert_eq!(first.next.as_deref(), Some("item:1"));
+
+    // A key inserted before the cursor and a deleted cursor don't affect the next page.
+    collection.start_lazy(|t| t.insert("item:00", 99)).unwrap();
+    collection.delete_now("item:1").unwrap();
+
+    let second = collection.page(first.next.as_deref(), 2).unwrap();
+    assert_eq!(
+        second.entries,
+        vec![("item:2".to_string(), 2), ("item:3".to_string(), 3)]
+    );
+
+    let last = collection.page(second.next.as_deref(), 2).unwrap();
+    assert_eq!(last.entries, vec![("item:4".to_string(), 4)]);
+    assert_eq!(last.next, None);
+}
+
+#[test]
+pub fn raw_collection_stores_bytes() {
+    let dustdata = DustData::new(test_config("raw_collection_stores_bytes")).unwrap();
+    let blobs = dustdata.raw_collection("blobs").unwrap();
+
+    blobs.insert("blob:1", &[0x08, 0x96, 0x01]).unwrap();
+
+    // The value follows its version, without the length prefix of bincode.
+    let chunk = dustdata.config().data_path.join("blobs/data/Data_0_0.db");
+    assert!(std::fs::read(chunk)
+        .unwrap()
+        .ends_with(&[1, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x96, 0x01]));
+
+    let mut streamed = blobs.collection().get_streaming("blob:1").unwrap().unwrap();
+    let streamed: Vec<u8> = dustdata::bincode::deserialize_from(&mut streamed).unwrap();
+    assert_eq!(streamed, vec![0x08, 0x96, 0x01]);
+
+    assert!(matches!(
+        blobs.insert("blob:1", &[]),
+        Err(dustdata::error::Error::AlreadyExists(_))
+    ));
+
+    assert_eq!(blobs.get("blob:1").unwrap(), Some(vec![0x08, 0x96, 0x01]));
+    assert_eq!(
+        blobs.update("blob:1", &[0xff]).unwrap(),
+        Some(vec![0x08, 0x96, 0x01])
+    );
+
+    let typed = dustdata.collection::<Vec<u8>>("blobs").unwrap();
+    assert_eq!(typed.get("blob:1").unwrap(), Some(vec![0xff]));
+
+    assert_eq!(blobs.delete("blob:1").unwrap(), Some(vec![0xff]));
+    assert_eq!(blobs.get("blob:1").unwrap(), None);
+}
+
+#[test]
+pub fn transaction_size_limit_is_enforced() {
+    let config = test_config("transaction_size_limit_is_enforced")
+        .max_transaction_ops(3)
+        .build();
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("limited").unwrap();
+
+    let mut transaction = collection.start();
+    let operations = (0..4)
+        .map(|i| dustdata::collection::Operation::Insert(format!("key:{}", i), i))
+        .collect::<Vec<_>>();
+
+    assert!(matches!(
+        transaction.try_extend(operations.clone()),
+        Err(dustdata::error::Error::TransactionTooLarge(3))
+    ));
+    transaction.try_extend(operations[..3].to_vec()).unwrap();
+    collection.commit(&mut transaction).unwrap();
+
+    let mut transaction = collection.start();
+    transaction.extend(operations);
+    assert!(matches!(
+        collection.commit(&mut transaction),
+        Err(dustdata::error::Error::TransactionTooLarge(3))
+    ));
+    assert!(!collection.contains_exact("key:3").unwrap());
+
+    let mut loader = collection.bulk_loader();
+    for i in 10..20 {
+        loader.push(&format!("key:{}", i), i).unwrap();
+    }
+    assert_eq!(loader.finish().unwrap().inserted, 10);
+}
+
+#[test]
+pub fn wal_since_returns_later_transactions() {
+    let dustdata = DustData::new(test_config("wal_since_returns_later_transactions")).unwrap();
+    let collection = dustdata.collection::<usize>("leader").unwrap();
+
+    assert_eq!(collection.wal_head(), None);
+
+    let first = collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    let second = collection
+        .start_lazy(|t| {
+            t.insert("b", 2);
+            t.update("a", 3);
+        })
+        .unwrap();
+
+    assert_eq!(collection.wal_head(), Some(second.id()));
+    assert_eq!(collection.wal_since(0).unwrap().len(), 2);
+
+    let logs = collection.wal_since(first.id()).unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].id, second.id());
+    assert!(matches!(
+        &logs[0].data[1],
+        dustdata::collection::WalOperation::Update { key, new_value: 3, old_value: 1 } if key == "a"
+    ));
+
+    assert!(collection.wal_since(second.id()).unwrap().is_empty());
+}
+
+#[test]
+pub fn transaction_ids_are_unique_and_ordered() {
+    let dustdata = DustData::new(test_config("transaction_ids_are_unique_and_ordered")).unwrap();
+    let collection = dustdata.collection::<usize>("ids").unwrap();
+
+    let mut ids = Vec::new();
+
+    for i in 0..500 {
+        let transaction = collection
+            .start_lazy(|t| t.insert(&format!("key:{}", i), i))
+            .unwrap();
+
+        assert!(transaction.id() >= transaction.timestamp());
+        ids.push(transaction.id());
+    }
+
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(collection.wal_since(0).unwrap().len(), 500);
+    assert_eq!(collection.wal_head(), ids.last().copied());
+
+    // Transactions started together still commit under distinct ids.
+    let mut first = collection.start();
+    let mut second = collection.start();
+    first.insert("first", 1);
+    second.insert("second", 2);
+    collection.commit(&mut second).unwrap();
+    collection.commit(&mut first).unwrap();
+
+    assert!(second.id() < first.id());
+}
+
+#[test]
+pub fn apply_wal_replicates_transactions() {
+    let leader = DustData::new(test_config("apply_wal_replicates_transactions/leader")).unwrap();
+    let follower =
+        DustData::new(test_config("apply_wal_replicates_transactions/follower")).unwrap();
+
+    let primary = leader.collection::<usize>("items").unwrap();
+    let replica = follower.collection::<usize>("items").unwrap();
+
+    primary
+        .start_lazy(|t| {
+            t.insert("a", 1);
+            t.insert("b", 2);
+        })
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    primary.start_lazy(|t| t.update("a", 10)).unwrap();
+
+    let logs = primary.wal_since(0).unwrap();
+    replica.apply_wal(logs.clone()).unwrap();
+
+    assert_eq!(replica.get("a").unwrap(), Some(10));
+    assert_eq!(replica.get("b").unwrap(), Some(2));
+    assert_eq!(replica.wal_head(), primary.wal_head());
+
+    // Applying the same logs again is a no-op.
+    replica.apply_wal(logs.clone()).unwrap();
+    assert_eq!(replica.get("a").unwrap(), Some(10));
+
+    // Another transaction under a logged id isn't mistaken for it.
+    let mut other = logs[0].clone();
+    other.data = vec![dustdata::collection::WalOperation::Insert {
+        key: "c".to_string(),
+        value: 3,
+    }];
+    assert!(matches!(
+        replica.apply_wal(vec![other]),
+        Err(dustdata::error::Error::CorruptedData(_))
+    ));
+    assert_eq!(replica.get("c").unwrap(), None);
+
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    primary.delete_now("b").unwrap();
+
+    let since = replica.wal_head().unwrap();
+    replica
+        .apply_wal(primary.wal_since(since).unwrap())
+        .unwrap();
+
+    assert_eq!(replica.get("b").unwrap(), None);
+}
+
+#[test]
+pub fn transaction_status_is_queryable() {
+    use dustdata::collection::TransactionStatus;
+
+    let dustdata = DustData::new(test_config("transaction_status_is_queryable")).unwrap();
+    let collection = dustdata.collection::<usize>("status").unwrap();
+
+    let mut transaction = collection.start();
+    transaction.insert("a", 1);
+    assert!(transaction.is_active());
+
+    collection.commit(&mut transaction).unwrap();
+    assert!(transaction.is_committed());
+    assert_eq!(transaction.status(), &TransactionStatus::Committed);
+
+    collection.rollback_transaction(&mut transaction).unwrap();
+    assert!(transaction.is_rolled_back());
+
+    let mut aborted = collection.start();
+    collection.abort_transaction(&mut aborted).unwrap();
+    assert!(aborted.is_aborted());
+    assert!(!aborted.is_active());
+}
+
+#[test]
+pub fn insert_auto_generates_ordered_keys() {
+    let mut config = test_config("insert_auto_generates_ordered_keys");
+    config.storage(|s| s.auto_key_width(6));
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("log").unwrap();
+
+        let handles = (0..4)
+            .map(|i| {
+                let collection = collection.clone();
+                std::thread::spawn(move || {
+                    (0..5)
+                        .map(|j| collection.insert_auto(i * 5 + j).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut keys = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        assert_eq!(keys.len(), 20);
+        assert_eq!(keys[0], "000001");
+        assert_eq!(keys[19], "000020");
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("log").unwrap();
+
+    assert_eq!(collection.insert_auto(0).unwrap(), "000021");
+}
+
+#[test]
+pub fn large_values_are_stored_as_blobs() {
+    let mut config = test_config("large_values_are_stored_as_blobs");
+    config.storage(|s| s.blob_threshold(1024));
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<Vec<u8>>("blobs").unwrap();
+    let blobs_path = config.data_path.join("blobs/data/blobs");
+    let blob_count = || std::fs::read_dir(&blobs_path).map_or(0, |dir| dir.count());
+
+    let big = vec![7u8; 64 * 1024];
+
+    collection
+        .start_lazy(|t| {
+            t.insert("small", vec![1, 2, 3]);
+            t.insert("big", big.clone());
+        })
+        .unwrap();
+
+    assert_eq!(blob_count(), 1);
+    assert!(collection.stats().unwrap().data_chunk_bytes < 1024);
+    assert_eq!(collection.get("big").unwrap(), Some(big.clone()));
+    assert_eq!(collection.get("small").unwrap(), Some(vec![1, 2, 3]));
+
+    collection.update_now("big", vec![8u8; 32 * 1024]).unwrap();
+    assert_eq!(blob_count(), 2);
+    assert!(collection.check_integrity().unwrap().is_clean());
+
+    collection.delete_now("big").unwrap();
+    collection.compact().unwrap();
+
+    assert_eq!(blob_count(), 0);
+    assert_eq!(collection.get("small").unwrap(), Some(vec![1, 2, 3]));
+}
+
+#[test]
+pub fn reopen_after_drop_keeps_the_next_instance_writes() {
+    let config = test_config("reopen_after_drop_keeps_the_next_instance_writes")
+        .compaction(|c| {
+            c.interval(std::time::Duration::from_millis(200))
+                .dead_ratio_threshold(0.0)
+        })
+        .build();
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<usize>("reopen").unwrap();
+
+    for i in 0..20 {
+        collection.update_now("key", i).unwrap();
+    }
+
+    // The handle outlives the instance, its background compaction must still stop
+    // before the next instance takes the lock.
+    let stale = collection.clone();
+    drop((collection, dustdata));
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("reopen").unwrap();
+
+        collection.update_now("next", 1).unwrap();
+    }
+
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    assert!(stale.compaction_stats().is_none());
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("reopen").unwrap();
+
+    assert_eq!(collection.get("key").unwrap(), Some(19));
+    assert_eq!(collection.get("next").unwrap(), Some(1));
+}
+
+#[test]
+pub fn flush_all_persists_every_collection() {
+    let dustdata = DustData::new(test_config("flush_all_persists_every_collection")).unwrap();
+    let users = dustdata.collection::<usize>("users").unwrap();
+    let orders = dustdata.collection::<usize>("orders").unwrap();
+
+    let before = (
+        users.stats().unwrap().index_bytes,
+        orders.stats().unwrap().index_bytes,
+    );
+
+    users.start_lazy(|t| t.insert("user:1", 1)).unwrap();
+    orders.start_lazy(|t| t.insert("order:1", 1)).unwrap();
+
+    dustdata.flush_all().unwrap();
+
+    assert!(users.stats().unwrap().index_bytes > before.0);
+    assert!(orders.stats().unwrap().index_bytes > before.1);
+}
+
+#[test]
+pub fn drop_flushes_before_unlocking() {
+    let config = test_config("drop_flushes_before_unlocking");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<String>("users").unwrap();
+
+        collection
+            .start_lazy(|t| t.insert("user:1", "Pedro".to_string()))
+            .unwrap();
+
+        // The handle outlives the instance, the instance still persists it on drop.
+        std::mem::forget(collection);
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("users").unwrap();
+
+    assert_eq!(collection.get("user:1").unwrap(), Some("Pedro".to_string()));
+}
+
+#[test]
+pub fn clear_prefix_keeps_other_prefixes() {
+    let dustdata = DustData::new(test_config("clear_prefix_keeps_other_prefixes")).unwrap();
+    let collection = dustdata.collection::<usize>("tenants").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..3 {
+                t.insert(&format!("tenant1:{}", i), i);
+                t.insert(&format!("tenant2:{}", i), i);
+            }
+            t.insert("tenant10:0", 0);
+        })
+        .unwrap();
+
+    let mut transaction = collection.start();
+    transaction.clear_prefix("tenant1:");
+    assert_eq!(transaction.get("tenant1:0"), None);
+    collection.commit(&mut transaction).unwrap();
+
+    assert!(!collection.contains_exact("tenant1:0").unwrap());
+    assert!(!collection.contains_exact("tenant1:2").unwrap());
+    assert_eq!(collection.get("tenant10:0").unwrap(), Some(0));
+    assert_eq!(collection.get("tenant2:1").unwrap(), Some(1));
+
+    collection.rollback_transaction(&mut transaction).unwrap();
+    assert_eq!(collection.get("tenant1:2").unwrap(), Some(2));
+}
+
+#[test]
+pub fn collection_config_can_be_overridden() {
+    let config = test_config("collection_config_can_be_overridden");
+    let dustdata = DustData::new(config.clone()).unwrap();
+
+    let plain = dustdata.collection::<String>("plain").unwrap();
+    let compressed = dustdata
+        .collection_with_config::<String, _>("compressed", |config| {
+            config
+                .data_path("./elsewhere")
+                .storage(|s| s.value_compression(|c| c).min_compress_size(0));
+        })
+        .unwrap();
+
+    let value = "a".repeat(4096);
+    plain
+        .start_lazy(|t| t.insert("key", value.clone()))
+        .unwrap();
+    compressed
+        .start_lazy(|t| t.insert("key", value.clone()))
+        .unwrap();
+
+    assert!(config.data_path.join("compressed").is_dir());
+    assert!(!std::path::Path::new("./elsewhere").exists());
+    assert_eq!(compressed.get("key").unwrap(), Some(value));
+    assert!(compressed.stats().unwrap().data_chunk_bytes < plain.stats().unwrap().data_chunk_bytes);
+
+    assert!(dustdata
+        .collection_with_config::<String, _>("invalid", |config| {
+            config.storage(|s| s.max_data_chunks(0));
+        })
+        .is_err());
+}
+
+#[test]
+pub fn metrics_count_operations() {
+    let dustdata = DustData::new(test_config("metrics_count_operations")).unwrap();
+    let collection = dustdata.collection::<usize>("metrics").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("a", 1);
+            t.insert("b", 2);
+            t.update("a", 3);
+        })
+        .unwrap();
+    collection.delete_now("b").unwrap();
+
+    collection.get("a").unwrap();
+    collection.get("b").unwrap();
+    collection.flush().unwrap();
+    collection.compact().unwrap();
+
+    let metrics = collection.clone().metrics();
+
+    assert_eq!(metrics.inserts, 2);
+    assert_eq!(metrics.updates, 1);
+    assert_eq!(metrics.deletes, 1);
+    assert_eq!(metrics.gets, 2);
+    assert_eq!(metrics.cache_hits, 1);
+    assert_eq!(metrics.cache_misses, 1); // the bloom filter still holds the deleted key
+    assert!(metrics.bytes_written > 0);
+    assert_eq!(metrics.flushes, 1);
+    assert_eq!(metrics.compactions, 1);
+}
+
+#[test]
+pub fn invalid_keys_are_rejected() {
+    let dustdata = DustData::new(test_config("invalid_keys_are_rejected")).unwrap();
+    let collection = dustdata.collection::<usize>("keys").unwrap();
+
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("", 1)),
+        Err(dustdata::error::Error::Other(_))
+    ));
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert(&"k".repeat(1025), 1)),
+        Err(dustdata::error::Error::Other(_))
+    ));
+    assert!(collection
+        .start_lazy(|t| t.insert(&"k".repeat(1024), 1))
+        .is_ok());
+
+    let config = test_config("invalid_keys_are_rejected_relaxed")
+        .key_constraints(|k| k.allow_empty(true).max_len(None))
+        .build();
+    let relaxed = DustData::new(config).unwrap();
+    let collection = relaxed.collection::<usize>("keys").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("", 1);
+            t.insert(&"k".repeat(4096), 2);
+        })
+        .unwrap();
+    assert_eq!(collection.get("").unwrap(), Some(1));
+}
+
+#[test]
+pub fn reserved_keys_are_rejected() {
+    type Collection = dustdata::Collection<usize>;
+
+    let dustdata = DustData::new(test_config("reserved_keys_are_rejected")).unwrap();
+    let collection = dustdata.collection::<usize>("reserved").unwrap();
+
+    assert!(Collection::is_reserved_key("__dustdata_seq"));
+    assert!(!Collection::is_reserved_key("__seq__"));
+
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("__dustdata_seq", 1)),
+        Err(dustdata::error::Error::Other(_))
+    ));
+    assert!(matches!(
+        collection.update_now("__dustdata_seq", 1),
+        Err(dustdata::error::Error::Other(_))
+    ));
+    assert!(collection.get("__dustdata_seq").unwrap().is_none());
+}
+
+#[test]
+pub fn group_commit_logs_concurrent_commits_in_order() {
+    let mut config = test_config("group_commit_logs_concurrent_commits_in_order");
+    config.wal(|w| w.group_commit(true));
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("group").unwrap();
+        let events = collection.subscribe();
+
+        let handles = (0..8)
+            .map(|i| {
+                let collection = collection.clone();
+                std::thread::spawn(move || {
+                    for j in 0..5 {
+                        collection.insert_auto(i * 5 + j).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Events wait for their logs to be synced, and are still sent in commit order.
+        let keys = events
+            .try_iter()
+            .map(|event| match event {
+                dustdata::collection::ChangeEvent::Inserted { key, .. } => key,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        assert_eq!(keys.len(), 40);
+        assert_eq!(keys, sorted);
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("group").unwrap();
+
+    let logs = collection.wal_since(0).unwrap();
+    assert_eq!(logs.len(), 40);
+
+    // Transactions are logged in the order they were committed, so their keys follow the sequence.
+    let keys = logs
+        .iter()
+        .map(|log| match &log.data[..] {
+            [dustdata::collection::WalOperation::Insert { key, .. }] => key.clone(),
+            data => panic!("unexpected operations {:?}", data),
+        })
+        .collect::<Vec<_>>();
+    let mut sorted = keys.clone();
+    sorted.sort();
+
+    assert_eq!(keys, sorted);
+
+    collection
+        .recover_to(collection.wal_head().unwrap())
+        .unwrap();
+    assert_eq!(collection.stats().unwrap().live_keys, 40);
+}
+
+#[test]
+pub fn multi_transaction_commits_across_collections() {
+    let dustdata =
+        DustData::new(test_config("multi_transaction_commits_across_collections")).unwrap();
+
+    let mut transaction = dustdata.transaction();
+    transaction
+        .collection::<String>("users")
+        .unwrap()
+        .insert("user:1", "Pedro".to_string());
+    transaction
+        .collection::<usize>("audit")
+        .unwrap()
+        .insert("entry:1", 1);
+    transaction.commit().unwrap();
+
+    let users = dustdata.collection::<String>("users").unwrap();
+    let audit = dustdata.collection::<usize>("audit").unwrap();
+
+    assert_eq!(users.get("user:1").unwrap().unwrap(), "Pedro");
+    assert_eq!(audit.get("entry:1").unwrap().unwrap(), 1);
+    assert_eq!(users.wal_head(), Some(transaction.id()));
+    assert_eq!(audit.wal_head(), Some(transaction.id()));
+
+    assert!(matches!(
+        transaction.commit(),
+        Err(dustdata::error::Error::TransactionAlreadyCommitted)
+    ));
+    assert!(dustdata.transaction().collection::<usize>("users").is_err());
+}
+
+#[test]
+pub fn multi_transaction_reverts_every_collection_on_failure() {
+    let dustdata = DustData::new(test_config(
+        "multi_transaction_reverts_every_collection_on_failure",
+    ))
+    .unwrap();
+
+    let users = dustdata.collection::<String>("users").unwrap();
+    let audit = dustdata.collection::<usize>("audit").unwrap();
+
+    users
+        .start_lazy(|t| t.insert("user:1", "Pedro".to_string()))
+        .unwrap();
+
+    // "audit" commits first, then "users" fails on the existing key.
+    let mut transaction = dustdata.transaction();
+    transaction
+        .collection::<usize>("audit")
+        .unwrap()
+        .insert("entry:1", 1);
+    transaction
+        .collection::<String>("users")
+        .unwrap()
+        .insert("user:1", "João".to_string());
+
+    assert!(matches!(
+        transaction.commit(),
+        Err(dustdata::error::Error::AlreadyExists(_))
+    ));
+
+    assert!(audit.get("entry:1").unwrap().is_none());
+    assert_eq!(users.get("user:1").unwrap().unwrap(), "Pedro");
+}
+
+#[test]
+pub fn multi_transaction_cut_short_is_discarded_on_open() {
+    let config = test_config("multi_transaction_cut_short_is_discarded_on_open");
+    let intents = config.data_path.join(".dustdata-transactions");
+
+    let tx_id = {
+        let dustdata = DustData::new(config.clone()).unwrap();
+
+        let mut transaction = dustdata.transaction();
+        transaction
+            .collection::<usize>("users")
+            .unwrap()
+            .insert("user:1", 1);
+        transaction
+            .collection::<usize>("audit")
+            .unwrap()
+            .insert("entry:1", 1);
+        transaction.commit().unwrap();
+
+        assert_eq!(std::fs::read_dir(&intents).unwrap().count(), 0);
+
+        transaction.id()
+    };
+
+    // Leaves the intent a crash before the end of the commit would have left.
+    let logs = vec![
+        config.data_path.join("users/log"),
+        config.data_path.join("audit/log"),
+    ];
+    std::fs::write(
+        intents.join("0.intent"),
+        dustdata::bincode::serialize(&(tx_id, logs)).unwrap(),
+    )
+    .unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+
+    for name in ["users", "audit"] {
+        let collection = dustdata.collection::<usize>(name).unwrap();
+
+        assert!(collection
+            .wal_since(0)
+            .unwrap()
+            .iter()
+            .all(|log| log.id != tx_id));
+    }
+
+    assert_eq!(std::fs::read_dir(&intents).unwrap().count(), 0);
+}
+
+#[test]
+pub fn get_streaming_reads_values_without_loading_them() {
+    let dustdata = DustData::new(test_config(
+        "get_streaming_reads_values_without_loading_them",
+    ))
+    .unwrap();
+
+    let document = (0..100_000).map(|i| i as u8).collect::<Vec<u8>>();
+
+    for name in ["raw", "compressed", "blob"] {
+        let collection = dustdata
+            .collection_with_config::<Vec<u8>, _>(name, |config| {
+                config.storage(|s| match name {
+                    "compressed" => {
+                        s.value_compression(|c| c.algorithm(dustdata::CompressionAlgorithm::Zstd))
+                    }
+                    "blob" => s.blob_threshold(1024),
+                    _ => s,
+                });
+            })
+            .unwrap();
+
+        collection
+            .start_lazy(|t| t.insert("document", document.clone()))
+            .unwrap();
+
+        let reader = collection.get_streaming("document").unwrap().unwrap();
+        let streamed: Vec<u8> = dustdata::bincode::deserialize_from(reader).unwrap();
+
+        assert_eq!(streamed, document, "{}", name);
+        assert!(collection.get_streaming("missing").unwrap().is_none());
+    }
+}
+
+#[test]
+pub fn newer_segment_format_is_rejected() {
+    let config = test_config("newer_segment_format_is_rejected");
+    let index_path = config.data_path.join("future/data/.index-dustdata");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("future").unwrap();
+        collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+    }
+
+    // Bumps the segment format version that follows the index magic.
+    let mut bytes = std::fs::read(&index_path).unwrap();
+    let version = bytes.windows(4).position(|w| w == b"DIX2").unwrap() + 4;
+    bytes[version] = u8::MAX;
+    std::fs::write(&index_path, &bytes).unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+
+    assert!(matches!(
+        dustdata.collection::<usize>("future"),
+        Err(dustdata::error::Error::VersionMismatch(_))
+    ));
+    assert_eq!(std::fs::read(&index_path).unwrap(), bytes);
+}
+
+#[test]
+pub fn collection_quota_rejects_writes_past_the_limit() {
+    let mut config = test_config("collection_quota_rejects_writes_past_the_limit");
+    config.storage(|s| s.max_collection_bytes(256));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("quota").unwrap();
+
+    let value = "v".repeat(64);
+    let mut inserted = 0;
+
+    while collection
+        .start_lazy(|t| t.insert(&format!("key:{}", inserted), value.clone()))
+        .is_ok()
+    {
+        inserted += 1;
+    }
+
+    assert!(inserted > 0);
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("key:next", value.clone())),
+        Err(dustdata::error::Error::QuotaExceeded(_))
+    ));
+    assert!(matches!(
+        collection.update_now("key:0", value.clone()),
+        Err(dustdata::error::Error::QuotaExceeded(_))
+    ));
+
+    // Deletes are always accepted, and compaction frees the space they held.
+    collection.delete_now("key:0").unwrap();
+    assert!(matches!(
+        collection.start_lazy(|t| t.insert("key:next", value.clone())),
+        Err(dustdata::error::Error::QuotaExceeded(_))
+    ));
+    collection.compact().unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("key:next", value.clone()))
+        .unwrap();
+}
+
+#[test]
+pub fn chunk_utilization_stays_within_one() {
+    let mut config = test_config("chunk_utilization_stays_within_one");
+    config.storage(|s| s.max_data_chunk_size(16).max_data_chunks(100));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("utilization").unwrap();
+
+    // Both values are larger than a data chunk.
+    collection
+        .start_lazy(|t| {
+            t.insert("a", "v".repeat(64));
+            t.insert("b", "v".repeat(64));
+        })
+        .unwrap();
+    collection
+        .start_lazy(|t| t.insert("c", "v".to_string()))
+        .unwrap();
+
+    let utilization = collection.stats().unwrap().chunk_utilization;
+    assert!((0.0..=1.0).contains(&utilization), "{}", utilization);
+}
+
+#[test]
+pub fn filter_scans_every_value() {
+    let mut config = test_config("filter_scans_every_value");
+    config.storage(|s| s.max_data_chunk_size(256).max_data_chunks(100));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("filter").unwrap();
+
+    for i in 0..20 {
+        collection
+            .start_lazy(|t| t.insert(&format!("key:{:02}", i), i))
+            .unwrap();
+    }
+    collection.update_now("key:00", 100).unwrap();
+    collection.delete_now("key:02").unwrap();
+
+    // Compaction splits the values across several data chunks.
+    collection.compact().unwrap();
+
+    let even = collection.filter(|value| value % 2 == 0).unwrap();
+
+    assert_eq!(even.len(), 9);
+    assert_eq!(even[0], ("key:00".to_string(), 100));
+    assert_eq!(even[1], ("key:04".to_string(), 4));
+    assert!(collection.stats().unwrap().data_chunks > 1);
+
+    assert_eq!(collection.count_where(|value| *value >= 10).unwrap(), 11);
+    assert_eq!(collection.count_where(|_| false).unwrap(), 0);
+}
+
+#[test]
+pub fn clone_collection_copies_the_data() {
+    let dustdata = DustData::new(test_config("clone_collection_copies_the_data")).unwrap();
+
+    let source = dustdata.collection::<usize>("source").unwrap();
+    source
+        .start_lazy(|t| {
+            t.insert("a", 1);
+            t.insert("b", 2);
+        })
+        .unwrap();
+
+    dustdata.clone_collection("source", "branch").unwrap();
+
+    let branch = dustdata.collection::<usize>("branch").unwrap();
+
+    assert_eq!(branch.get("a").unwrap(), Some(1));
+    assert_eq!(branch.get("b").unwrap(), Some(2));
+    assert_eq!(branch.wal_head(), None);
+
+    // The branch is independent of the source.
+    branch.update_now("a", 10).unwrap();
+    assert_eq!(source.get("a").unwrap(), Some(1));
+
+    assert!(matches!(
+        dustdata.clone_collection("source", "branch"),
+        Err(dustdata::error::Error::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        dustdata.clone_collection("missing", "other"),
+        Err(dustdata::error::Error::NotFound(_))
+    ));
+}
+
+#[test]
+pub fn coalesce_merges_underfull_chunks() {
+    let config = test_config("coalesce_merges_underfull_chunks");
+
+    {
+        let mut config = config.clone();
+        config.storage(|s| s.max_data_chunk_size(64).max_data_chunks(100));
+
+        let dustdata = DustData::new(config).unwrap();
+        let collection = dustdata.collection::<usize>("coalesce").unwrap();
+
+        for i in 0..10 {
+            collection
+                .start_lazy(|t| t.insert(&format!("key:{}", i), i))
+                .unwrap();
+        }
+        collection.delete_now("key:0").unwrap();
+
+        // Compaction with tiny chunks leaves one file per few values.
+        collection.compact().unwrap();
+        assert!(collection.stats().unwrap().data_chunks > 2);
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("coalesce").unwrap();
+
+    let before = collection.stats().unwrap();
+    assert!(before.chunk_utilization < 0.5);
+
+    let stats = collection.coalesce().unwrap();
+    assert_eq!(stats.live_segments, 9);
+
+    let after = collection.stats().unwrap();
+    assert_eq!(after.data_chunks, 1);
+    assert!(after.chunk_utilization > before.chunk_utilization);
+
+    collection.start_lazy(|t| t.insert("key:10", 10)).unwrap();
+
+    for i in 1..=10 {
+        assert_eq!(collection.get(&format!("key:{}", i)).unwrap(), Some(i));
+    }
+    assert_eq!(collection.get("key:0").unwrap(), None);
+
+    assert_eq!(collection.rebuild_index().unwrap(), 10);
+    assert_eq!(collection.get("key:10").unwrap(), Some(10));
+    assert_eq!(collection.coalesce().unwrap().live_segments, 0);
+}
+
+#[test]
+pub fn failed_commit_keeps_versions() {
+    let dustdata = DustData::new(test_config("failed_commit_keeps_versions")).unwrap();
+    let collection = dustdata.collection::<usize>("versions").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("a", 1);
+            t.insert("b", 2);
+        })
+        .unwrap();
+    collection.update_now("b", 20).unwrap();
+
+    let (_, a_version) = collection.get_versioned("a").unwrap().unwrap();
+    let (_, b_version) = collection.get_versioned("b").unwrap().unwrap();
+
+    // The insert of an existing key fails once the update and the delete are applied.
+    assert!(matches!(
+        collection.start_lazy(|t| {
+            t.update("a", 10);
+            t.delete("b");
+            t.insert("a", 100);
+        }),
+        Err(dustdata::error::Error::AlreadyExists(_))
+    ));
+
+    assert_eq!(
+        collection.get_versioned("b").unwrap(),
+        Some((20, b_version))
+    );
+    assert_eq!(
+        collection.update_if_version("a", 11, a_version).unwrap(),
+        a_version + 1
+    );
+}
+
+#[test]
+pub fn versions_guard_conditional_updates() {
+    let config = test_config("versions_guard_conditional_updates");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<String>("versions").unwrap();
+
+        collection
+            .start_lazy(|t| t.insert("key", "first".to_string()))
+            .unwrap();
+        assert_eq!(
+            collection.get_versioned("key").unwrap(),
+            Some(("first".to_string(), 1))
+        );
+
+        collection.update_now("key", "second".to_string()).unwrap();
+        assert_eq!(
+            collection
+                .update_if_version("key", "third".to_string(), 2)
+                .unwrap(),
+            3
+        );
+
+        assert!(matches!(
+            collection.update_if_version("key", "stale".to_string(), 2),
+            Err(dustdata::error::Error::VersionConflict(3))
+        ));
+        assert!(matches!(
+            collection.update_if_version("missing", "value".to_string(), 1),
+            Err(dustdata::error::Error::NotFound(_))
+        ));
+
+        collection.compact().unwrap();
+    }
+
+    // Versions are stored with the values, so they survive a restart.
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("versions").unwrap();
+
+    assert_eq!(
+        collection.get_versioned("key").unwrap(),
+        Some(("third".to_string(), 3))
+    );
+    assert_eq!(collection.get("key").unwrap(), Some("third".to_string()));
+    assert_eq!(collection.get_versioned("missing").unwrap(), None);
+}
+
+#[test]
+pub fn failed_commit_leaves_nothing_behind() {
+    let config = test_config("failed_commit_leaves_nothing_behind");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("atomic").unwrap();
+
+        collection
+            .start_lazy(|t| {
+                t.insert("kept", 1);
+                t.insert("updated", 1);
+            })
+            .unwrap();
+
+        let mut transaction = collection.start();
+        transaction.insert("first", 1);
+        transaction.update("updated", 2);
+        transaction.delete("kept");
+        transaction.insert("updated", 3);
+        assert!(collection.commit(&mut transaction).is_err());
+
+        // A drop is undone too, along with the operations after it.
+        let mut transaction = collection.start();
+        transaction.clear();
+        transaction.insert("after_drop", 1);
+        transaction.update("missing", 1);
+        assert!(collection.commit(&mut transaction).is_err());
+
+        assert_eq!(collection.get("first").unwrap(), None);
+        assert_eq!(collection.get("after_drop").unwrap(), None);
+        assert_eq!(collection.get("kept").unwrap(), Some(1));
+        assert_eq!(collection.get("updated").unwrap(), Some(1));
+    }
+
+    // The undo is written to the data chunks, so a rebuilt index agrees.
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("atomic").unwrap();
+
+    assert_eq!(collection.rebuild_index().unwrap(), 2);
+    assert_eq!(collection.get("first").unwrap(), None);
+    assert_eq!(collection.get("kept").unwrap(), Some(1));
+    assert_eq!(collection.get("updated").unwrap(), Some(1));
+}
+
+#[test]
+pub fn dry_run_rollback_previews_the_reverse_operations() {
+    let dustdata = DustData::new(test_config(
+        "dry_run_rollback_previews_the_reverse_operations",
+    ))
+    .unwrap();
+    let collection = dustdata.collection::<usize>("dry_run").unwrap();
+
+    collection.start_lazy(|t| t.insert("updated", 1)).unwrap();
+    let transaction = collection
+        .start_lazy(|t| {
+            t.insert("inserted", 1);
+            t.update("updated", 2);
+        })
+        .unwrap();
+
+    let operations = collection.dry_run_rollback(transaction.id()).unwrap();
+
+    assert_eq!(operations.len(), 2);
+    assert!(
+        matches!(&operations[0], dustdata::collection::Operation::Delete(key) if key == "inserted")
+    );
+    assert!(
+        matches!(&operations[1], dustdata::collection::Operation::Update(key, 1) if key == "updated")
+    );
+
+    // Nothing is committed.
+    assert_eq!(collection.get("inserted").unwrap(), Some(1));
+    assert_eq!(collection.get("updated").unwrap(), Some(2));
+
+    assert!(collection.dry_run_rollback(0).unwrap().is_empty());
+}
+
+#[test]
+pub fn rollback_sees_commits_made_after_the_transaction() {
+    let dustdata = DustData::new(test_config(
+        "rollback_sees_commits_made_after_the_transaction",
+    ))
+    .unwrap();
+    let collection = dustdata.collection::<usize>("rollback_after").unwrap();
+
+    collection.start_lazy(|t| t.insert("updated", 1)).unwrap();
+    let mut transaction = collection
+        .start_lazy(|t| {
+            t.insert("inserted", 1);
+            t.update("updated", 2);
+        })
+        .unwrap();
+
+    // Another commit deletes a key the transaction inserted before it's rolled back.
+    collection.delete_now("inserted").unwrap();
+
+    assert!(matches!(
+        collection.rollback_transaction(&mut transaction),
+        Err(dustdata::error::Error::NotFound(_))
+    ));
+    assert!(transaction.is_committed());
+    assert_eq!(collection.get("inserted").unwrap(), None);
+    assert_eq!(collection.get("updated").unwrap(), Some(2));
+}
+
+#[test]
+pub fn prefix_exists_never_misses_a_present_prefix() {
+    let mut config = test_config("prefix_exists_never_misses_a_present_prefix");
+    config.storage(|s| s.prefix_filter_depth(10));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("tenants").unwrap();
+
+    let mut transaction = collection.start();
+    for i in 0..50 {
+        transaction.insert(&format!("tenant:{}:user", i), i);
+    }
+    collection.commit(&mut transaction).unwrap();
+
+    for i in 0..50 {
+        // Past the depth, only the first 10 characters are checked.
+        assert!(collection.prefix_exists(&format!("tenant:{}:", i)).unwrap());
+        assert!(collection
+            .prefix_exists(&format!("tenant:{}:user", i))
+            .unwrap());
+    }
+    assert!(collection.prefix_exists("t").unwrap());
+    assert!(!collection.prefix_exists("account:").unwrap());
+
+    // The filter is rebuilt from the index, so deleted prefixes go away.
+    collection
+        .start_lazy(|t| t.clear_prefix("tenant:"))
+        .unwrap();
+    collection.rebuild_index().unwrap();
+    assert!(!collection.prefix_exists("tenant:").unwrap());
+}
+
+#[test]
+pub fn prefix_exists_checks_the_index_without_a_prefix_filter() {
+    let dustdata = DustData::new(test_config(
+        "prefix_exists_checks_the_index_without_a_prefix_filter",
+    ))
+    .unwrap();
+    let collection = dustdata.collection::<usize>("tenants").unwrap();
+
+    collection
+        .start_lazy(|t| t.insert("tenant:42:a", 1))
+        .unwrap();
+
+    assert!(collection.prefix_exists("tenant:42:").unwrap());
+    assert!(collection.prefix_exists("").unwrap());
+    assert!(!collection.prefix_exists("tenant:4:").unwrap());
+}
+
+#[test]
+pub fn collections_work_without_directory_syncs() {
+    let mut config = test_config("collections_work_without_directory_syncs");
+    config.sync_dir_on_create(false);
+    assert!(dustdata::DustDataConfig::default().sync_dir_on_create);
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("no_dir_sync").unwrap();
+
+        collection.start_lazy(|t| t.insert("key", 1)).unwrap();
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("no_dir_sync").unwrap();
+
+    assert_eq!(collection.get("key").unwrap(), Some(1));
+}
+
+#[test]
+pub fn open_collection_requires_an_existing_collection() {
+    let config = test_config("open_collection_requires_an_existing_collection");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+
+        assert!(!dustdata.collection_exists("users"));
+        assert!(matches!(
+            dustdata.open_collection::<usize>("users"),
+            Err(dustdata::error::Error::NotFound(_))
+        ));
+
+        dustdata.collection::<usize>("users").unwrap();
+        assert!(dustdata.collection_exists("users"));
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+
+    assert!(dustdata.collection_exists("users"));
+    assert!(!dustdata.collection_exists("usres"));
+    assert!(!dustdata.collection_exists("../users"));
+    dustdata.open_collection::<usize>("users").unwrap();
+}
+
+#[test]
+pub fn coalesce_keeps_deleted_keys_deleted() {
+    let config = test_config("coalesce_keeps_deleted_keys_deleted");
+
+    // Every reopen starts a new chunk once the last one holds 64 bytes.
+    let reopen = |f: &dyn Fn(&dustdata::Collection<String>)| {
+        let mut config = config.clone();
+        config.storage(|s| s.max_data_chunk_size(64).max_data_chunks(100));
+
+        let dustdata = DustData::new(config).unwrap();
+        f(&dustdata.collection::<String>("coalesce").unwrap());
+    };
+
+    reopen(&|c| {
+        c.start_lazy(|t| t.insert("a", "a".repeat(100))).unwrap();
+    });
+    reopen(&|c| {
+        c.start_lazy(|t| t.insert("x", "x".repeat(20))).unwrap();
+        c.delete_now("a").unwrap();
+    });
+    reopen(&|c| {
+        c.start_lazy(|t| t.insert("y", "y".repeat(20))).unwrap();
+    });
+
+    // Only the chunks after the first are less than half full now.
+    let mut config = config.clone();
+    config.storage(|s| s.max_data_chunk_size(200).max_data_chunks(100));
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("coalesce").unwrap();
+
+    assert_eq!(collection.stats().unwrap().data_chunks, 3);
+    assert_eq!(collection.coalesce().unwrap().live_segments, 2);
+    assert_eq!(collection.stats().unwrap().data_chunks, 2);
+
+    // The tombstone of "a" moved with the live values, so the value left in the first
+    // chunk stays deleted.
+    assert_eq!(collection.rebuild_index().unwrap(), 2);
+    assert_eq!(collection.get("a").unwrap(), None);
+    assert_eq!(collection.get("x").unwrap(), Some("x".repeat(20)));
+}
+
+#[test]
+pub fn compaction_reports_segments_orphaned_by_a_crash() {
+    let config = test_config("compaction_reports_segments_orphaned_by_a_crash");
+    let index_path = config.data_path.join("orphans/data/.index-dustdata");
+
+    let old_index = {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("orphans").unwrap();
+
+        collection.start_lazy(|t| t.insert("a", 1)).unwrap();
+        collection.start_lazy(|t| t.insert("deleted", 1)).unwrap();
+        collection.delete_now("deleted").unwrap();
+        collection.flush().unwrap();
+
+        let old_index = std::fs::read(&index_path).unwrap();
+
+        collection.update_now("a", 2).unwrap();
+        collection.start_lazy(|t| t.insert("b", 3)).unwrap();
+
+        old_index
+    };
+
+    // Simulates a crash after the appends to the data chunk, before the index was persisted.
+    std::fs::write(&index_path, old_index).unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("orphans").unwrap();
+
+    let report = collection.check_integrity().unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.unindexed_segments.len(), 2);
+    assert!(report.orphaned_segments.len() > 2);
+
+    let stats = collection.compact().unwrap();
+    assert!(stats.orphaned_bytes > 0);
+    assert!(stats.superseded_bytes() > 0);
+    assert_eq!(
+        stats.orphaned_bytes + stats.superseded_bytes(),
+        stats.reclaimed_bytes()
+    );
+
+    assert!(collection
+        .check_integrity()
+        .unwrap()
+        .unindexed_segments
+        .is_empty());
+    assert_eq!(collection.get("a").unwrap(), Some(1));
+    assert_eq!(collection.get("b").unwrap(), None);
+}
+
+#[test]
+pub fn concurrent_commits_reads_and_compactions_finish() {
+    let dustdata = DustData::new(test_config(
+        "concurrent_commits_reads_and_compactions_finish",
+    ))
+    .unwrap();
+    let collection = dustdata.collection::<usize>("mixed").unwrap();
+
+    let handles = (0..6)
+        .map(|i| {
+            let collection = collection.clone();
+            std::thread::spawn(move || {
+                for j in 0..50 {
+                    let key = format!("{}-{}", i % 2, j % 10);
+
+                    match i % 3 {
+                        0 => {
+                            let mut transaction = collection
+                                .start_lazy(|t| t.insert(&format!("{}-{}", i, j), j))
+                                .unwrap();
+                            if j % 5 == 0 {
+                                collection
+                                    .reset_transaction::<usize>(&mut transaction)
+                                    .unwrap();
+                            }
+                        }
+                        1 => {
+                            collection.get(&key).unwrap();
+                            collection.stats().unwrap();
+                        }
+                        _ => {
+                            collection.compact().unwrap();
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(collection.len().unwrap(), 80);
+}
+
+#[test]
+pub fn warm_start_loads_recent_values_on_open() {
+    let config = test_config("warm_start_loads_recent_values_on_open");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<String>("warm").unwrap();
+
+        for i in 0..10 {
+            collection
+                .start_lazy(|t| t.insert(&format!("key:{}", i), "x".repeat(100)))
+                .unwrap();
+        }
+    }
+
+    let config = config.clone().storage(|s| s.warm_start(400)).build();
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("warm").unwrap();
+
+    assert!(collection.memtable_size().unwrap() > 0);
+    assert!(collection.memtable_size().unwrap() <= 400);
+
+    collection.get("key:9").unwrap().unwrap();
+    assert_eq!(collection.metrics().cache_hits, 1);
+
+    assert_eq!(collection.get("key:0").unwrap().unwrap(), "x".repeat(100));
+    assert_eq!(collection.metrics().cache_misses, 1);
+}
+
+#[test]
+pub fn with_value_borrows_cached_and_stored_values() {
+    let config = test_config("with_value_borrows_cached_and_stored_values");
+
+    {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<User>("users").unwrap();
+
+        collection
+            .start_lazy(|t| {
+                t.insert(
+                    "ana",
+                    User {
+                        email: "ana@mail.com".to_string(),
+                        name: "Ana".to_string(),
+                    },
+                )
+            })
+            .unwrap();
+
+        let email = collection.with_value("ana", |user| user.email.clone());
+        assert_eq!(email.unwrap().as_deref(), Some("ana@mail.com"));
+        assert_eq!(collection.metrics().cache_hits, 1);
+    }
+
+    // A fresh memtable makes the read go to disk.
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<User>("users").unwrap();
+
+    let name_len = collection
+        .with_value("ana", |user| user.name.len())
+        .unwrap();
+    assert_eq!(name_len, Some(3));
+    assert_eq!(collection.metrics().cache_misses, 1);
+
+    let called = collection.with_value("bob", |_| panic!("no value to borrow"));
+    assert_eq!(called.unwrap(), None::<()>);
+}
+
+#[test]
+pub fn missing_chunks_return_structured_errors() {
+    use dustdata::error::Error;
+
+    // Full chunks aren't reopened for appends, so the removed ones stay missing.
+    let config = test_config("missing_chunks_return_structured_errors")
+        .storage(|s| s.max_data_chunk_size(1))
+        .wal(|w| w.max_log_size(1))
+        .build();
+
+    for (key, value) in [("a", 1), ("b", 2)] {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("missing").unwrap();
+
+        collection.start_lazy(|t| t.insert(key, value)).unwrap();
+    }
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<usize>("missing").unwrap();
+
+    std::fs::remove_file(config.data_path.join("missing/data/Data_0_0.db")).unwrap();
+    std::fs::remove_file(config.data_path.join("missing/log/DustDataLog_0")).unwrap();
+
+    assert!(matches!(
+        collection.get("a"),
+        Err(Error::MissingDataChunk { chunk }) if chunk == "Data_0_0.db"
+    ));
+    assert!(matches!(
+        collection.wal_since(0),
+        Err(Error::MissingWalChunk { chunk }) if chunk == "DustDataLog_0"
+    ));
+
+    collection.rebuild_index().unwrap();
+    assert!(collection.get("a").unwrap().is_none());
+    assert_eq!(collection.get("b").unwrap().unwrap(), 2);
+}
+
+#[test]
+pub fn flush_prunes_log_chunks_past_the_retained_count() {
+    let config = test_config("flush_prunes_log_chunks_past_the_retained_count")
+        .wal(|w| w.max_log_size(1).max_retained_logs(2))
+        .build();
+
+    // Every open starts a new log chunk, since the previous one is full.
+    for i in 0..5 {
+        let dustdata = DustData::new(config.clone()).unwrap();
+        let collection = dustdata.collection::<usize>("pruned").unwrap();
+
+        collection
+            .start_lazy(|t| t.insert(&format!("key:{}", i), i))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+
+        dustdata.close().unwrap();
+    }
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<usize>("pruned").unwrap();
+
+    collection.flush().unwrap();
+    assert_eq!(collection.stats().unwrap().wal_log_chunks, 2);
+
+    // The chunk opened last is empty, so only the last transaction is retained.
+    let logs = collection.wal_since(0).unwrap();
+    assert_eq!(logs.len(), 1);
+    assert!(collection.recover_to(logs[0].id).is_err());
+
+    for i in 0..5 {
+        assert_eq!(collection.get(&format!("key:{}", i)).unwrap().unwrap(), i);
+    }
+}
+
+#[test]
+pub fn log_chunks_rotate_while_open() {
+    for group_commit in [false, true] {
+        let config = test_config(&format!("log_chunks_rotate_while_open_{}", group_commit))
+            .wal(|w| {
+                w.max_log_size(1)
+                    .max_retained_logs(2)
+                    .group_commit(group_commit)
+            })
+            .build();
+        let dustdata = DustData::new(config).unwrap();
+        let collection = dustdata.collection::<usize>("rotated").unwrap();
+        let chunks = collection.stats().unwrap().wal_log_chunks;
+
+        for i in 0..5 {
+            collection
+                .start_lazy(|t| t.insert(&format!("key:{}", i), i))
+                .unwrap();
+        }
+
+        assert!(collection.stats().unwrap().wal_log_chunks >= chunks + 4);
+
+        collection.flush().unwrap();
+        assert_eq!(collection.stats().unwrap().wal_log_chunks, 2);
+        assert_eq!(collection.wal_since(0).unwrap().len(), 2);
+
+        for i in 0..5 {
+            assert_eq!(collection.get(&format!("key:{}", i)).unwrap().unwrap(), i);
+        }
+    }
+}
+
+#[test]
+pub fn snapshots_restore_the_collection_as_it_was() {
+    let config = test_config("snapshots_restore_the_collection_as_it_was");
+    let snapshots = config.data_path.join("snapshots");
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("snapshot").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert("a", "1".to_string());
+            t.insert("b", "2".to_string());
+        })
+        .unwrap();
+
+    let name = collection.snapshot(&snapshots).unwrap();
+    assert!(name.ends_with(".snapshot"));
+
+    collection
+        .start_lazy(|t| {
+            t.delete("a");
+            t.update("b", "3".to_string());
+            t.insert("c", "4".to_string());
+        })
+        .unwrap();
+
+    collection.restore_snapshot(&snapshots.join(&name)).unwrap();
+
+    assert_eq!(collection.get("a").unwrap().unwrap(), "1");
+    assert_eq!(collection.get("b").unwrap().unwrap(), "2");
+    assert!(collection.get("c").unwrap().is_none());
+    assert_eq!(collection.len().unwrap(), 2);
+
+    std::fs::write(snapshots.join("bogus.snapshot"), b"not a snapshot").unwrap();
+    assert!(matches!(
+        collection.restore_snapshot(&snapshots.join("bogus.snapshot")),
+        Err(dustdata::error::Error::CorruptedData(_))
+    ));
+    assert_eq!(collection.len().unwrap(), 2);
+}
+
+#[test]
+pub fn snapshots_use_the_configured_compression() {
+    use dustdata::CompressionAlgorithm;
+
+    let zstd_config = test_config("snapshots_use_the_configured_compression/zstd")
+        .snapshot_compression(|c| c.algorithm(CompressionAlgorithm::Zstd).level(19))
+        .build();
+    let raw_config = test_config("snapshots_use_the_configured_compression/raw")
+        .snapshot_compression(|c| c.algorithm(CompressionAlgorithm::None))
+        .build();
+    let zstd_snapshots = zstd_config.data_path.join("snapshots");
+    let raw_snapshots = raw_config.data_path.join("snapshots");
+
+    let zstd = DustData::new(zstd_config).unwrap();
+    let raw = DustData::new(raw_config).unwrap();
+    let archived = zstd.collection::<String>("items").unwrap();
+    let local = raw.collection::<String>("items").unwrap();
+
+    for collection in [&archived, &local] {
+        collection
+            .start_lazy(|t| {
+                for i in 0..100 {
+                    t.insert(&format!("key:{}", i), "x".repeat(100));
+                }
+            })
+            .unwrap();
+    }
+
+    let zstd_snapshot = zstd_snapshots.join(archived.snapshot(&zstd_snapshots).unwrap());
+    let raw_snapshot = raw_snapshots.join(local.snapshot(&raw_snapshots).unwrap());
+
+    let zstd_len = std::fs::metadata(&zstd_snapshot).unwrap().len();
+    let raw_len = std::fs::metadata(&raw_snapshot).unwrap().len();
+    assert!(zstd_len * 10 < raw_len);
+
+    // Snapshots record their algorithm, so they restore whatever the collection is set to.
+    local.start_lazy(|t| t.delete("key:0")).unwrap();
+    local.restore_snapshot(&zstd_snapshot).unwrap();
+    assert_eq!(local.len().unwrap(), 100);
+
+    archived.start_lazy(|t| t.delete("key:0")).unwrap();
+    archived.restore_snapshot(&raw_snapshot).unwrap();
+    assert_eq!(archived.get("key:0").unwrap().unwrap(), "x".repeat(100));
+}
+
+#[test]
+pub fn snapshot_deltas_restore_onto_their_base() {
+    use dustdata::error::Error;
+
+    let config = test_config("snapshot_deltas_restore_onto_their_base");
+    let snapshots = config.data_path.join("snapshots");
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("items").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            for i in 0..100 {
+                t.insert(&format!("key:{}", i), i.to_string());
+            }
+        })
+        .unwrap();
+
+    let full = collection.snapshot(&snapshots).unwrap();
+
+    collection
+        .update_now("key:0", "updated".to_string())
+        .unwrap();
+    let first = collection.snapshot_delta(&full, &snapshots).unwrap();
+
+    collection.delete_now("key:1").unwrap();
+    collection
+        .start_lazy(|t| t.insert("key:100", "100".to_string()))
+        .unwrap();
+    let second = collection.snapshot_delta(&first, &snapshots).unwrap();
+    assert!(second.ends_with(".delta"));
+
+    let size = |name: &str| std::fs::metadata(snapshots.join(name)).unwrap().len();
+    assert!(size(&second) < size(&full));
+
+    collection.start_lazy(|t| t.clear()).unwrap();
+
+    let (full, first, second) = (
+        snapshots.join(full),
+        snapshots.join(first),
+        snapshots.join(second),
+    );
+
+    // Deltas out of order, or a chain starting with a delta, restore nothing.
+    assert!(matches!(
+        collection.restore_snapshot_chain(&full, &[&second, &first]),
+        Err(Error::Other(_))
+    ));
+    assert!(matches!(
+        collection.restore_snapshot_chain(&first, &[&second]),
+        Err(Error::Other(_))
+    ));
+    assert_eq!(collection.len().unwrap(), 0);
+
+    collection.restore_snapshot_chain(&full, &[&first]).unwrap();
+    assert_eq!(collection.get("key:0").unwrap().unwrap(), "updated");
+    assert!(collection.get("key:1").unwrap().is_some());
+
+    collection
+        .restore_snapshot_chain(&full, &[&first, &second])
+        .unwrap();
+    assert_eq!(collection.get("key:0").unwrap().unwrap(), "updated");
+    assert!(collection.get("key:1").unwrap().is_none());
+    assert_eq!(collection.get("key:100").unwrap().unwrap(), "100");
+    assert_eq!(collection.len().unwrap(), 100);
+}
+
+#[test]
+pub fn gets_go_on_during_compaction() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let config = test_config("gets_go_on_during_compaction")
+        .storage(|s| s.flush_threshold(0))
+        .build();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("compacting").unwrap();
+
+    for round in 0..2 {
+        collection
+            .start_lazy(|t| {
+                for i in 0..500 {
+                    let key = format!("key:{}", i);
+                    let value = format!("{}", round).repeat(4096);
+
+                    if round == 0 {
+                        t.insert(&key, value);
+                    } else {
+                        t.update(&key, value);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    let compacting = Arc::new(AtomicBool::new(true));
+    let reads = Arc::new(AtomicUsize::new(0));
+
+    let reader = {
+        let collection = collection.clone();
+        let compacting = compacting.clone();
+        let reads = reads.clone();
+
+        std::thread::spawn(move || {
+            while compacting.load(Ordering::SeqCst) {
+                let i = reads.fetch_add(1, Ordering::SeqCst) % 500;
+                let value = collection.get(&format!("key:{}", i)).unwrap().unwrap();
+
+                assert_eq!(value, "1".repeat(4096));
+            }
+        })
+    };
+
+    while reads.load(Ordering::SeqCst) == 0 {
+        std::thread::yield_now();
+    }
+
+    let before = reads.load(Ordering::SeqCst);
+
+    collection.compact().unwrap();
+
+    let during = reads.load(Ordering::SeqCst) - before;
+
+    compacting.store(false, Ordering::SeqCst);
+    reader.join().unwrap();
+
+    assert!(during > 10, "only {} gets during compaction", during);
+    assert_eq!(collection.len().unwrap(), 500);
+    assert!(collection.check_integrity().unwrap().is_clean());
+}
+
+#[test]
+pub fn get_or_insert_with_runs_the_default_once() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dustdata = DustData::new(test_config("get_or_insert_with_runs_the_default_once")).unwrap();
+    let collection = dustdata.collection::<usize>("defaults").unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let handles = (0..8)
+        .map(|i| {
+            let collection = collection.clone();
+            let calls = calls.clone();
+
+            std::thread::spawn(move || {
+                collection
+                    .get_or_insert_with("counter", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        i
+                    })
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let values = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|value| *value == values[0]));
+    assert_eq!(collection.get("counter").unwrap().unwrap(), values[0]);
+
+    assert_eq!(
+        collection
+            .get_or_insert_with("counter", || unreachable!())
+            .unwrap(),
+        values[0]
+    );
+}
+
+#[test]
+pub fn modify_updates_a_field_in_place() {
+    use dustdata::collection::ChangeEvent;
+
+    let dustdata = DustData::new(test_config("modify_updates_a_field_in_place")).unwrap();
+    let collection = dustdata.collection::<User>("users").unwrap();
+
+    collection
+        .start_lazy(|t| {
+            t.insert(
+                "ana",
+                User {
+                    email: "ana@old.com".to_string(),
+                    name: "Ana".to_string(),
+                },
+            )
+        })
+        .unwrap();
+
+    let events = collection.subscribe();
+
+    let modified = collection
+        .modify("ana", |user| user.email = "ana@new.com".to_string())
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(modified.email, "ana@new.com");
+    assert_eq!(collection.get("ana").unwrap().unwrap(), modified);
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ChangeEvent::Updated { old, new, .. } if old.email == "ana@old.com" && new == modified
+    ));
+
+    assert!(collection
+        .modify("bob", |_| unreachable!())
+        .unwrap()
+        .is_none());
+    assert!(events.try_recv().is_err());
+}
+
+#[test]
+pub fn clear_frees_the_data_chunks_once_flushed() {
+    let config = test_config("clear_frees_the_data_chunks_once_flushed")
+        .storage(|s| s.max_data_chunk_size(64 * 1024))
+        .build();
+    let data_path = config.data_path.join("cleared/data");
+    let chunk_bytes = || {
+        std::fs::read_dir(&data_path)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".db"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    let dustdata = DustData::new(config.clone()).unwrap();
+    let collection = dustdata.collection::<String>("cleared").unwrap();
+
+    for batch in 0..4 {
+        collection
+            .start_lazy(|t| {
+                for i in 0..100 {
+                    t.insert(&format!("key:{}:{}", batch, i), "x".repeat(1024));
+                }
+            })
+            .unwrap();
+    }
+
+    collection.start_lazy(|t| t.clear()).unwrap();
+    assert!(chunk_bytes() > 400 * 1024);
+
+    collection.flush().unwrap();
+    assert_eq!(chunk_bytes(), 0);
+    assert!(collection.is_empty().unwrap());
+
+    // Keys written in the same transaction as the clear are compacted instead.
+    collection
+        .start_lazy(|t| t.insert("before", "x".repeat(1024)))
+        .unwrap();
+    collection
+        .start_lazy(|t| {
+            t.clear();
+            t.insert("kept", "value".to_string());
+        })
+        .unwrap();
+    collection.flush().unwrap();
+
+    assert!(chunk_bytes() < 1024);
+    assert_eq!(collection.get("kept").unwrap().unwrap(), "value");
+    assert!(collection.get("before").unwrap().is_none());
+
+    drop(collection);
+    dustdata.close().unwrap();
+
+    let dustdata = DustData::new(config).unwrap();
+    let collection = dustdata.collection::<String>("cleared").unwrap();
+
+    assert_eq!(collection.rebuild_index().unwrap(), 1);
+    assert_eq!(collection.get("kept").unwrap().unwrap(), "value");
+}
@@ -0,0 +1,155 @@
+use dustdata::{Size, StorageConfig, WALConfig};
+
+#[test]
+pub fn size_parses_human_readable_units() {
+    assert_eq!("10MB".parse::<Size>().unwrap().to_bytes(), 10_485_760);
+    assert_eq!("512KiB".parse::<Size>().unwrap(), Size::Kilobytes(512));
+    assert_eq!("1 GB".parse::<Size>().unwrap().to_bytes(), 1 << 30);
+    assert_eq!("42".parse::<Size>().unwrap(), Size::Bytes(42));
+    assert!("10XB".parse::<Size>().is_err());
+    assert!("MB".parse::<Size>().is_err());
+    assert!("99999999999999GB".parse::<Size>().is_err());
+
+    let storage = StorageConfig::new()
+        .max_data_chunk_size("2MB".parse::<Size>().unwrap())
+        .clone();
+    let wal = WALConfig::new().max_log_size(4096).clone();
+
+    assert_eq!(storage.max_data_chunk_size, 2 * 1024 * 1024);
+    assert_eq!(wal.max_log_size, 4096);
+}
+
+#[test]
+pub fn sizes_compare_and_combine_across_units() {
+    assert!(Size::Gigabytes(1) > Size::Megabytes(500));
+    assert!(Size::Kilobytes(1) < Size::Bytes(1025));
+    assert_eq!(Size::Kilobytes(1), Size::Bytes(1024));
+
+    assert_eq!(
+        Size::Megabytes(1) + Size::Kilobytes(512),
+        Size::Kilobytes(1536)
+    );
+    assert_eq!(Size::Megabytes(1) - Size::Kilobytes(1024), Size::Bytes(0));
+    assert_eq!(Size::Bytes(1) - Size::Kilobytes(1), Size::Bytes(0));
+
+    assert_eq!(Size::from(4096usize).to_string(), "4 KB");
+    assert_eq!(
+        (Size::Megabytes(5) + Size::Megabytes(5)).to_string(),
+        "10 MB"
+    );
+    assert_eq!(Size::Bytes(42).to_string(), "42 B");
+    assert_eq!(usize::from(Size::Kilobytes(2)), 2048);
+
+    assert_eq!(Size::Gigabytes(u64::MAX).checked_to_bytes(), None);
+    assert_eq!(Size::Bytes(u64::MAX).checked_add(Size::Bytes(1)), None);
+}
+
+#[test]
+pub fn default_sizes_use_powers_of_1024() {
+    assert_eq!(StorageConfig::new().max_data_chunk_size, 10_485_760);
+    assert_eq!(WALConfig::new().max_log_size, 5_242_880);
+}
+
+#[cfg(feature = "toml")]
+#[test]
+pub fn config_loads_from_toml_file() {
+    let path = std::path::Path::new("./test_data/config_loads_from_toml_file/dustdata.toml");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    std::fs::write(
+        path,
+        r#"
+data_path = "./data/users"
+
+[wal]
+log_path = "./wal"
+max_log_size = 1048576
+compression = { level = 9 }
+
+[storage]
+max_data_chunk_size = 2097152
+max_data_chunks = 4
+"#,
+    )
+    .unwrap();
+
+    let config = dustdata::DustDataConfig::from_toml_file(path).unwrap();
+
+    assert_eq!(config.data_path, std::path::Path::new("./data/users"));
+    assert_eq!(config.wal.log_path, std::path::Path::new("./wal"));
+    assert_eq!(config.wal.max_log_size, 1_048_576);
+    assert_eq!(config.wal.compression.unwrap().level, 9);
+    assert_eq!(config.storage.max_data_chunk_size, 2_097_152);
+    assert_eq!(config.storage.max_data_chunks, 4);
+    assert_eq!(config.storage.min_compress_size, 256);
+
+    std::fs::write(path, "[storage]\nmax_data_chunks = \"many\"").unwrap();
+    assert!(dustdata::DustDataConfig::from_toml_file(path).is_err());
+}
+
+#[test]
+pub fn invalid_configs_are_rejected() {
+    let config = dustdata::DustDataConfig::default();
+    assert!(config.validate().is_ok());
+
+    let invalid = [
+        config.clone().storage(|s| s.max_data_chunks(0)).build(),
+        config.clone().storage(|s| s.max_data_chunk_size(0)).build(),
+        config.clone().storage(|s| s.prefix_filter_depth(0)).build(),
+        config.clone().wal(|w| w.max_log_size(0)).build(),
+        config.clone().wal(|w| w.max_retained_logs(0)).build(),
+        config
+            .clone()
+            .storage(|s| s.compression(|c| c.level(10)))
+            .build(),
+        config
+            .clone()
+            .wal(|w| w.compression(|c| c.algorithm(dustdata::CompressionAlgorithm::Zstd).level(23)))
+            .build(),
+        config
+            .clone()
+            .snapshot_compression(|c| c.algorithm(dustdata::CompressionAlgorithm::Gzip).level(12))
+            .build(),
+        config
+            .clone()
+            .storage(|s| s.max_collection_bytes(1024).blob_threshold(512))
+            .build(),
+        config.clone().storage(|s| s.bloom_fp_rate(0.0)).build(),
+        config.clone().storage(|s| s.bloom_fp_rate(1.0)).build(),
+        config
+            .clone()
+            .storage(|s| s.max_data_chunk_size(Size::Gigabytes(u64::MAX)))
+            .build(),
+        config
+            .clone()
+            .wal(|w| w.max_log_size(Size::Megabytes(u64::MAX)))
+            .build(),
+    ];
+
+    for config in invalid {
+        assert!(matches!(
+            config.validate(),
+            Err(dustdata::error::Error::Other(_))
+        ));
+        assert!(dustdata::DustData::new(config).is_err());
+    }
+
+    let lz4 = config
+        .clone()
+        .storage(|s| {
+            s.value_compression(|c| c.algorithm(dustdata::CompressionAlgorithm::Lz4).level(99))
+        })
+        .build();
+    assert!(lz4.validate().is_ok());
+
+    // The largest sizes that fit mean no limit, they aren't overflows.
+    let unlimited = config
+        .clone()
+        .storage(|s| {
+            s.max_data_chunk_size(usize::MAX)
+                .max_collection_bytes(usize::MAX)
+        })
+        .wal(|w| w.max_log_size(Size::Bytes(u64::MAX)))
+        .build();
+    assert!(unlimited.validate().is_ok());
+}
@@ -0,0 +1,25 @@
+use dustdata::error::Error;
+
+#[test]
+pub fn io_error_is_exposed_as_source() {
+    let io_error = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+    let error: Box<dyn std::error::Error> = Box::new(Error::from(io_error));
+
+    assert_eq!(error.to_string(), "IO Error: denied");
+    assert!(error.source().is_some());
+    assert_eq!(Error::Deadlock.to_string(), "Deadlock");
+}
+
+#[test]
+pub fn revert_failures_are_attached_to_the_error() {
+    let error: Box<dyn std::error::Error> = Box::new(Error::RevertFailed {
+        error: Box::new(Error::AlreadyExists("user:1".to_string())),
+        reverts: vec![("audit".to_string(), Error::Deadlock)],
+    });
+
+    assert_eq!(
+        error.to_string(),
+        "user:1 already exists, and reverting audit failed: Deadlock"
+    );
+    assert_eq!(error.source().unwrap().to_string(), "user:1 already exists");
+}